@@ -0,0 +1,128 @@
+// Compares the cost of deciding whether subscribers are already in a voice channel, once by
+// scanning every channel's members (what `send_notifications` used to do) and once using the
+// guild's `voice_states` map via `voice::locate_user`.
+//
+// Run with `cargo bench`.
+
+// Only `locate_user` is measured, and its unit tests are only run as part of the main crate.
+#[path = "../src/voice.rs"]
+#[allow(dead_code)]
+mod voice;
+
+use serenity::model::{
+    id::{ChannelId, UserId},
+    voice::VoiceState,
+};
+
+use std::collections::{HashMap, HashSet};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 20;
+
+struct Guild {
+    channels: Vec<ChannelId>,
+    afk_channels: HashSet<ChannelId>,
+    voice_states: HashMap<UserId, VoiceState>,
+    subscribers: Vec<UserId>,
+}
+
+fn voice_state(user_id: UserId, channel_id: ChannelId) -> VoiceState {
+    serde_json::from_value(serde_json::json!({
+        "channel_id": channel_id.to_string(),
+        "deaf": false,
+        "mute": false,
+        "self_deaf": false,
+        "self_mute": false,
+        "self_video": false,
+        "session_id": "",
+        "suppress": false,
+        "user_id": user_id.to_string(),
+    }))
+    .expect("valid voice state")
+}
+
+// Builds a guild where every third user is connected to some voice channel.
+fn build_guild(channel_count: u64, subscriber_count: u64) -> Guild {
+    let channels = (1..=channel_count).map(ChannelId::from).collect::<Vec<_>>();
+    let afk_channels = channels.iter().take(1).copied().collect();
+    let subscribers = (1..=subscriber_count)
+        .map(|u| UserId::from(1_000_000 + u))
+        .collect::<Vec<_>>();
+
+    let voice_states = subscribers
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| i % 3 == 0)
+        .map(|(i, &user_id)| (user_id, voice_state(user_id, channels[i % channels.len()])))
+        .collect();
+
+    Guild {
+        channels,
+        afk_channels,
+        voice_states,
+        subscribers,
+    }
+}
+
+fn linear_scan(guild: &Guild, joined_channel: ChannelId) -> usize {
+    guild
+        .subscribers
+        .iter()
+        .filter(|&&user_id| {
+            guild.channels.iter().any(|&channel| {
+                channel != joined_channel
+                    && !guild.afk_channels.contains(&channel)
+                    && guild
+                        .voice_states
+                        .values()
+                        .any(|s| s.channel_id == Some(channel) && s.user_id == user_id)
+            })
+        })
+        .count()
+}
+
+fn indexed_lookup(guild: &Guild, joined_channel: ChannelId) -> usize {
+    guild
+        .subscribers
+        .iter()
+        .filter(|&&user_id| {
+            // Every channel of the generated guilds is a voice channel.
+            voice::locate_user(
+                &guild.voice_states,
+                user_id,
+                joined_channel,
+                |_| true,
+                |c| guild.afk_channels.contains(&c),
+            ) == voice::VoiceLocation::InOtherChannel
+        })
+        .count()
+}
+
+fn measure<F: Fn() -> usize>(f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(f());
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    for &(channels, subscribers) in &[(50, 50), (200, 200), (500, 500), (500, 2000)] {
+        let guild = build_guild(channels, subscribers);
+        let joined_channel = guild.channels[1];
+
+        assert_eq!(
+            linear_scan(&guild, joined_channel),
+            indexed_lookup(&guild, joined_channel)
+        );
+
+        let linear = measure(|| linear_scan(&guild, joined_channel));
+        let indexed = measure(|| indexed_lookup(&guild, joined_channel));
+
+        println!(
+            "{:>4} channels, {:>4} subscribers: linear scan {:>12?}, voice_states lookup {:>10?}",
+            channels, subscribers, linear, indexed
+        );
+    }
+}
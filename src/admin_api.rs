@@ -0,0 +1,254 @@
+use crate::http::{error_response, json_response};
+use crate::model::PCGuild;
+use crate::state::PCState;
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use serenity::model::id::GuildId;
+
+// JSON API for managing the same settings as the DM commands, plus admins. All IDs are
+// represented as strings, like in Discord's own API.
+//
+// GET    /api/guilds
+// GET    /api/guilds/<guild>
+// GET    /api/guilds/<guild>/subscriptions
+// PUT    /api/guilds/<guild>/channels/<channel>/subscribers/<user>
+// DELETE /api/guilds/<guild>/channels/<channel>/subscribers/<user>
+// GET    /api/guilds/<guild>/afk-channels
+// PUT    /api/guilds/<guild>/afk-channels/<channel>
+// DELETE /api/guilds/<guild>/afk-channels/<channel>
+// GET    /api/guilds/<guild>/admins
+// PUT    /api/guilds/<guild>/admins/<user>    (optional body: {"send_notif_copies": bool})
+// DELETE /api/guilds/<guild>/admins/<user>
+// PUT    /api/guilds/<guild>/fallback-channel/<channel>
+// DELETE /api/guilds/<guild>/fallback-channel/<channel>
+// GET    /api/guilds/<guild>/undeliverable
+//
+// Modifying requests respond with `{"changed": bool}`.
+pub async fn handle(req: Request<Body>, segments: &[&str], state: &PCState) -> Response<Body> {
+    route(req, segments, state)
+        .await
+        .unwrap_or_else(|(status, message)| error_response(status, &message))
+}
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+async fn route(
+    req: Request<Body>,
+    segments: &[&str],
+    state: &PCState,
+) -> ApiResult<Response<Body>> {
+    let method = req.method().clone();
+
+    let guild_id = match segments {
+        ["guilds"] if method == Method::GET => {
+            let guilds = state.read(|d| {
+                let mut guilds = d.guilds().collect::<Vec<_>>();
+                guilds.sort_unstable_by_key(|g| g.id);
+                guilds.into_iter().map(guild_json).collect()
+            });
+            return Ok(json_response(StatusCode::OK, &Value::Array(guilds)));
+        }
+        ["guilds", guild_id, ..] => parse_id::<GuildId>(guild_id)?,
+        _ => return Err(not_found()),
+    };
+
+    let changed = match (&method, &segments[2..]) {
+        (&Method::GET, []) => return read_guild(state, guild_id, guild_json),
+        (&Method::GET, ["subscriptions"]) => {
+            return read_guild(state, guild_id, |g| guild_json(g)["notif_channels"].take())
+        }
+        (&Method::GET, ["afk-channels"]) => {
+            return read_guild(state, guild_id, |g| guild_json(g)["afk_channels"].take())
+        }
+        (&Method::GET, ["admins"]) => {
+            return read_guild(state, guild_id, |g| guild_json(g)["admins"].take())
+        }
+        (&Method::GET, ["undeliverable"]) => {
+            let mut users = state.read(|d| {
+                state.read_runtime(|r| r.undeliverable_subscribers(d, guild_id).collect::<Vec<_>>())
+            });
+            users.sort_unstable_by_key(|(user_id, _)| *user_id);
+            let users = users
+                .into_iter()
+                .map(|(user_id, failures)| {
+                    json!({
+                        "id": user_id.to_string(),
+                        "consecutive_failures": failures.consecutive,
+                        "last_failure": failures.last_failure,
+                        "paused": failures.paused,
+                    })
+                })
+                .collect();
+            return Ok(json_response(StatusCode::OK, &Value::Array(users)));
+        }
+        (&Method::PUT, ["channels", channel_id, "subscribers", user_id]) => {
+            let (channel_id, user_id) = (parse_id(channel_id)?, parse_id(user_id)?);
+            state.update(|d| d.add_subscription(user_id, guild_id, channel_id))
+        }
+        (&Method::DELETE, ["channels", channel_id, "subscribers", user_id]) => {
+            let (channel_id, user_id) = (parse_id(channel_id)?, parse_id(user_id)?);
+            state.update(|d| d.remove_subscription(user_id, guild_id, channel_id))
+        }
+        (&Method::PUT, ["afk-channels", channel_id]) => {
+            let channel_id = parse_id(channel_id)?;
+            state.update(|d| d.add_afk_channel(guild_id, channel_id))
+        }
+        (&Method::DELETE, ["afk-channels", channel_id]) => {
+            let channel_id = parse_id(channel_id)?;
+            state.update(|d| d.remove_afk_channel(guild_id, channel_id))
+        }
+        (&Method::PUT, ["fallback-channel", channel_id]) => {
+            let channel_id = parse_id(channel_id)?;
+            state.update(|d| d.set_fallback_channel(guild_id, Some(channel_id)))
+        }
+        (&Method::DELETE, ["fallback-channel", channel_id]) => {
+            let channel_id = parse_id(channel_id)?;
+            state.update(|d| {
+                d.fallback_channel(guild_id) == Some(channel_id)
+                    && d.set_fallback_channel(guild_id, None)
+            })
+        }
+        (&Method::PUT, ["admins", user_id]) => {
+            let user_id = parse_id(user_id)?;
+            let settings = read_admin_settings(req).await?;
+            state.update(|d| d.add_admin(user_id, guild_id, settings.send_notif_copies))
+        }
+        (&Method::DELETE, ["admins", user_id]) => {
+            let user_id = parse_id(user_id)?;
+            state.update(|d| d.remove_admin(user_id, guild_id))
+        }
+        _ => return Err(not_found()),
+    };
+
+    Ok(json_response(
+        StatusCode::OK,
+        &json!({ "changed": changed }),
+    ))
+}
+
+#[derive(Deserialize, Default)]
+struct AdminSettings {
+    #[serde(default)]
+    send_notif_copies: bool,
+}
+
+async fn read_admin_settings(req: Request<Body>) -> ApiResult<AdminSettings> {
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Failed to read body".to_string()))?;
+
+    if body.is_empty() {
+        return Ok(AdminSettings::default());
+    }
+
+    serde_json::from_slice(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid body: {}", e)))
+}
+
+fn read_guild<F>(state: &PCState, guild_id: GuildId, f: F) -> ApiResult<Response<Body>>
+where
+    F: FnOnce(&PCGuild) -> Value,
+{
+    match state.read(|d| d.find_guild(guild_id).map(f)) {
+        Some(body) => Ok(json_response(StatusCode::OK, &body)),
+        None => Err((StatusCode::NOT_FOUND, "Unknown guild".to_string())),
+    }
+}
+
+fn not_found() -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, "Not found".to_string())
+}
+
+fn parse_id<T: From<u64>>(s: &str) -> ApiResult<T> {
+    s.parse::<u64>()
+        .map(T::from)
+        .map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid ID: {}", s)))
+}
+
+fn guild_json(guild: &PCGuild) -> Value {
+    let mut admins = guild.admins().collect::<Vec<_>>();
+    admins.sort_unstable();
+
+    let mut afk_channels = guild.afk_channels().collect::<Vec<_>>();
+    afk_channels.sort_unstable();
+
+    let mut idle_move_exemptions = guild.idle_move_exemptions().collect::<Vec<_>>();
+    idle_move_exemptions.sort_unstable_by_key(|e| e.to_string());
+
+    let mut lobby_channels = guild.lobby_channels().collect::<Vec<_>>();
+    lobby_channels.sort_unstable();
+
+    let mut temp_channels = guild.temp_channels().collect::<Vec<_>>();
+    temp_channels.sort_unstable_by_key(|&(id, _)| id);
+
+    let mut notif_channels = guild.notif_channels().collect::<Vec<_>>();
+    notif_channels.sort_unstable_by_key(|c| c.id);
+
+    json!({
+        "id": guild.id.to_string(),
+        "admins": admins
+            .into_iter()
+            .map(|(id, send_notif_copies)| json!({
+                "id": id.to_string(),
+                "send_notif_copies": send_notif_copies,
+            }))
+            .collect::<Vec<_>>(),
+        "afk_channels": afk_channels
+            .into_iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>(),
+        "discord_afk_channel": guild.discord_afk_channel().map(|id| id.to_string()),
+        "ignore_discord_afk_channel": guild.ignores_discord_afk_channel(),
+        "idle_move_after": guild.idle_move_after(),
+        "idle_move_exemptions": idle_move_exemptions
+            .into_iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>(),
+        "fallback_channel": guild.fallback_channel().map(|id| id.to_string()),
+        "lobby_channels": lobby_channels
+            .into_iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>(),
+        "temp_channels": temp_channels
+            .into_iter()
+            .map(|(id, c)| json!({
+                "id": id.to_string(),
+                "lobby": c.lobby.to_string(),
+                "owner": c.owner.map(|id| id.to_string()),
+            }))
+            .collect::<Vec<_>>(),
+        "spoiler_rules": guild
+            .spoiler_rules()
+            .iter()
+            .map(|rule| json!({
+                "game": rule.game,
+                "channel": rule.channel.map(|id| id.to_string()),
+            }))
+            .collect::<Vec<_>>(),
+        "suppression_mode": guild.suppression_mode().name(),
+        "suppression_rules": guild
+            .suppression_rules()
+            .iter()
+            .map(|rule| json!({
+                "kind": rule.kind(),
+                "pattern": rule.pattern(),
+            }))
+            .collect::<Vec<_>>(),
+        "notif_channels": notif_channels
+            .into_iter()
+            .map(|channel| {
+                let mut users = channel.subscribed_users.iter().collect::<Vec<_>>();
+                users.sort_unstable();
+                json!({
+                    "id": channel.id.to_string(),
+                    "subscribed_users": users
+                        .into_iter()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>(),
+    })
+}
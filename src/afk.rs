@@ -0,0 +1,342 @@
+use crate::metrics;
+use crate::model::{unix_now, IdleMove, PCData};
+use crate::state::PCState;
+
+use log::{info, warn};
+use serenity::{
+    http::Http,
+    model::id::{ChannelId, GuildId, RoleId, UserId},
+    CacheAndHttp,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Beginnings of words that suggest a voice channel is meant for people who are away.
+const NAME_HINTS: [&str; 6] = ["afk", "away", "idle", "sleep", "brb", "inactive"];
+
+// How often voice states are checked for members that have been deafened for too long.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Whether a channel's name, like "💤 Sleeping" or "AFK-Corner", suggests that it's an AFK channel.
+// Only used to suggest channels to admins, since guessing wrong would stop notifications.
+pub fn looks_like_afk_channel(name: &str) -> bool {
+    name.contains('💤')
+        || name
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .any(|word| NAME_HINTS.iter().any(|hint| word.starts_with(hint)))
+}
+
+// Members that never get moved to an AFK channel for being deafened, either by themselves or
+// because of one of their roles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IdleMoveExemption {
+    Role(RoleId),
+    User(UserId),
+}
+
+impl IdleMoveExemption {
+    pub fn parse(kind: &str, id: &str) -> Option<IdleMoveExemption> {
+        let id = id.trim().parse::<u64>().ok()?;
+        match kind {
+            "role" => Some(IdleMoveExemption::Role(RoleId::from(id))),
+            "user" => Some(IdleMoveExemption::User(UserId::from(id))),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IdleMoveExemption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdleMoveExemption::Role(role_id) => write!(f, "role {}", role_id),
+            IdleMoveExemption::User(user_id) => write!(f, "user {}", user_id),
+        }
+    }
+}
+
+// Moves members that have been deafened (by themselves or the server) for longer than their
+// guild's configured time to its AFK channel. Since voice states don't say when someone deafened,
+// that is counted from the first check that sees them deafened, so it's only accurate to within
+// `IDLE_CHECK_INTERVAL` and starts over after a restart.
+pub async fn run_idle_mover(cache_and_http: Arc<CacheAndHttp>, state: PCState) {
+    let cache = &cache_and_http.cache;
+    let mut deafened_since = HashMap::new();
+    // Members that weren't cached and had to be fetched, kept for as long as they stay deafened so
+    // that exempt ones aren't fetched again on every check.
+    let mut fetched = HashMap::new();
+
+    loop {
+        tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+
+        let now = unix_now();
+        let mut still_deafened = HashMap::new();
+
+        for guild_id in cache.guilds() {
+            let deafened = cache
+                .guild_field(guild_id, |g| {
+                    g.voice_states
+                        .values()
+                        .filter(|vs| vs.self_deaf || vs.deaf)
+                        .filter_map(|vs| vs.channel_id.map(|c| (vs.user_id, c)))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+
+            let due = state.read(|d| {
+                due_idle_moves(
+                    d,
+                    guild_id,
+                    &deafened,
+                    &deafened_since,
+                    &mut still_deafened,
+                    now,
+                )
+            });
+
+            for (idle_move, since) in due {
+                let user_id = idle_move.user;
+                let key = (guild_id, user_id);
+
+                // Without the member's roles, role exemptions can't be checked, so members that
+                // aren't cached are fetched, and skipped until the next full `after` if that fails.
+                let member = match cache
+                    .member(guild_id, user_id)
+                    .or_else(|| fetched.get(&key).cloned())
+                {
+                    Some(m) => m,
+                    None => match cache_and_http.http.get_member(guild_id.0, user_id.0).await {
+                        Ok(m) => {
+                            fetched.insert(key, m.clone());
+                            m
+                        }
+                        Err(err) => {
+                            warn!(
+                                guild = guild_id.0, user = user_id.0;
+                                "Error fetching member {} to check idle move exemptions: {:?}",
+                                user_id, err
+                            );
+                            still_deafened.insert(key, now);
+                            continue;
+                        }
+                    },
+                };
+                if member.user.bot
+                    || state.read(|d| d.is_idle_move_exempt(guild_id, user_id, &member.roles))
+                {
+                    continue;
+                }
+                // If moving them fails, e.g. because of missing permissions, wait for another full
+                // `after` before trying again.
+                if !move_member(
+                    &cache_and_http.http,
+                    &state,
+                    guild_id,
+                    idle_move,
+                    now.saturating_sub(since),
+                )
+                .await
+                {
+                    still_deafened.insert(key, now);
+                }
+            }
+        }
+
+        fetched.retain(|key, _| still_deafened.contains_key(key));
+        deafened_since = still_deafened;
+    }
+}
+
+// Finds the guild's members that have been deafened outside of AFK channels for longer than its
+// configured time, and returns their moves along with when each was first seen deafened. Users
+// exempt by ID are left out, role exemptions are up to the caller. Every member that counts as
+// deafened is recorded in `still_deafened`.
+fn due_idle_moves(
+    data: &PCData,
+    guild_id: GuildId,
+    deafened: &[(UserId, ChannelId)],
+    deafened_since: &HashMap<(GuildId, UserId), u64>,
+    still_deafened: &mut HashMap<(GuildId, UserId), u64>,
+    now: u64,
+) -> Vec<(IdleMove, u64)> {
+    let (after, target) = match data
+        .idle_move_after(guild_id)
+        .zip(data.idle_move_target(guild_id))
+    {
+        Some(settings) => settings,
+        None => return vec![],
+    };
+
+    let mut due = vec![];
+    for &(user_id, channel_id) in deafened {
+        if data.is_afk_channel(guild_id, channel_id) {
+            continue;
+        }
+
+        let since = deafened_since
+            .get(&(guild_id, user_id))
+            .copied()
+            .unwrap_or(now);
+        still_deafened.insert((guild_id, user_id), since);
+        if now.saturating_sub(since) >= after && !data.is_idle_move_exempt(guild_id, user_id, &[]) {
+            let idle_move = IdleMove {
+                user: user_id,
+                from: channel_id,
+                to: target,
+                at: now,
+            };
+            due.push((idle_move, since));
+        }
+    }
+
+    due
+}
+
+async fn move_member(
+    http: &Http,
+    state: &PCState,
+    guild_id: GuildId,
+    idle_move: IdleMove,
+    deafened_for: u64,
+) -> bool {
+    match guild_id
+        .move_member(http, idle_move.user, idle_move.to)
+        .await
+    {
+        Ok(_) => {
+            info!(
+                guild = guild_id.0, user = idle_move.user.0, channel = idle_move.from.0;
+                "[idle_move] Moved {} from {} to {} after being deafened for {}s",
+                idle_move.user, idle_move.from, idle_move.to, deafened_for
+            );
+            metrics::IDLE_MOVES.inc(["ok"]);
+            state.update_runtime(|r| r.record_idle_move(guild_id, idle_move));
+            true
+        }
+        Err(err) => {
+            warn!(
+                guild = guild_id.0, user = idle_move.user.0;
+                "Error moving {} to AFK channel {}: {:?}",
+                idle_move.user, idle_move.to, err
+            );
+            metrics::IDLE_MOVES.inc(["error"]);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: GuildId = GuildId(10);
+
+    fn test_data() -> PCData {
+        let mut data = PCData::default();
+        data.set_idle_move_after(GUILD, Some(600));
+        data.add_afk_channel(GUILD, ChannelId(102));
+        data.add_afk_channel(GUILD, ChannelId(101));
+        data
+    }
+
+    #[test]
+    fn members_are_due_once_deafened_long_enough() {
+        let data = test_data();
+        let deafened = [(UserId(1), ChannelId(200)), (UserId(2), ChannelId(200))];
+        let deafened_since =
+            HashMap::from([((GUILD, UserId(1)), 1_000), ((GUILD, UserId(2)), 1_001)]);
+        let mut still_deafened = HashMap::new();
+
+        let moves = due_idle_moves(
+            &data,
+            GUILD,
+            &deafened,
+            &deafened_since,
+            &mut still_deafened,
+            1_600,
+        );
+        let idle_move = IdleMove {
+            user: UserId(1),
+            from: ChannelId(200),
+            to: ChannelId(101),
+            at: 1_600,
+        };
+        assert_eq!(moves, [(idle_move, 1_000)]);
+        assert_eq!(still_deafened, deafened_since);
+    }
+
+    #[test]
+    fn newly_deafened_members_start_counting_now() {
+        let data = test_data();
+        let mut still_deafened = HashMap::new();
+
+        let moves = due_idle_moves(
+            &data,
+            GUILD,
+            &[(UserId(1), ChannelId(200))],
+            &HashMap::new(),
+            &mut still_deafened,
+            1_600,
+        );
+        assert!(moves.is_empty());
+        assert_eq!(still_deafened, HashMap::from([((GUILD, UserId(1)), 1_600)]));
+    }
+
+    #[test]
+    fn exempt_users_and_afk_channels_are_left_alone() {
+        let mut data = test_data();
+        data.add_idle_move_exemption(GUILD, IdleMoveExemption::User(UserId(1)));
+        let deafened = [(UserId(1), ChannelId(200)), (UserId(2), ChannelId(102))];
+        let deafened_since = HashMap::from([((GUILD, UserId(1)), 0), ((GUILD, UserId(2)), 0)]);
+        let mut still_deafened = HashMap::new();
+
+        let moves = due_idle_moves(
+            &data,
+            GUILD,
+            &deafened,
+            &deafened_since,
+            &mut still_deafened,
+            1_600,
+        );
+        assert!(moves.is_empty());
+        assert_eq!(still_deafened, HashMap::from([((GUILD, UserId(1)), 0)]));
+    }
+
+    #[test]
+    fn nothing_is_due_without_a_time_and_an_afk_channel() {
+        let mut data = PCData::default();
+        data.set_idle_move_after(GUILD, Some(600));
+        let deafened = [(UserId(1), ChannelId(200))];
+        let deafened_since = HashMap::from([((GUILD, UserId(1)), 0)]);
+        let mut still_deafened = HashMap::new();
+
+        assert_eq!(
+            due_idle_moves(
+                &data,
+                GUILD,
+                &deafened,
+                &deafened_since,
+                &mut still_deafened,
+                1_600
+            ),
+            []
+        );
+
+        let mut data = test_data();
+        data.set_idle_move_after(GUILD, None);
+        assert_eq!(
+            due_idle_moves(
+                &data,
+                GUILD,
+                &deafened,
+                &deafened_since,
+                &mut still_deafened,
+                1_600
+            ),
+            []
+        );
+        assert!(still_deafened.is_empty());
+    }
+}
@@ -1,15 +1,32 @@
-use crate::model::PCData;
-use crate::storage;
+use crate::afk::{self, IdleMoveExemption};
+use crate::games::{self, Game, GameResult};
+use crate::gc;
+use crate::health::GatewayEvents;
+use crate::history::{Decision, JoinEvent, NotificationHistory};
+use crate::lobbies::{self, RoomCooldowns};
+use crate::logging;
+use crate::metrics;
+use crate::model::{unix_now, PAUSE_AFTER_FAILURES};
+use crate::outbox::{self, Delivery, DmError, Notification, Outbox};
+use crate::sessions;
+use crate::spoilers::{SpoilerRule, Webhooks};
+use crate::state::PCState;
+use crate::suppression::{self, SuppressionMode, SuppressionRule};
+use crate::voice::{self, VoiceLocation};
 
 use log::{debug, error, info, warn};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use serenity::{
     async_trait,
     model::{
-        channel::{Channel, ChannelType, GuildChannel, Message},
-        event::ResumedEvent,
+        channel::{Channel, ChannelType, GuildChannel, Message, MessageFlags},
+        event::{MessageUpdateEvent, ResumedEvent},
         gateway::Ready,
-        guild::{Guild, GuildInfo},
+        guild::{Guild, GuildInfo, Member, PartialGuild, UnavailableGuild},
         id::{ChannelId, GuildId, UserId},
         user::{CurrentUser, OnlineStatus, User},
         voice::VoiceState,
@@ -20,10 +37,44 @@ use serenity::{
 pub struct DataKey;
 
 impl TypeMapKey for DataKey {
-    type Value = PCData;
+    type Value = PCState;
 }
 
-pub struct Handler;
+pub struct OutboxKey;
+
+impl TypeMapKey for OutboxKey {
+    type Value = Outbox;
+}
+
+// Only holds the lock on the context's `TypeMap` for as long as it takes to clone the handle.
+async fn get_state(ctx: &Context) -> PCState {
+    ctx.data.read().await.get::<DataKey>().unwrap().clone()
+}
+
+async fn get_outbox(ctx: &Context) -> Outbox {
+    ctx.data.read().await.get::<OutboxKey>().unwrap().clone()
+}
+
+pub struct Handler {
+    // Set once the bot has been asked to shut down, after which no new commands are accepted.
+    shutting_down: Arc<AtomicBool>,
+    gateway_events: Arc<GatewayEvents>,
+    history: NotificationHistory,
+    webhooks: Webhooks,
+    room_cooldowns: RoomCooldowns,
+}
+
+impl Handler {
+    pub fn new(shutting_down: Arc<AtomicBool>, gateway_events: Arc<GatewayEvents>) -> Handler {
+        Handler {
+            shutting_down,
+            gateway_events,
+            history: NotificationHistory::default(),
+            webhooks: Webhooks::default(),
+            room_cooldowns: RoomCooldowns::default(),
+        }
+    }
+}
 
 #[async_trait]
 impl EventHandler for Handler {
@@ -34,48 +85,311 @@ impl EventHandler for Handler {
             return;
         }
 
-        if !msg.is_private() {
-            suppress_embeds_if_necessary(&ctx, &mut msg).await;
+        if let Some(guild_id) = msg.guild_id {
+            if let Some(result) = record_game_result(&ctx, guild_id, &msg).await {
+                let spoiler = get_state(&ctx)
+                    .await
+                    .read(|d| d.should_spoiler(guild_id, result.game.name, msg.channel_id));
+                if spoiler && repost_with_spoilers(&ctx, guild_id, &msg, &self.webhooks).await {
+                    return;
+                }
+            }
+            suppress_embeds_if_necessary(&ctx, guild_id, &mut msg).await;
+            return;
+        }
+
+        if self.shutting_down.load(Ordering::SeqCst) {
+            info!("Ignoring message from {} while shutting down.", msg.author);
             return;
         }
 
-        info!("Handling message: {}: {}", msg.author, msg.content);
+        let event_id = logging::next_event_id();
+        let user_id = msg.author.id.0;
+        info!(
+            event_id = event_id, user = user_id;
+            "Handling message: {}: {}",
+            msg.author,
+            logging::loggable_content(&msg.content)
+        );
 
-        if msg.content.starts_with("!add-vc-notify") {
-            handle_add_vc_notify(&ctx, msg).await;
+        let (command, outcome) = if msg.content.starts_with("!add-vc-notify") {
+            ("add-vc-notify", handle_add_vc_notify(&ctx, msg).await)
         } else if msg.content.starts_with("!remove-vc-notify") {
-            handle_remove_vc_notify(&ctx, msg).await;
+            ("remove-vc-notify", handle_remove_vc_notify(&ctx, msg).await)
+        } else if msg.content.starts_with("!list-vc-notify") {
+            ("list-vc-notify", handle_list_vc_notify(&ctx, msg).await)
         } else if msg.content.starts_with("!add-afk-channel") {
-            handle_add_afk_channel(&ctx, msg).await;
+            ("add-afk-channel", handle_add_afk_channel(&ctx, msg).await)
+        } else if msg.content.starts_with("!why") {
+            ("why", handle_why(&ctx, msg, &self.history).await)
+        } else if msg.content.starts_with("!leaderboard") {
+            ("leaderboard", handle_leaderboard(&ctx, msg).await)
+        } else if msg.content.starts_with("!streaks") {
+            ("streaks", handle_streaks(&ctx, msg).await)
+        } else if msg.content.starts_with("!voice-time") {
+            ("voice-time", handle_voice_time(&ctx, msg).await)
+        } else if msg.content.starts_with("!last-night") {
+            ("last-night", handle_last_night(&ctx, msg).await)
+        } else if msg.content.starts_with("!channel-activity") {
+            ("channel-activity", handle_channel_activity(&ctx, msg).await)
+        } else if msg.content.starts_with("!voice-tracking") {
+            ("voice-tracking", handle_voice_tracking(&ctx, msg).await)
+        } else if msg.content.starts_with("!whos-on") {
+            ("whos-on", handle_whos_on(&ctx, msg).await)
+        } else if msg.content.starts_with("!digest") {
+            ("digest", handle_digest(&ctx, msg).await)
+        } else if msg.content.starts_with("!set-fallback-channel") {
+            (
+                "set-fallback-channel",
+                handle_set_fallback_channel(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!remove-fallback-channel") {
+            (
+                "remove-fallback-channel",
+                handle_remove_fallback_channel(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!list-undeliverable") {
+            (
+                "list-undeliverable",
+                handle_list_undeliverable(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!ignore-discord-afk-channel") {
+            (
+                "ignore-discord-afk-channel",
+                handle_ignore_discord_afk_channel(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!add-lobby-channel") {
+            (
+                "add-lobby-channel",
+                handle_add_lobby_channel(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!remove-lobby-channel") {
+            (
+                "remove-lobby-channel",
+                handle_remove_lobby_channel(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!set-idle-move") {
+            ("set-idle-move", handle_set_idle_move(&ctx, msg).await)
+        } else if msg.content.starts_with("!add-idle-move-exemption") {
+            (
+                "add-idle-move-exemption",
+                handle_change_idle_move_exemption(&ctx, msg, true).await,
+            )
+        } else if msg.content.starts_with("!remove-idle-move-exemption") {
+            (
+                "remove-idle-move-exemption",
+                handle_change_idle_move_exemption(&ctx, msg, false).await,
+            )
+        } else if msg.content.starts_with("!list-idle-moves") {
+            ("list-idle-moves", handle_list_idle_moves(&ctx, msg).await)
         } else if msg.content.starts_with("!remove-afk-channel") {
-            handle_remove_afk_channel(&ctx, msg).await;
+            (
+                "remove-afk-channel",
+                handle_remove_afk_channel(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!list-suppression-rules") {
+            (
+                "list-suppression-rules",
+                handle_list_suppression_rules(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!add-suppression-rule") {
+            (
+                "add-suppression-rule",
+                handle_add_suppression_rule(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!remove-suppression-rule") {
+            (
+                "remove-suppression-rule",
+                handle_remove_suppression_rule(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!list-spoiler-rules") {
+            (
+                "list-spoiler-rules",
+                handle_list_spoiler_rules(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!add-spoiler-rule") {
+            ("add-spoiler-rule", handle_add_spoiler_rule(&ctx, msg).await)
+        } else if msg.content.starts_with("!remove-spoiler-rule") {
+            (
+                "remove-spoiler-rule",
+                handle_remove_spoiler_rule(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!set-suppression-mode") {
+            (
+                "set-suppression-mode",
+                handle_set_suppression_mode(&ctx, msg).await,
+            )
+        } else if msg.content.starts_with("!reset-suppression-rules") {
+            (
+                "reset-suppression-rules",
+                handle_reset_suppression_rules(&ctx, msg).await,
+            )
         } else {
             // !help, or an unknown command, also print help for now.
-            handle_help(&ctx, msg).await;
+            ("help", handle_help(&ctx, msg).await)
+        };
+
+        info!(
+            event_id = event_id, user = user_id, command = command, outcome = outcome.label();
+            "[command] {}: {}",
+            command,
+            outcome.label()
+        );
+        metrics::COMMANDS.inc([command, outcome.label()]);
+    }
+
+    // Discord often only attaches embeds to a message after it was posted, so suppression is
+    // checked again whenever a message in a server changes.
+    async fn message_update(
+        &self,
+        ctx: Context,
+        _old_if_available: Option<Message>,
+        new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        let guild_id = match event.guild_id {
+            Some(g) => g,
+            None => return,
+        };
+
+        if event
+            .flags
+            .is_some_and(|f| f.contains(MessageFlags::SUPPRESS_EMBEDS))
+        {
+            return;
+        }
+
+        // Updates only contain what changed. Fetching the whole message is only worth it if a rule
+        // matches one of the new links.
+        let links = suppression::message_links(
+            event.content.as_deref().unwrap_or_default(),
+            event.embeds.as_deref().unwrap_or_default(),
+        );
+        let state = get_state(&ctx).await;
+        let any_match = state.read(|d| {
+            suppression::should_suppress(
+                d.suppression_rules(guild_id),
+                SuppressionMode::Any,
+                &links,
+            )
+        });
+        if !any_match {
+            return;
+        }
+
+        let mut msg = match new {
+            Some(m) => m,
+            None => match event.channel_id.message(&ctx.http, event.id).await {
+                Ok(m) => m,
+                Err(err) => {
+                    warn!(
+                        guild = guild_id.0, channel = event.channel_id.0;
+                        "Error fetching updated message {}: {:?}",
+                        event.id,
+                        err
+                    );
+                    return;
+                }
+            },
+        };
+
+        if msg.is_own(&ctx.cache) {
+            return;
         }
+
+        suppress_embeds_if_necessary(&ctx, guild_id, &mut msg).await;
     }
 
     async fn voice_state_update(&self, ctx: Context, old: Option<VoiceState>, new: VoiceState) {
+        metrics::VOICE_STATE_UPDATES.inc();
+
+        let event_id = logging::next_event_id();
+        let channel_id = new.channel_id.unwrap_or_else(|| ChannelId::from(0));
         info!(
+            event_id = event_id,
+            guild = new.guild_id.unwrap_or_else(|| GuildId::from(0)).0,
+            channel = channel_id.0,
+            user = new.user_id.0;
             "[voice_state_update] {}: {}",
             new.user_id,
-            new.channel_id.unwrap_or_else(|| ChannelId::from(0))
+            channel_id
         );
 
+        if let Some(guild_id) = new.guild_id {
+            let state = get_state(&ctx).await;
+            sessions::voice_channel_changed(&state, guild_id, new.user_id, new.channel_id);
+            lobbies::voice_channel_changed(
+                &ctx,
+                &state,
+                &self.room_cooldowns,
+                guild_id,
+                new.user_id,
+                old.as_ref().and_then(|o| o.channel_id),
+                new.channel_id,
+            )
+            .await;
+        }
+
         if !is_join_event(&ctx, &old, &new).await {
-            debug!("[voice_state_update] Not sending notifs because !is_join_event.");
+            debug!(
+                event_id = event_id;
+                "[voice_state_update] Not sending notifs because !is_join_event."
+            );
             return;
         }
 
-        send_notifications(&ctx, &new).await;
+        metrics::JOINS_DETECTED.inc();
+        send_notifications(&ctx, &new, event_id, &self.history).await;
     }
 
-    async fn cache_ready(&self, _ctx: Context, _guilds: Vec<GuildId>) {
+    async fn cache_ready(&self, ctx: Context, _guilds: Vec<GuildId>) {
         info!("[cache_ready]");
+        self.gateway_events.set_cache_ready();
+        let state = get_state(&ctx).await;
+        gc::reconcile(&state, &ctx.cache);
+        sessions::reconcile(&state, &ctx.cache);
+        lobbies::clean_up(&ctx, &state).await;
+    }
+
+    async fn channel_delete(&self, ctx: Context, channel: &GuildChannel) {
+        info!("[channel_delete] {} {}", channel.guild_id, channel.id);
+        gc::channel_deleted(&get_state(&ctx).await, channel.guild_id, channel.id);
+    }
+
+    async fn guild_delete(&self, ctx: Context, incomplete: UnavailableGuild, _full: Option<Guild>) {
+        info!(
+            "[guild_delete] {} (unavailable: {})",
+            incomplete.id, incomplete.unavailable
+        );
+        gc::guild_deleted(&get_state(&ctx).await, &incomplete);
+    }
+
+    async fn guild_member_removal(
+        &self,
+        ctx: Context,
+        guild_id: GuildId,
+        user: User,
+        _member: Option<Member>,
+    ) {
+        debug!("[guild_member_removal] {} {}", guild_id, user.id);
+        gc::member_removed(&get_state(&ctx).await, &ctx.cache, guild_id, user.id);
+    }
+
+    async fn guild_create(&self, ctx: Context, guild: Guild, _is_new: bool) {
+        self.gateway_events.guild_available(guild.id);
+
+        let state = get_state(&ctx).await;
+        update_discord_afk_channel(&state, guild.id, guild.afk_channel_id);
+        offer_afk_suggestions(&ctx, &state, &guild).await;
+    }
+
+    async fn guild_update(&self, ctx: Context, _old: Option<Guild>, new: PartialGuild) {
+        update_discord_afk_channel(&get_state(&ctx).await, new.id, new.afk_channel_id);
     }
 
     async fn guild_unavailable(&self, _ctx: Context, guild_id: GuildId) {
         info!("[guild_unavailable] {}", guild_id);
+        self.gateway_events.guild_unavailable(guild_id);
     }
 
     async fn ready(&self, _ctx: Context, data_about_bot: Ready) {
@@ -98,31 +412,165 @@ impl EventHandler for Handler {
     }
 }
 
-async fn suppress_embeds_if_necessary(ctx: &Context, msg: &mut Message) {
-    const SUPPRESSED_EMBEDS: [&'static str; 7] = [
-        "https://heardle.app/",
-        "https://www.heardle.app/",
-        "https://framed.wtf/",
-        "https://moviedle.app/",
-        "https://oec.world/en/tradle",
-        "https://posterdle.com/",
-        "https://histordle.com/yeardle",
-    ];
+async fn suppress_embeds_if_necessary(ctx: &Context, guild_id: GuildId, msg: &mut Message) {
+    if msg
+        .flags
+        .is_some_and(|f| f.contains(MessageFlags::SUPPRESS_EMBEDS))
+    {
+        return;
+    }
 
     debug!(
         "Checking whether embed should be suppressed: {:?}",
         msg.embeds
     );
 
-    if let [embed] = &msg.embeds[..] {
-        if let Some(url) = embed.url.as_deref() {
-            if SUPPRESSED_EMBEDS.contains(&url) {
-                if let Err(e) = msg.suppress_embeds(&ctx.http).await {
-                    error!("Error suppressing embed: {:?}", e);
-                }
+    let state = get_state(ctx).await;
+    let suppress = {
+        let links = suppression::message_links(&msg.content, &msg.embeds);
+        state.read(|d| {
+            suppression::should_suppress(
+                d.suppression_rules(guild_id),
+                d.suppression_mode(guild_id),
+                &links,
+            )
+        })
+    };
+
+    if suppress {
+        if let Err(e) = msg.suppress_embeds(&ctx.http).await {
+            error!(
+                guild = guild_id.0, channel = msg.channel_id.0;
+                "Error suppressing embed: {:?}",
+                e
+            );
+            metrics::EMBED_SUPPRESSIONS.inc(["error"]);
+        } else {
+            metrics::EMBED_SUPPRESSIONS.inc(["ok"]);
+        }
+    }
+}
+
+// Returns the result if the message is a daily puzzle result post, even if it wasn't recorded
+// because the author already posted one for the same puzzle.
+async fn record_game_result(ctx: &Context, guild_id: GuildId, msg: &Message) -> Option<GameResult> {
+    // This includes the bot's own reposts, which are posted through webhooks.
+    if msg.author.bot {
+        return None;
+    }
+
+    let result = games::parse_result(&msg.content)?;
+
+    let recorded = get_state(ctx)
+        .await
+        .update_runtime(|r| r.record_game_result(guild_id, msg.author.id, &result));
+
+    if recorded {
+        info!(
+            guild = guild_id.0, user = msg.author.id.0;
+            "[game_result] {} #{} by {}: {:?}",
+            result.game.name,
+            result.day,
+            msg.author.id,
+            result.score
+        );
+        metrics::GAME_RESULTS.inc([result.game.name]);
+    }
+
+    Some(result)
+}
+
+// Reposts a result post as its author, with the result grid hidden behind spoiler tags, and
+// deletes the original. Returns false if the message was left alone.
+async fn repost_with_spoilers(
+    ctx: &Context,
+    guild_id: GuildId,
+    msg: &Message,
+    webhooks: &Webhooks,
+) -> bool {
+    // Webhooks can't repost attachments without downloading them first.
+    if !msg.attachments.is_empty() {
+        return false;
+    }
+
+    let webhook = match webhooks.get(ctx, msg.channel_id).await {
+        Ok(w) => w,
+        Err(err) => {
+            warn!(
+                guild = guild_id.0, channel = msg.channel_id.0;
+                "Error getting webhook to repost result with spoilers: {:?}",
+                err
+            );
+            metrics::SPOILER_REPOSTS.inc(["error"]);
+            return false;
+        }
+    };
+
+    let name = msg
+        .member
+        .as_ref()
+        .and_then(|m| m.nick.clone())
+        .unwrap_or_else(|| msg.author.name.clone());
+
+    let result = webhook
+        .execute(&ctx.http, true, |w| {
+            w.username(name)
+                .avatar_url(msg.author.face())
+                .content(games::hide_grid(&msg.content))
+                .flags(MessageFlags::SUPPRESS_EMBEDS)
+                .allowed_mentions(|m| m.empty_parse())
+        })
+        .await;
+
+    let repost = match result {
+        Ok(repost) => repost,
+        Err(err) => {
+            warn!(
+                guild = guild_id.0, channel = msg.channel_id.0;
+                "Error reposting result with spoilers: {:?}",
+                err
+            );
+            // The webhook might have been deleted.
+            webhooks.forget(msg.channel_id);
+            metrics::SPOILER_REPOSTS.inc(["error"]);
+            return false;
+        }
+    };
+
+    if let Err(err) = msg.channel_id.delete_message(&ctx.http, msg.id).await {
+        error!(
+            guild = guild_id.0, channel = msg.channel_id.0;
+            "Error deleting message {} after reposting it with spoilers: {:?}",
+            msg.id,
+            err
+        );
+        metrics::SPOILER_REPOSTS.inc(["error"]);
+
+        // Don't leave the result posted twice. The original is then treated like any other
+        // result post.
+        if let Some(repost) = repost {
+            if let Err(err) = webhook.delete_message(&ctx.http, repost.id).await {
+                error!(
+                    guild = guild_id.0, channel = msg.channel_id.0;
+                    "Error deleting repost {} of message {}: {:?}",
+                    repost.id,
+                    msg.id,
+                    err
+                );
             }
         }
+        return false;
     }
+
+    info!(
+        guild = guild_id.0, channel = msg.channel_id.0, user = msg.author.id.0;
+        "[spoiler] Reposted {} by {} with spoilers",
+        msg.id,
+        msg.author.id
+    );
+    metrics::SPOILER_REPOSTS.inc(["ok"]);
+
+    true
 }
 
 async fn is_join_event(ctx: &Context, old: &Option<VoiceState>, new_state: &VoiceState) -> bool {
@@ -132,15 +580,22 @@ async fn is_join_event(ctx: &Context, old: &Option<VoiceState>, new_state: &Voic
         Some(id) => id,
     };
 
-    let guild = match get_guild_from_channel(ctx, new_channel).await {
-        None => return false,
+    // Voice states sent by the gateway carry their guild, so we only have to look it up for
+    // the rare case where they don't.
+    let guild = match new_state.guild_id {
         Some(g) => g,
+        None => match get_guild_from_channel(ctx, new_channel).await {
+            None => return false,
+            Some(g) => g,
+        },
     };
-    let data = ctx.data.read().await;
-    let pc_data = data.get::<DataKey>().unwrap();
+    let state = get_state(ctx).await;
 
-    // If the new channels is an AFK channel, this shouldn't count as a join event.
-    if pc_data.is_afk_channel(guild, new_channel) {
+    // If the new channels is an AFK channel, this shouldn't count as a join event. Neither should
+    // joining a lobby, since the user gets moved to their own channel right away.
+    if state
+        .read(|d| d.is_afk_channel(guild, new_channel) || d.is_lobby_channel(guild, new_channel))
+    {
         return false;
     }
 
@@ -156,8 +611,10 @@ async fn is_join_event(ctx: &Context, old: &Option<VoiceState>, new_state: &Voic
         Some(id) => id,
     };
 
-    // If the old channel is an AFK channel, this should count as joining.
-    if pc_data.is_afk_channel(guild, old_channel) {
+    // If the old channel is an AFK channel or a lobby, this should count as joining.
+    if state
+        .read(|d| d.is_afk_channel(guild, old_channel) || d.is_lobby_channel(guild, old_channel))
+    {
         return true;
     }
 
@@ -165,30 +622,68 @@ async fn is_join_event(ctx: &Context, old: &Option<VoiceState>, new_state: &Voic
     false
 }
 
-async fn handle_help(ctx: &Context, msg: Message) {
+// How handling a command ended, as recorded in the commands metric.
+#[derive(Clone, Copy)]
+enum CommandOutcome {
+    Ok,
+    NoChange,
+    InvalidArgument,
+    NotPermitted,
+}
+
+impl CommandOutcome {
+    fn label(self) -> &'static str {
+        match self {
+            CommandOutcome::Ok => "ok",
+            CommandOutcome::NoChange => "no_change",
+            CommandOutcome::InvalidArgument => "invalid_argument",
+            CommandOutcome::NotPermitted => "not_permitted",
+        }
+    }
+}
+
+async fn handle_help(ctx: &Context, msg: Message) -> CommandOutcome {
     send_msg(
         ctx,
         &msg.author,
         concat!(
-            "Hello! I currently support two commands:\n",
+            "Hello! I currently support these commands:\n",
             "- `!add-vc-notify`\n",
-            "- `!remove-vc-notify\n`",
+            "- `!remove-vc-notify`\n",
+            "- `!list-vc-notify`\n",
+            "- `!why`: Shows whether you were notified about recent joins, and why (not)\n",
+            "- `!leaderboard` and `!streaks`: Daily puzzle results posted on a server\n",
+            "- `!whos-on`: Who is in voice channels on the servers we share right now\n",
+            "- `!voice-time`: How long you were in voice channels this week\n",
+            "- `!last-night` and `!channel-activity`: Who was in voice channels on a server\n",
+            "- `!voice-tracking on|off`: Whether your time in voice channels is recorded\n",
+            "- `!digest on|off`: Weekly summaries of activity in your subscribed channels\n",
             "Send any command by itself to get more information!"
         ),
     )
     .await;
+
+    CommandOutcome::Ok
 }
 
-async fn send_notifications(ctx: &Context, voice_state: &VoiceState) {
+// Every log line is tagged with `event_id`, so that it is possible to trace why a specific user was
+// or wasn't notified about a specific join.
+async fn send_notifications(
+    ctx: &Context,
+    voice_state: &VoiceState,
+    event_id: u64,
+    history: &NotificationHistory,
+) {
     let channel_id = match voice_state.channel_id {
         None => return,
         Some(id) => id,
     };
 
-    let data = ctx.data.read().await;
-    let pc_data = data.get::<DataKey>().unwrap();
+    let state = get_state(ctx).await;
 
-    let channel = match channel_id.to_channel(&ctx.http).await {
+    // Passing the whole context (instead of just `ctx.http`) makes serenity check the cache
+    // first and only fall back to an HTTP request if the channel isn't cached.
+    let channel = match channel_id.to_channel(ctx).await {
         Err(_) => return,
         Ok(c) => c,
     };
@@ -198,75 +693,154 @@ async fn send_notifications(ctx: &Context, voice_state: &VoiceState) {
         Some(g) => g,
     };
 
-    let channel_members = guild_channel.members(&ctx.cache).await.unwrap_or_else(|e| {
-        warn!("Failed to get members in channel: {:?}", e);
-        vec![]
-    });
-
-    let guild = match guild_channel.guild(&ctx.cache) {
+    // Only the fields needed are read from the cache, instead of cloning the whole guild.
+    let guild_id = guild_channel.guild_id;
+    let guild_name = match ctx.cache.guild_field(guild_id, |g| g.name.clone()) {
         None => return,
-        Some(g) => g,
+        Some(name) => name,
     };
 
-    let joined_user = voice_state.user_id.to_user(&ctx.http).await.ok();
+    let joined_user = match &voice_state.member {
+        Some(member) => Some(member.user.clone()),
+        None => voice_state.user_id.to_user(ctx).await.ok(),
+    };
 
     let joined_user_name = joined_user
         .clone()
         .map(|u| u.name)
         .unwrap_or_else(|| "Someone".to_string());
 
+    let event = Arc::new(JoinEvent {
+        at: SystemTime::now(),
+        guild_name: guild_name.clone(),
+        channel_name: guild_channel.name.clone(),
+        joined_user_name: joined_user_name.clone(),
+    });
+
+    let outbox = get_outbox(ctx).await;
+    let mut queued = Vec::new();
     let mut notified_users = Vec::new();
 
     debug!(
+        event_id = event_id, guild = guild_id.0, channel = guild_channel.id.0;
         "[send_notifications] Determining notifs for {:?} having joined {:?}",
         joined_user, guild_channel
     );
 
-    let subscribed_users = pc_data.find_subscribed_users(guild.id, guild_channel.id);
+    // Temporary channels created from a lobby notify the lobby's subscribers.
+    let subscribed_users = state.read(|d| {
+        let channel_id = d
+            .temp_channel_lobby(guild_id, guild_channel.id)
+            .unwrap_or(guild_channel.id);
+        d.find_subscribed_users(guild_id, channel_id)
+            .map(|users| users.collect::<Vec<_>>())
+    });
     if let Some(subscribed_users) = subscribed_users {
-        'user: for user_id in subscribed_users {
-            debug!("Testing {:?} from subscribed_users", user_id);
+        // What's needed about the subscribers, copied out of the cache in one go.
+        let (voice_states, statuses, voice_channels) = ctx
+            .cache
+            .guild_field(guild_id, |g| {
+                let voice_states = subscribed_users
+                    .iter()
+                    .filter_map(|u| g.voice_states.get(u).map(|vs| (*u, vs.clone())))
+                    .collect::<HashMap<_, _>>();
+                let statuses = subscribed_users
+                    .iter()
+                    .filter_map(|u| g.presences.get(u).map(|p| (*u, p.status)))
+                    .collect::<HashMap<_, _>>();
+                let voice_channels = voice::regular_voice_channels(&voice_states, &g.channels);
+                (voice_states, statuses, voice_channels)
+            })
+            .unwrap_or_default();
+
+        for user_id in subscribed_users {
+            debug!(
+                event_id = event_id, user = user_id.0;
+                "Testing {:?} from subscribed_users",
+                user_id
+            );
             if user_id == voice_state.user_id {
                 // Don't notify users that they joined themselves.
-                debug!("Not notifying {:?} because they are the joiner.", user_id);
-                continue;
-            }
-
-            if channel_members.iter().any(|m| m.user.id == user_id) {
-                // Don't notify users if they are already in the voice channel themselves.
                 debug!(
-                    "Not notifying {:?} because they are in the channel.",
+                    event_id = event_id, user = user_id.0;
+                    "Not notifying {:?} because they are the joiner.",
                     user_id
                 );
+                record_decision(history, &event, user_id, Decision::IsJoiner);
                 continue;
             }
 
-            // TODO: Is there no better way of determining this?
-            // TODO: This was a bit prettier as an iterator, but not sure how that plays with async
-            for (_, c) in guild.channels.iter() {
-                if let Channel::Guild(c) = c {
-                    if skip_because_in_channel(ctx, c, &guild, user_id, pc_data).await {
-                        debug!(
-                            "Not notifying {:?} because they are in another non-AFK channel.",
-                            user_id
-                        );
-                        continue 'user;
-                    }
+            let location = state.read(|d| {
+                voice::locate_user(
+                    &voice_states,
+                    user_id,
+                    guild_channel.id,
+                    |c| voice_channels.contains(&c),
+                    |c| d.is_afk_channel(guild_id, c),
+                )
+            });
+            match location {
+                VoiceLocation::InJoinedChannel => {
+                    // Don't notify users if they are already in the voice channel themselves.
+                    debug!(
+                        event_id = event_id, user = user_id.0;
+                        "Not notifying {:?} because they are in the channel.",
+                        user_id
+                    );
+                    record_decision(history, &event, user_id, Decision::InJoinedChannel);
+                    continue;
+                }
+                VoiceLocation::InOtherChannel => {
+                    // Don't notify users if they are already in *any* voice channel on the same
+                    // server, unless it's an AFK channel.
+                    debug!(
+                        event_id = event_id, user = user_id.0;
+                        "Not notifying {:?} because they are in another non-AFK channel.",
+                        user_id
+                    );
+                    record_decision(history, &event, user_id, Decision::InOtherChannel);
+                    continue;
                 }
+                VoiceLocation::NotInVoice
+                | VoiceLocation::InAfkChannel
+                | VoiceLocation::InOtherKindOfChannel => (),
             }
 
-            let presence = match guild.presences.get(&user_id) {
+            let status = match statuses.get(&user_id) {
                 None => {
                     debug!(
+                        event_id = event_id, user = user_id.0;
                         "Not notifying {:?} because their presence is None.",
                         user_id
                     );
+                    record_decision(history, &event, user_id, Decision::NoPresence);
                     continue;
                 }
-                Some(p) => p,
+                Some(&s) => s,
             };
 
-            let send_notif = match presence.status {
+            if let Some(failures) = state.read_runtime(|r| r.delivery_failures(user_id)) {
+                if failures.paused {
+                    debug!(
+                        event_id = event_id, user = user_id.0;
+                        "Not notifying {:?} because their notifications are paused.",
+                        user_id
+                    );
+                    record_decision(history, &event, user_id, Decision::Paused);
+                    continue;
+                }
+                if !failures.may_retry(unix_now()) {
+                    debug!(
+                        event_id = event_id, user = user_id.0;
+                        "Not notifying {:?} because DMs to them failed recently.",
+                        user_id
+                    );
+                    record_decision(history, &event, user_id, Decision::BackingOff);
+                    continue;
+                }
+            }
+
+            let send_notif = match status {
                 OnlineStatus::Online => true,
                 OnlineStatus::Idle => true,
                 OnlineStatus::DoNotDisturb => false,
@@ -276,38 +850,83 @@ async fn send_notifications(ctx: &Context, voice_state: &VoiceState) {
             };
 
             if send_notif {
-                let user = match user_id.to_user(&ctx.http).await {
+                let user = match user_id.to_user(ctx).await {
                     Err(e) => {
                         debug!(
+                            event_id = event_id, user = user_id.0;
                             "Not notifying {:?} because they could not be turned into a User: {:?}",
                             user_id, e
                         );
+                        record_decision(history, &event, user_id, Decision::UnknownUser);
                         continue;
                     }
                     Ok(u) => u,
                 };
 
-                send_msg(
-                    &ctx,
-                    &user,
-                    &format!(
-                        "{} joined {} on {}!",
-                        joined_user_name, guild_channel.name, guild.name
-                    ),
-                )
-                .await;
-
-                notified_users.push(user);
+                let delivery = outbox.enqueue(Notification {
+                    recipient: user.clone(),
+                    guild_id,
+                    channel_id: guild_channel.id,
+                    guild_name: guild_name.clone(),
+                    channel_name: guild_channel.name.clone(),
+                    joined_user_id: voice_state.user_id,
+                    joined_user_name: joined_user_name.clone(),
+                });
+                queued.push((user, delivery));
             } else {
                 debug!(
+                    event_id = event_id, user = user_id.0;
                     "Not notifying {:?} because send_notif is false with presence.status {:?}",
-                    user_id, presence.status
+                    user_id, status
                 );
+                record_decision(history, &event, user_id, Decision::Status(status));
+            }
+        }
+
+        // All notifications are queued before waiting for any of them, so that they are sent
+        // concurrently.
+        for (user, delivery) in queued {
+            let user_id = user.id;
+            let delivery = delivery.await.unwrap_or(Delivery::Failed(DmError::Other));
+
+            match delivery {
+                Delivery::Sent => {
+                    debug!(event_id = event_id, user = user_id.0; "Notified {:?}.", user_id);
+                    record_decision(history, &event, user_id, Decision::Notified);
+                    notified_users.push(user);
+                }
+                Delivery::Stale => {
+                    debug!(
+                        event_id = event_id, user = user_id.0;
+                        "Not notifying {:?} because the joiner left before it was sent.",
+                        user_id
+                    );
+                    record_decision(history, &event, user_id, Decision::Stale);
+                }
+                Delivery::Failed(err) => {
+                    debug!(
+                        event_id = event_id, user = user_id.0;
+                        "Not notifying {:?} because sending the DM failed.",
+                        user_id
+                    );
+                    record_decision(history, &event, user_id, Decision::DeliveryFailed);
+
+                    if let DmError::Undeliverable(failures) = err {
+                        if failures.paused && failures.consecutive == PAUSE_AFTER_FAILURES {
+                            info!(
+                                event_id = event_id, user = user_id.0;
+                                "Pausing notifications for {:?} because DMs to them keep failing.",
+                                user_id
+                            );
+                            send_fallback_notice(ctx, guild_id, user_id).await;
+                        }
+                    }
+                }
             }
         }
 
         if let Some(joined_user) = joined_user {
-            if pc_data.should_send_notif_copies(joined_user.id, guild.id) {
+            if state.read(|d| d.should_send_notif_copies(joined_user.id, guild_id)) {
                 let user_list = match notified_users {
                     _ if notified_users.is_empty() => "nobody".to_string(),
                     notified_users => notified_users
@@ -318,7 +937,7 @@ async fn send_notifications(ctx: &Context, voice_state: &VoiceState) {
                 };
 
                 send_msg(
-                    &ctx,
+                    ctx,
                     &joined_user,
                     &format!("Sent join notifications to {}!", user_list),
                 )
@@ -328,54 +947,53 @@ async fn send_notifications(ctx: &Context, voice_state: &VoiceState) {
     }
 }
 
-// Don't notify users if they are already in *any* voice channel on the same
-// server, unless it's an AFK channel.
-async fn skip_because_in_channel(
-    ctx: &Context,
-    channel: &GuildChannel,
-    guild: &Guild,
+fn record_decision(
+    history: &NotificationHistory,
+    event: &Arc<JoinEvent>,
     user_id: UserId,
-    pc_data: &PCData,
-) -> bool {
-    let k = channel.kind == ChannelType::Voice;
-    let afk = pc_data.is_afk_channel(guild.id, channel.id);
-    let member = is_member(channel, user_id, ctx).await;
-    k && !afk && member
+    decision: Decision,
+) {
+    match decision.skip_reason() {
+        None => metrics::NOTIFICATIONS_SENT.inc(),
+        Some(reason) => metrics::NOTIFICATIONS_SKIPPED.inc([reason]),
+    }
+
+    history.record(user_id, event, decision);
 }
 
-async fn handle_add_vc_notify(ctx: &Context, msg: Message) {
-    let mut data = ctx.data.write().await;
-    let pc_data = data.get_mut::<DataKey>().unwrap();
+async fn handle_add_vc_notify(ctx: &Context, msg: Message) -> CommandOutcome {
+    let state = get_state(ctx).await;
 
     let author = &msg.author;
     let id = author.id;
 
-    let channel = match get_channel_from_msg(&ctx, &msg).await {
+    let channel = match get_channel_from_msg(ctx, &msg).await {
         Some(c) => c,
-        None => return,
+        None => return CommandOutcome::InvalidArgument,
     };
     let guild_channel = match channel.guild() {
         Some(gc) => gc,
         None => {
             send_msg(
-                &ctx,
+                ctx,
                 author,
                 "Could not find server that the channel belongs to!",
             )
             .await;
-            return;
+            return CommandOutcome::InvalidArgument;
         }
     };
 
-    let guild_name = guild_channel
-        .guild(&ctx.cache)
-        .map(|g| g.name)
+    let guild_name = ctx
+        .cache
+        .guild_field(guild_channel.guild_id, |g| g.name.clone())
         .unwrap_or_else(|| "<error fetching server name>".to_string());
 
-    pc_data.add_subscription(id, guild_channel.guild_id, guild_channel.id);
+    let changed =
+        state.update(|d| d.add_subscription(id, guild_channel.guild_id, guild_channel.id));
 
     send_msg(
-        &ctx,
+        ctx,
         author,
         &format!(
             "Subscribed to notifications for {} on {}!",
@@ -384,157 +1002,1664 @@ async fn handle_add_vc_notify(ctx: &Context, msg: Message) {
     )
     .await;
 
-    if let Err(err) = storage::save_data(pc_data) {
-        error!("Error saving notif_data.json: {:?}", err);
+    if changed {
+        CommandOutcome::Ok
+    } else {
+        CommandOutcome::NoChange
     }
 }
 
-async fn handle_remove_vc_notify(ctx: &Context, msg: Message) {
-    let mut data = ctx.data.write().await;
-    let pc_data = data.get_mut::<DataKey>().unwrap();
+async fn handle_remove_vc_notify(ctx: &Context, msg: Message) -> CommandOutcome {
+    let state = get_state(ctx).await;
 
     let author = &msg.author;
     let id = author.id;
 
-    let channel = match get_channel_from_msg(&ctx, &msg).await {
+    let channel = match get_channel_from_msg(ctx, &msg).await {
         Some(c) => c,
-        None => return,
+        None => return CommandOutcome::InvalidArgument,
     };
 
     let guild_channel = match channel.guild() {
         Some(gc) => gc,
         None => {
             send_msg(
-                &ctx,
+                ctx,
                 author,
                 "Could not find server that the channel belongs to!",
             )
             .await;
-            return;
+            return CommandOutcome::InvalidArgument;
         }
     };
 
-    if pc_data.remove_subscription(id, guild_channel.guild_id, guild_channel.id) {
+    if state.update(|d| d.remove_subscription(id, guild_channel.guild_id, guild_channel.id)) {
         send_msg(
-            &ctx,
+            ctx,
             author,
             "Unscribed from notifications for this channel!",
         )
         .await;
+        CommandOutcome::Ok
     } else {
-        send_msg(&ctx, author, "You are not subscribed to this channel!").await;
+        send_msg(ctx, author, "You are not subscribed to this channel!").await;
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_list_vc_notify(ctx: &Context, msg: Message) -> CommandOutcome {
+    let mut subscriptions = get_state(ctx)
+        .await
+        .read(|d| d.find_subscriptions(msg.author.id).collect::<Vec<_>>());
+
+    if subscriptions.is_empty() {
+        send_msg(ctx, &msg.author, "You are not subscribed to any channels!").await;
+        return CommandOutcome::Ok;
     }
 
-    if let Err(err) = storage::save_data(pc_data) {
-        error!("Error saving notif_data.json: {:?}", err);
+    subscriptions.sort_unstable();
+
+    let mut text = "You are subscribed to notifications for:".to_string();
+    for (guild_id, channel_id) in subscriptions {
+        let guild_name = ctx
+            .cache
+            .guild_field(guild_id, |g| g.name.clone())
+            .unwrap_or_else(|| guild_id.to_string());
+        let channel_name = ctx
+            .cache
+            .guild_channel_field(channel_id, |c| c.name.clone())
+            .unwrap_or_else(|| channel_id.to_string());
+
+        text.push_str(&format!(
+            "\n[{}] {} <{}>",
+            guild_name, channel_name, channel_id
+        ));
     }
-}
 
-async fn handle_add_afk_channel(ctx: &Context, msg: Message) {
-    let mut data = ctx.data.write().await;
-    let pc_data = data.get_mut::<DataKey>().unwrap();
+    send_long_msg(ctx, &msg.author, &text).await;
 
+    CommandOutcome::Ok
+}
+
+// Lists who is in the non-AFK voice channels of all servers the author shares with the bot.
+async fn handle_whos_on(ctx: &Context, msg: Message) -> CommandOutcome {
     let author = &msg.author;
-    let id = author.id;
 
-    let channel = match get_channel_from_msg(&ctx, &msg).await {
-        Some(c) => c,
-        None => return,
+    let mut channels = match get_list_of_common_channels(ctx, author).await {
+        Ok(channels) => channels,
+        Err(err) => {
+            warn!("Error finding common channels: {:?}", err);
+            send_msg(ctx, author, "Failed to find common channels!").await;
+            return CommandOutcome::Ok;
+        }
     };
+    channels.sort_unstable_by(|(g1, c1), (g2, c2)| {
+        (&g1.name, g1.id, c1.position, c1.id).cmp(&(&g2.name, g2.id, c2.position, c2.id))
+    });
 
-    let guild_channel = match channel.guild() {
-        Some(gc) => gc,
-        None => {
-            send_msg(
-                &ctx,
-                author,
-                "Could not find server that the channel belongs to!",
-            )
-            .await;
-            return;
+    let state = get_state(ctx).await;
+    let mut author_members = HashMap::new();
+    let mut text = String::new();
+    for (guild, channel) in channels {
+        if state.read(|d| d.is_afk_channel(guild.id, channel.id)) {
+            continue;
         }
-    };
 
-    if !pc_data.is_admin(id, guild_channel.guild_id) {
+        if let Entry::Vacant(entry) = author_members.entry(guild.id) {
+            let member = match guild.id.member(ctx, author.id).await {
+                Ok(m) => Some(m),
+                Err(err) => {
+                    warn!(
+                        "Error fetching member {} of guild {}: {:?}",
+                        author.id, guild.id, err
+                    );
+                    None
+                }
+            };
+            entry.insert(member);
+        }
+        let can_join = match &author_members[&guild.id] {
+            Some(member) => can_join_voice_channel(ctx, guild.id, &channel, member),
+            None => false,
+        };
+        if !can_join {
+            continue;
+        }
+
+        let mut members = ctx
+            .cache
+            .guild_field(guild.id, |g| {
+                g.voice_states
+                    .values()
+                    .filter(|vs| vs.channel_id == Some(channel.id))
+                    .map(|vs| (vs.user_id, describe_voice_state(vs)))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if members.is_empty() {
+            continue;
+        }
+        members.sort_unstable_by_key(|&(user_id, _)| user_id);
+
+        text.push_str(&format!("\n[{}] {}:", guild.name, channel.name));
+        for (user_id, notes) in members {
+            match notes {
+                Some(notes) => text.push_str(&format!("\n- <@{}> ({})", user_id, notes)),
+                None => text.push_str(&format!("\n- <@{}>", user_id)),
+            }
+        }
+    }
+
+    if text.is_empty() {
         send_msg(
-            &ctx,
+            ctx,
             author,
-            "You are not permitted to modify administrative settings for this server!",
+            "Nobody is in a voice channel on any of the servers we share!",
+        )
+        .await;
+    } else {
+        send_long_msg(
+            ctx,
+            author,
+            &format!("Currently in voice channels:{}", text),
         )
         .await;
-        return;
     }
 
-    pc_data.add_afk_channel(guild_channel.guild_id, guild_channel.id);
-    send_msg(&ctx, author, "Set channel as AFK channel!").await;
+    CommandOutcome::Ok
+}
 
-    if let Err(err) = storage::save_data(pc_data) {
-        error!("Error saving notif_data.json: {:?}", err);
-    }
+// Whether the member may see and connect to the channel, so that `!whos-on` doesn't reveal who is
+// in channels they have no access to. Fails closed if the guild isn't cached.
+fn can_join_voice_channel(
+    ctx: &Context,
+    guild_id: GuildId,
+    channel: &GuildChannel,
+    member: &Member,
+) -> bool {
+    ctx.cache
+        .guild_field(guild_id, |g| g.user_permissions_in(channel, member).ok())
+        .flatten()
+        .is_some_and(|p| p.view_channel() && p.connect())
 }
 
-async fn handle_remove_afk_channel(ctx: &Context, msg: Message) {
-    let mut data = ctx.data.write().await;
-    let pc_data = data.get_mut::<DataKey>().unwrap();
+// Short notes on what someone in a voice channel is doing, like "streaming, muted", or `None` if
+// there's nothing to note.
+fn describe_voice_state(state: &VoiceState) -> Option<String> {
+    let mut notes = vec![];
+    if state.self_stream == Some(true) {
+        notes.push("streaming");
+    }
+    if state.self_video {
+        notes.push("camera on");
+    }
+    if state.self_deaf || state.deaf {
+        notes.push("deafened");
+    } else if state.self_mute || state.mute {
+        notes.push("muted");
+    }
 
-    let author = &msg.author;
-    let id = author.id;
+    if notes.is_empty() {
+        None
+    } else {
+        Some(notes.join(", "))
+    }
+}
 
-    let channel = match get_channel_from_msg(&ctx, &msg).await {
-        Some(c) => c,
-        None => return,
-    };
+async fn handle_why(ctx: &Context, msg: Message, history: &NotificationHistory) -> CommandOutcome {
+    let entries = history.describe(msg.author.id);
 
-    let guild_channel = match channel.guild() {
-        Some(gc) => gc,
+    if entries.is_empty() {
+        send_msg(
+            ctx,
+            &msg.author,
+            "Nobody has joined any of your subscribed channels since I was last restarted!",
+        )
+        .await;
+        return CommandOutcome::Ok;
+    }
+
+    let mut text = "Recent joins on your subscribed channels, newest first:".to_string();
+    for entry in entries {
+        text.push_str("\n- ");
+        text.push_str(&entry);
+    }
+
+    send_long_msg(ctx, &msg.author, &text).await;
+
+    CommandOutcome::Ok
+}
+
+fn update_discord_afk_channel(state: &PCState, guild_id: GuildId, channel_id: Option<ChannelId>) {
+    if state.update(|d| d.set_discord_afk_channel(guild_id, channel_id)) {
+        info!(
+            guild = guild_id.0;
+            "[afk] Discord AFK channel of guild {} is now {:?}",
+            guild_id, channel_id
+        );
+    }
+}
+
+// The first time a guild with admins is seen, tells them about voice channels whose names suggest
+// they are AFK channels, but that aren't registered as such.
+async fn offer_afk_suggestions(ctx: &Context, state: &PCState, guild: &Guild) {
+    let admins = state.read(|d| match d.find_guild(guild.id) {
+        Some(g) if !d.afk_suggestions_offered(guild.id) => g.admins().map(|(id, _)| id).collect(),
+        _ => vec![],
+    });
+    if admins.is_empty() {
+        return;
+    }
+
+    let mut suggestions = state.read(|d| {
+        guild
+            .channels
+            .values()
+            .filter_map(|c| c.clone().guild())
+            .filter(|c| c.kind == ChannelType::Voice)
+            .filter(|c| !d.is_afk_channel(guild.id, c.id) && afk::looks_like_afk_channel(&c.name))
+            .collect::<Vec<_>>()
+    });
+    suggestions.sort_unstable_by_key(|c| (c.position, c.id));
+
+    if !suggestions.is_empty() {
+        let mut text = format!(
+            "Hi! These voice channels on {} look like AFK channels, but aren't registered as \
+             such. Joining an AFK channel doesn't trigger notifications and time spent in them \
+             isn't recorded. Use `!add-afk-channel <channel id>` for any that are:",
+            guild.name
+        );
+        for channel in suggestions {
+            text.push_str(&format!("\n{} <{}>", channel.name, channel.id));
+        }
+
+        for user_id in admins {
+            match user_id.to_user(ctx).await {
+                Ok(user) => {
+                    send_msg(ctx, &user, &text).await;
+                }
+                Err(err) => warn!(
+                    guild = guild.id.0, user = user_id.0;
+                    "Error looking up admin {} to suggest AFK channels: {:?}",
+                    user_id, err
+                ),
+            }
+        }
+        info!(guild = guild.id.0; "[afk] Suggested AFK channels for guild {}", guild.id);
+    }
+
+    state.update(|d| d.set_afk_suggestions_offered(guild.id));
+}
+
+async fn handle_ignore_discord_afk_channel(ctx: &Context, msg: Message) -> CommandOutcome {
+    const USAGE: &str = "!ignore-discord-afk-channel <server id> <on|off>";
+
+    let author = &msg.author;
+    let args = get_arguments_from_msg(&msg, 2);
+
+    let guild_id = match get_admin_guild_from_arg(ctx, author, args.first().copied(), USAGE).await {
+        Ok(g) => g,
+        Err(outcome) => return outcome,
+    };
+
+    let ignore = match args.get(1).copied() {
+        Some("on") => true,
+        Some("off") => false,
+        _ => {
+            send_msg(ctx, author, &format!("Use `{}`!", USAGE)).await;
+            return CommandOutcome::InvalidArgument;
+        }
+    };
+
+    let changed = get_state(ctx)
+        .await
+        .update(|d| d.set_ignore_discord_afk_channel(guild_id, ignore));
+    send_msg(
+        ctx,
+        author,
+        if ignore {
+            "The AFK channel from the server's settings will not count as an AFK channel, unless \
+             it is added with `!add-afk-channel`!"
+        } else {
+            "The AFK channel from the server's settings will count as an AFK channel!"
+        },
+    )
+    .await;
+
+    if changed {
+        CommandOutcome::Ok
+    } else {
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_add_lobby_channel(ctx: &Context, msg: Message) -> CommandOutcome {
+    let state = get_state(ctx).await;
+    let author = &msg.author;
+
+    let guild_channel = match get_admin_channel_from_msg(ctx, &msg).await {
+        Ok(gc) => gc,
+        Err(outcome) => return outcome,
+    };
+
+    if guild_channel.kind != ChannelType::Voice {
+        send_msg(ctx, author, "Only voice channels can be lobby channels!").await;
+        return CommandOutcome::InvalidArgument;
+    }
+
+    let changed = state.update(|d| d.add_lobby_channel(guild_channel.guild_id, guild_channel.id));
+    send_msg(
+        ctx,
+        author,
+        "Set channel as lobby channel! Everyone who joins it will get their own voice channel.",
+    )
+    .await;
+
+    if changed {
+        CommandOutcome::Ok
+    } else {
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_remove_lobby_channel(ctx: &Context, msg: Message) -> CommandOutcome {
+    let state = get_state(ctx).await;
+    let author = &msg.author;
+
+    let guild_channel = match get_admin_channel_from_msg(ctx, &msg).await {
+        Ok(gc) => gc,
+        Err(outcome) => return outcome,
+    };
+
+    if state.update(|d| d.remove_lobby_channel(guild_channel.guild_id, guild_channel.id)) {
+        send_msg(ctx, author, "Unset channel as lobby channel!").await;
+        CommandOutcome::Ok
+    } else {
+        send_msg(
+            ctx,
+            author,
+            "Could not unset as lobby channel. Is the channel currently a lobby channel?",
+        )
+        .await;
+        CommandOutcome::NoChange
+    }
+}
+
+// How many of the most recent idle moves `!list-idle-moves` shows.
+const IDLE_MOVES_SHOWN: usize = 10;
+
+// Longer than anyone stays deafened in a voice channel without actually being away.
+const MAX_IDLE_MOVE_MINUTES: u64 = 24 * 60;
+
+async fn handle_set_idle_move(ctx: &Context, msg: Message) -> CommandOutcome {
+    const USAGE: &str = "!set-idle-move <server id> <minutes|off>";
+
+    let author = &msg.author;
+    let args = get_arguments_from_msg(&msg, 2);
+
+    let guild_id = match get_admin_guild_from_arg(ctx, author, args.first().copied(), USAGE).await {
+        Ok(g) => g,
+        Err(outcome) => return outcome,
+    };
+
+    let after = match args.get(1).copied() {
+        Some("off") => None,
+        Some(minutes) => match minutes
+            .parse::<u64>()
+            .ok()
+            .filter(|&m| m > 0 && m <= MAX_IDLE_MOVE_MINUTES)
+            .and_then(|m| m.checked_mul(60))
+        {
+            Some(secs) => Some(secs),
+            None => {
+                send_msg(
+                    ctx,
+                    author,
+                    &format!(
+                        "Use `{}`, with at most {} minutes!",
+                        USAGE, MAX_IDLE_MOVE_MINUTES
+                    ),
+                )
+                .await;
+                return CommandOutcome::InvalidArgument;
+            }
+        },
+        None => {
+            send_msg(ctx, author, &format!("Use `{}`!", USAGE)).await;
+            return CommandOutcome::InvalidArgument;
+        }
+    };
+
+    let state = get_state(ctx).await;
+    let changed = state.update(|d| d.set_idle_move_after(guild_id, after));
+    let text = match (after, state.read(|d| d.idle_move_target(guild_id))) {
+        (None, _) => "Deafened members will not be moved to an AFK channel anymore!".to_string(),
+        (Some(secs), Some(target)) => format!(
+            "Members that are deafened for {} will be moved to <#{}>!",
+            sessions::format_duration(secs),
+            target
+        ),
+        (Some(secs), None) => format!(
+            "Members that are deafened for {} will be moved to an AFK channel, once one is \
+             added with `!add-afk-channel <channel id>`!",
+            sessions::format_duration(secs)
+        ),
+    };
+    send_msg(ctx, author, &text).await;
+
+    if changed {
+        CommandOutcome::Ok
+    } else {
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_change_idle_move_exemption(
+    ctx: &Context,
+    msg: Message,
+    add: bool,
+) -> CommandOutcome {
+    let usage = if add {
+        "!add-idle-move-exemption <server id> <role|user> <id>"
+    } else {
+        "!remove-idle-move-exemption <server id> <role|user> <id>"
+    };
+
+    let author = &msg.author;
+    let args = get_arguments_from_msg(&msg, 3);
+
+    let guild_id = match get_admin_guild_from_arg(ctx, author, args.first().copied(), usage).await {
+        Ok(g) => g,
+        Err(outcome) => return outcome,
+    };
+
+    let exemption = match (args.get(1), args.get(2)) {
+        (Some(kind), Some(id)) => IdleMoveExemption::parse(kind, id),
+        _ => None,
+    };
+    let exemption = match exemption {
+        Some(e) => e,
+        None => {
+            send_msg(ctx, author, &format!("Use `{}`!", usage)).await;
+            return CommandOutcome::InvalidArgument;
+        }
+    };
+
+    let changed = get_state(ctx).await.update(|d| {
+        if add {
+            d.add_idle_move_exemption(guild_id, exemption)
+        } else {
+            d.remove_idle_move_exemption(guild_id, exemption)
+        }
+    });
+
+    let text = match (add, changed) {
+        (true, _) => format!(
+            "{} will not be moved to an AFK channel for being deafened!",
+            describe_idle_move_exemption(exemption)
+        ),
+        (false, true) => format!(
+            "{} can be moved to an AFK channel for being deafened again!",
+            describe_idle_move_exemption(exemption)
+        ),
+        (false, false) => format!(
+            "{} is not exempt from being moved to an AFK channel!",
+            describe_idle_move_exemption(exemption)
+        ),
+    };
+    send_msg(ctx, author, &text).await;
+
+    if changed {
+        CommandOutcome::Ok
+    } else {
+        CommandOutcome::NoChange
+    }
+}
+
+fn describe_idle_move_exemption(exemption: IdleMoveExemption) -> String {
+    match exemption {
+        IdleMoveExemption::Role(role_id) => format!("Members with role <@&{}>", role_id),
+        IdleMoveExemption::User(user_id) => format!("<@{}>", user_id),
+    }
+}
+
+async fn handle_list_idle_moves(ctx: &Context, msg: Message) -> CommandOutcome {
+    let args = get_arguments_from_msg(&msg, 1);
+    let guild_id = match get_admin_guild_from_arg(
+        ctx,
+        &msg.author,
+        args.first().copied(),
+        "!list-idle-moves <server id>",
+    )
+    .await
+    {
+        Ok(g) => g,
+        Err(outcome) => return outcome,
+    };
+
+    let state = get_state(ctx).await;
+    let (after, target, mut exemptions) = state.read(|d| {
+        (
+            d.idle_move_after(guild_id),
+            d.idle_move_target(guild_id),
+            d.find_guild(guild_id)
+                .map(|g| g.idle_move_exemptions().collect::<Vec<_>>())
+                .unwrap_or_default(),
+        )
+    });
+    let moves = state.read_runtime(|r| r.idle_moves(guild_id).to_vec());
+
+    let mut text = match (after, target) {
+        (None, _) => "Deafened members are not moved to an AFK channel.".to_string(),
+        (Some(secs), Some(target)) => format!(
+            "Members that are deafened for {} are moved to <#{}>.",
+            sessions::format_duration(secs),
+            target
+        ),
+        (Some(secs), None) => format!(
+            "Members that are deafened for {} would be moved to an AFK channel, but none is \
+             registered.",
+            sessions::format_duration(secs)
+        ),
+    };
+
+    if !exemptions.is_empty() {
+        exemptions.sort_unstable_by_key(|e| match e {
+            IdleMoveExemption::Role(role_id) => (0, role_id.0),
+            IdleMoveExemption::User(user_id) => (1, user_id.0),
+        });
+        text.push_str("\n\nExempt:");
+        for exemption in exemptions {
+            text.push_str(&format!("\n- {}", describe_idle_move_exemption(exemption)));
+        }
+    }
+
+    if moves.is_empty() {
+        text.push_str("\n\nNobody has been moved yet.");
+    } else {
+        text.push_str("\n\nMost recent moves:");
+        for idle_move in moves.iter().rev().take(IDLE_MOVES_SHOWN) {
+            text.push_str(&format!(
+                "\n- <t:{}:f> <@{}> from {} to {}",
+                idle_move.at,
+                idle_move.user,
+                channel_name(ctx, idle_move.from),
+                channel_name(ctx, idle_move.to)
+            ));
+        }
+    }
+
+    send_long_msg(ctx, &msg.author, &text).await;
+
+    CommandOutcome::Ok
+}
+
+async fn handle_add_afk_channel(ctx: &Context, msg: Message) -> CommandOutcome {
+    let state = get_state(ctx).await;
+    let author = &msg.author;
+
+    let guild_channel = match get_admin_channel_from_msg(ctx, &msg).await {
+        Ok(gc) => gc,
+        Err(outcome) => return outcome,
+    };
+
+    let changed = state.update(|d| d.add_afk_channel(guild_channel.guild_id, guild_channel.id));
+    send_msg(ctx, author, "Set channel as AFK channel!").await;
+
+    if changed {
+        CommandOutcome::Ok
+    } else {
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_remove_afk_channel(ctx: &Context, msg: Message) -> CommandOutcome {
+    let state = get_state(ctx).await;
+    let author = &msg.author;
+
+    let guild_channel = match get_admin_channel_from_msg(ctx, &msg).await {
+        Ok(gc) => gc,
+        Err(outcome) => return outcome,
+    };
+
+    if state.update(|d| d.remove_afk_channel(guild_channel.guild_id, guild_channel.id)) {
+        send_msg(ctx, author, "Unset channel as AFK channel!").await;
+        CommandOutcome::Ok
+    } else {
+        send_msg(
+            ctx,
+            author,
+            "Could not unset as AFK channel. Is the channel currently an AFK channel?",
+        )
+        .await;
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_set_fallback_channel(ctx: &Context, msg: Message) -> CommandOutcome {
+    let state = get_state(ctx).await;
+    let author = &msg.author;
+
+    let guild_channel = match get_admin_channel_from_msg(ctx, &msg).await {
+        Ok(gc) => gc,
+        Err(outcome) => return outcome,
+    };
+
+    if guild_channel.kind != ChannelType::Text {
+        send_msg(
+            ctx,
+            author,
+            "The fallback channel has to be a text channel!",
+        )
+        .await;
+        return CommandOutcome::InvalidArgument;
+    }
+
+    let changed =
+        state.update(|d| d.set_fallback_channel(guild_channel.guild_id, Some(guild_channel.id)));
+    send_msg(
+        ctx,
+        author,
+        "Set channel as fallback channel! Users whose notifications get paused because I can't DM \
+         them will be told about it there.",
+    )
+    .await;
+
+    if changed {
+        CommandOutcome::Ok
+    } else {
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_remove_fallback_channel(ctx: &Context, msg: Message) -> CommandOutcome {
+    let state = get_state(ctx).await;
+    let author = &msg.author;
+
+    let guild_channel = match get_admin_channel_from_msg(ctx, &msg).await {
+        Ok(gc) => gc,
+        Err(outcome) => return outcome,
+    };
+
+    let removed = state.update(|d| {
+        d.fallback_channel(guild_channel.guild_id) == Some(guild_channel.id)
+            && d.set_fallback_channel(guild_channel.guild_id, None)
+    });
+
+    if removed {
+        send_msg(ctx, author, "Unset channel as fallback channel!").await;
+        CommandOutcome::Ok
+    } else {
+        send_msg(
+            ctx,
+            author,
+            "Could not unset as fallback channel. Is the channel currently the fallback channel?",
+        )
+        .await;
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_leaderboard(ctx: &Context, msg: Message) -> CommandOutcome {
+    let (guild_id, game) =
+        match get_game_from_msg(ctx, &msg, "!leaderboard <server id> <game>").await {
+            Ok(g) => g,
+            Err(outcome) => return outcome,
+        };
+
+    let mut stats = get_state(ctx).await.read_runtime(|r| {
+        let latest_day = latest_game_day(r.game_results(guild_id, game.name));
+        r.game_results(guild_id, game.name)
+            .map(|(user_id, scores)| (user_id, games::player_stats(game, scores, latest_day)))
+            .collect::<Vec<_>>()
+    });
+
+    if stats.is_empty() {
+        send_msg(ctx, &msg.author, "Nobody has posted any results yet!").await;
+        return CommandOutcome::Ok;
+    }
+
+    stats.sort_unstable_by(|(a_id, a), (b_id, b)| {
+        a.average
+            .total_cmp(&b.average)
+            .then(b.played.cmp(&a.played))
+            .then(a_id.cmp(b_id))
+    });
+
+    let mut text = format!(
+        "{} leaderboard on {} (average guesses, failures count as {}):",
+        game.name,
+        guild_name(ctx, guild_id),
+        game.max_score + 1
+    );
+    for (rank, (user_id, stats)) in stats.into_iter().take(LEADERBOARD_LENGTH).enumerate() {
+        text.push_str(&format!(
+            "\n{}. <@{}>: {:.2} over {} puzzle(s), {} solved",
+            rank + 1,
+            user_id,
+            stats.average,
+            stats.played,
+            stats.solved
+        ));
+    }
+
+    send_long_msg(ctx, &msg.author, &text).await;
+
+    CommandOutcome::Ok
+}
+
+async fn handle_streaks(ctx: &Context, msg: Message) -> CommandOutcome {
+    let (guild_id, game) = match get_game_from_msg(ctx, &msg, "!streaks <server id> <game>").await {
+        Ok(g) => g,
+        Err(outcome) => return outcome,
+    };
+
+    let mut stats = get_state(ctx).await.read_runtime(|r| {
+        let latest_day = latest_game_day(r.game_results(guild_id, game.name));
+        r.game_results(guild_id, game.name)
+            .map(|(user_id, scores)| (user_id, games::player_stats(game, scores, latest_day)))
+            .filter(|(_, stats)| stats.best_streak > 0)
+            .collect::<Vec<_>>()
+    });
+
+    if stats.is_empty() {
+        send_msg(ctx, &msg.author, "Nobody has solved any puzzles yet!").await;
+        return CommandOutcome::Ok;
+    }
+
+    stats.sort_unstable_by(|(a_id, a), (b_id, b)| {
+        b.current_streak
+            .cmp(&a.current_streak)
+            .then(b.best_streak.cmp(&a.best_streak))
+            .then(a_id.cmp(b_id))
+    });
+
+    let mut text = format!(
+        "{} streaks on {} (current, best):",
+        game.name,
+        guild_name(ctx, guild_id)
+    );
+    for (rank, (user_id, stats)) in stats.iter().take(LEADERBOARD_LENGTH).enumerate() {
+        text.push_str(&format!(
+            "\n{}. <@{}>: {} day(s), {} day(s)",
+            rank + 1,
+            user_id,
+            stats.current_streak,
+            stats.best_streak
+        ));
+    }
+
+    if let Some((_, own)) = stats.iter().find(|(user_id, _)| *user_id == msg.author.id) {
+        text.push_str(&format!(
+            "\nYour streak: {} day(s), best {} day(s)",
+            own.current_streak, own.best_streak
+        ));
+    }
+
+    send_long_msg(ctx, &msg.author, &text).await;
+
+    CommandOutcome::Ok
+}
+
+const LEADERBOARD_LENGTH: usize = 10;
+
+// The newest puzzle anyone has posted a result for.
+fn latest_game_day<'a>(results: impl Iterator<Item = (UserId, &'a games::GameScores)>) -> u32 {
+    results
+        .filter_map(|(_, scores)| scores.keys().next_back().copied())
+        .max()
+        .unwrap_or(0)
+}
+
+// Parses the arguments of `!leaderboard` and `!streaks`, which anyone on the server may use.
+async fn get_game_from_msg(
+    ctx: &Context,
+    msg: &Message,
+    usage: &str,
+) -> Result<(GuildId, &'static Game), CommandOutcome> {
+    let author = &msg.author;
+    let args = get_arguments_from_msg(msg, 2);
+
+    let state = get_state(ctx).await;
+    let guild_id = get_member_guild_from_arg(ctx, author, args.first().copied(), usage, |g| {
+        state.read_runtime(|r| !r.games_played(g).is_empty())
+    })
+    .await?;
+
+    match args.get(1).and_then(|name| games::find_game(name)) {
+        Some(game) => Ok((guild_id, game)),
+        None => {
+            let played = get_state(ctx)
+                .await
+                .read_runtime(|r| r.games_played(guild_id).join(", "));
+            send_msg(
+                ctx,
+                author,
+                &format!(
+                    "Use `{}` with one of the games that results were posted for: {}",
+                    usage,
+                    if played.is_empty() {
+                        "none yet"
+                    } else {
+                        &played
+                    }
+                ),
+            )
+            .await;
+            Err(CommandOutcome::InvalidArgument)
+        }
+    }
+}
+
+async fn handle_voice_time(ctx: &Context, msg: Message) -> CommandOutcome {
+    let author = &msg.author;
+    let now = unix_now();
+    let week_start = sessions::start_of_week(now);
+
+    let state = get_state(ctx).await;
+    let opted_out = state.read(|d| d.is_voice_tracking_opted_out(author.id));
+    let mut guilds = state.read_sessions(|s| {
+        s.guild_ids()
+            .map(|guild_id| {
+                let secs = s
+                    .voice_sessions(guild_id, week_start, now)
+                    .filter(|session| session.user == author.id)
+                    .map(|session| sessions::overlap(&session, week_start, now))
+                    .sum::<u64>();
+                (guild_id, secs)
+            })
+            .filter(|(_, secs)| *secs > 0)
+            .collect::<Vec<_>>()
+    });
+
+    if opted_out {
+        send_msg(
+            ctx,
+            author,
+            "You opted out of voice tracking! Send `!voice-tracking on` to turn it back on.",
+        )
+        .await;
+        return CommandOutcome::Ok;
+    }
+
+    if guilds.is_empty() {
+        send_msg(
+            ctx,
+            author,
+            "You haven't been in any voice channels this week!",
+        )
+        .await;
+        return CommandOutcome::Ok;
+    }
+
+    guilds.sort_unstable_by_key(|&(guild_id, secs)| (std::cmp::Reverse(secs), guild_id));
+
+    let mut text = format!("Your time in voice channels since <t:{}:f>:", week_start);
+    for &(guild_id, secs) in &guilds {
+        text.push_str(&format!(
+            "\n[{}] {}",
+            guild_name(ctx, guild_id),
+            sessions::format_duration(secs)
+        ));
+    }
+    if guilds.len() > 1 {
+        let total = guilds.iter().map(|(_, secs)| secs).sum();
+        text.push_str(&format!("\nTotal: {}", sessions::format_duration(total)));
+    }
+
+    send_msg(ctx, author, &text).await;
+
+    CommandOutcome::Ok
+}
+
+async fn handle_last_night(ctx: &Context, msg: Message) -> CommandOutcome {
+    let state = get_state(ctx).await;
+    let args = get_arguments_from_msg(&msg, 1);
+    let guild_id = match get_member_guild_from_arg(
+        ctx,
+        &msg.author,
+        args.first().copied(),
+        "!last-night <server id>",
+        |g| has_voice_sessions(&state, g),
+    )
+    .await
+    {
+        Ok(g) => g,
+        Err(outcome) => return outcome,
+    };
+
+    let (from, to) = sessions::last_night(unix_now());
+    let mut users = HashMap::new();
+    state.read_sessions(|s| {
+        for session in s.voice_sessions(guild_id, from, unix_now()) {
+            let secs = sessions::overlap(&session, from, to);
+            if secs > 0 {
+                let (total, channels) = users
+                    .entry(session.user)
+                    .or_insert_with(|| (0, HashSet::new()));
+                *total += secs;
+                channels.insert(session.channel);
+            }
+        }
+    });
+
+    if users.is_empty() {
+        send_msg(
+            ctx,
+            &msg.author,
+            &format!(
+                "Nobody was in a voice channel on {} between <t:{}:f> and <t:{}:t>!",
+                guild_name(ctx, guild_id),
+                from,
+                to
+            ),
+        )
+        .await;
+        return CommandOutcome::Ok;
+    }
+
+    let mut users = users.into_iter().collect::<Vec<_>>();
+    users.sort_unstable_by_key(|&(user_id, (secs, _))| (std::cmp::Reverse(secs), user_id));
+
+    let mut text = format!(
+        "In voice channels on {} between <t:{}:f> and <t:{}:t>:",
+        guild_name(ctx, guild_id),
+        from,
+        to
+    );
+    for (user_id, (secs, channels)) in users {
+        let mut channel_names = channels
+            .into_iter()
+            .map(|c| channel_name(ctx, c))
+            .collect::<Vec<_>>();
+        channel_names.sort_unstable();
+        text.push_str(&format!(
+            "\n- <@{}>: {} ({})",
+            user_id,
+            sessions::format_duration(secs),
+            channel_names.join(", ")
+        ));
+    }
+
+    send_long_msg(ctx, &msg.author, &text).await;
+
+    CommandOutcome::Ok
+}
+
+async fn handle_channel_activity(ctx: &Context, msg: Message) -> CommandOutcome {
+    const DAYS: u64 = 7;
+
+    let state = get_state(ctx).await;
+    let args = get_arguments_from_msg(&msg, 1);
+    let guild_id = match get_member_guild_from_arg(
+        ctx,
+        &msg.author,
+        args.first().copied(),
+        "!channel-activity <server id>",
+        |g| has_voice_sessions(&state, g),
+    )
+    .await
+    {
+        Ok(g) => g,
+        Err(outcome) => return outcome,
+    };
+
+    let now = unix_now();
+    let from = now - DAYS * 24 * 60 * 60;
+    let mut channels = HashMap::new();
+    state.read_sessions(|s| {
+        for session in s.voice_sessions(guild_id, from, now) {
+            let (total, sessions, users) = channels
+                .entry(session.channel)
+                .or_insert_with(|| (0, 0, HashSet::new()));
+            *total += sessions::overlap(&session, from, now);
+            *sessions += 1;
+            users.insert(session.user);
+        }
+    });
+
+    if channels.is_empty() {
+        send_msg(
+            ctx,
+            &msg.author,
+            &format!(
+                "Nobody was in a voice channel on {} in the last {} days!",
+                guild_name(ctx, guild_id),
+                DAYS
+            ),
+        )
+        .await;
+        return CommandOutcome::Ok;
+    }
+
+    let mut channels = channels.into_iter().collect::<Vec<_>>();
+    channels
+        .sort_unstable_by_key(|&(channel_id, (secs, _, _))| (std::cmp::Reverse(secs), channel_id));
+
+    let mut text = format!(
+        "Voice channel activity on {} in the last {} days:",
+        guild_name(ctx, guild_id),
+        DAYS
+    );
+    for (channel_id, (secs, session_count, users)) in channels {
+        text.push_str(&format!(
+            "\n- {}: {} in {} session(s) by {} user(s)",
+            channel_name(ctx, channel_id),
+            sessions::format_duration(secs),
+            session_count,
+            users.len()
+        ));
+    }
+
+    send_long_msg(ctx, &msg.author, &text).await;
+
+    CommandOutcome::Ok
+}
+
+fn has_voice_sessions(state: &PCState, guild_id: GuildId) -> bool {
+    state.read_sessions(|s| s.guild_ids().any(|g| g == guild_id))
+}
+
+async fn handle_voice_tracking(ctx: &Context, msg: Message) -> CommandOutcome {
+    let author = &msg.author;
+
+    let opt_out = match get_arguments_from_msg(&msg, 1).first().copied() {
+        Some("on") => false,
+        Some("off") => true,
+        _ => {
+            let opted_out = get_state(ctx)
+                .await
+                .read(|d| d.is_voice_tracking_opted_out(author.id));
+            send_msg(
+                ctx,
+                author,
+                &format!(
+                    "Voice tracking is currently {} for you. Use `!voice-tracking on` or \
+                     `!voice-tracking off` to change that. Turning it off also deletes \
+                     everything recorded about you so far.",
+                    if opted_out { "off" } else { "on" }
+                ),
+            )
+            .await;
+            return CommandOutcome::InvalidArgument;
+        }
+    };
+
+    let state = get_state(ctx).await;
+    let mut changed = state.update(|d| d.set_voice_tracking_opt_out(author.id, opt_out));
+    if opt_out {
+        changed |= state.update_sessions(|s| s.forget_user(author.id));
+    }
+    send_msg(
+        ctx,
+        author,
+        if opt_out {
+            "Turned off voice tracking and deleted your recorded voice sessions!"
+        } else {
+            "Turned on voice tracking! Your time in voice channels will be recorded from your next \
+             join on."
+        },
+    )
+    .await;
+
+    if changed {
+        CommandOutcome::Ok
+    } else {
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_digest(ctx: &Context, msg: Message) -> CommandOutcome {
+    let author = &msg.author;
+
+    let subscribe = match get_arguments_from_msg(&msg, 1).first().copied() {
+        Some("on") => true,
+        Some("off") => false,
+        _ => {
+            let subscribed = get_state(ctx)
+                .await
+                .read(|d| d.is_digest_subscriber(author.id));
+            send_msg(
+                ctx,
+                author,
+                &format!(
+                    "Weekly digests are currently {} for you. Use `!digest on` or `!digest off` \
+                     to change that. Every Monday, a digest summarizes the previous week in the \
+                     voice channels you are subscribed to: the busiest times, who was around \
+                     most, and the sessions you missed.",
+                    if subscribed { "on" } else { "off" }
+                ),
+            )
+            .await;
+            return CommandOutcome::InvalidArgument;
+        }
+    };
+
+    let state = get_state(ctx).await;
+    let changed = state.update(|d| d.set_digest_subscription(author.id, subscribe));
+    if changed {
+        // New subscribers count as having been sent a digest just now, so they get their first
+        // one once the current week is over.
+        state.update_runtime(|r| {
+            if subscribe {
+                r.record_digest_sent(author.id, unix_now())
+            } else {
+                r.forget_digests_sent(author.id)
+            }
+        });
+    }
+    send_msg(
+        ctx,
+        author,
+        if subscribe {
+            "Subscribed to weekly digests! You'll get your first one next Monday."
+        } else {
+            "Unsubscribed from weekly digests!"
+        },
+    )
+    .await;
+
+    if changed {
+        CommandOutcome::Ok
+    } else {
+        CommandOutcome::NoChange
+    }
+}
+
+// Lists subscribers that DMs couldn't be delivered to in all servers the author is an admin of.
+async fn handle_list_undeliverable(ctx: &Context, msg: Message) -> CommandOutcome {
+    let author = &msg.author;
+
+    let state = get_state(ctx).await;
+    let mut guilds = state.read(|d| {
+        state.read_runtime(|r| {
+            d.guilds()
+                .filter(|g| d.is_admin(author.id, g.id))
+                .map(|g| {
+                    (
+                        g.id,
+                        r.undeliverable_subscribers(d, g.id).collect::<Vec<_>>(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
+    });
+
+    if guilds.is_empty() {
+        send_msg(
+            ctx,
+            author,
+            "You are not permitted to view administrative information for any server!",
+        )
+        .await;
+        return CommandOutcome::NotPermitted;
+    }
+
+    guilds.sort_unstable_by_key(|(guild_id, _)| *guild_id);
+
+    let mut text = String::new();
+    for (guild_id, mut users) in guilds {
+        let guild_name = ctx
+            .cache
+            .guild_field(guild_id, |g| g.name.clone())
+            .unwrap_or_else(|| guild_id.to_string());
+
+        if users.is_empty() {
+            text.push_str(&format!(
+                "[{}] All subscribers can be sent DMs.\n",
+                guild_name
+            ));
+            continue;
+        }
+
+        users.sort_unstable_by_key(|(user_id, _)| *user_id);
+
+        text.push_str(&format!(
+            "[{}] Subscribers I can't send DMs to:\n",
+            guild_name
+        ));
+        for (user_id, failures) in users {
+            text.push_str(&format!(
+                "- <@{}>: {} failed DM(s) in a row, last one <t:{}:R>{}\n",
+                user_id,
+                failures.consecutive,
+                failures.last_failure,
+                if failures.paused {
+                    ", notifications paused"
+                } else {
+                    ""
+                }
+            ));
+        }
+    }
+
+    send_long_msg(ctx, author, text.trim_end()).await;
+
+    CommandOutcome::Ok
+}
+
+const ADD_SUPPRESSION_RULE_USAGE: &str =
+    "!add-suppression-rule <server id> <exact|domain|prefix|regex> <pattern>";
+const REMOVE_SUPPRESSION_RULE_USAGE: &str =
+    "!remove-suppression-rule <server id> <exact|domain|prefix|regex> <pattern>";
+
+async fn handle_list_suppression_rules(ctx: &Context, msg: Message) -> CommandOutcome {
+    let args = get_arguments_from_msg(&msg, 1);
+    let guild_id = match get_admin_guild_from_arg(
+        ctx,
+        &msg.author,
+        args.first().copied(),
+        "!list-suppression-rules <server id>",
+    )
+    .await
+    {
+        Ok(g) => g,
+        Err(outcome) => return outcome,
+    };
+
+    let (rules, mode) = get_state(ctx).await.read(|d| {
+        (
+            d.suppression_rules(guild_id).to_vec(),
+            d.suppression_mode(guild_id),
+        )
+    });
+    let guild_name = ctx
+        .cache
+        .guild_field(guild_id, |g| g.name.clone())
+        .unwrap_or_else(|| guild_id.to_string());
+
+    if rules.is_empty() {
+        send_msg(
+            ctx,
+            &msg.author,
+            &format!("There are no embed suppression rules for {}!", guild_name),
+        )
+        .await;
+        return CommandOutcome::Ok;
+    }
+
+    let mut text = format!(
+        "Embeds are suppressed on {} if {} links in a message match one of:",
+        guild_name,
+        mode.name()
+    );
+    for rule in rules {
+        text.push_str(&format!("\n- `{}`", rule));
+    }
+
+    send_long_msg(ctx, &msg.author, &text).await;
+
+    CommandOutcome::Ok
+}
+
+async fn handle_add_suppression_rule(ctx: &Context, msg: Message) -> CommandOutcome {
+    let (guild_id, rule) =
+        match get_suppression_rule_from_msg(ctx, &msg, ADD_SUPPRESSION_RULE_USAGE).await {
+            Ok(r) => r,
+            Err(outcome) => return outcome,
+        };
+
+    if get_state(ctx)
+        .await
+        .update(|d| d.add_suppression_rule(guild_id, rule))
+    {
+        send_msg(ctx, &msg.author, "Added embed suppression rule!").await;
+        CommandOutcome::Ok
+    } else {
+        send_msg(ctx, &msg.author, "That rule already exists!").await;
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_remove_suppression_rule(ctx: &Context, msg: Message) -> CommandOutcome {
+    let (guild_id, rule) =
+        match get_suppression_rule_from_msg(ctx, &msg, REMOVE_SUPPRESSION_RULE_USAGE).await {
+            Ok(r) => r,
+            Err(outcome) => return outcome,
+        };
+
+    if get_state(ctx)
+        .await
+        .update(|d| d.remove_suppression_rule(guild_id, &rule))
+    {
+        send_msg(ctx, &msg.author, "Removed embed suppression rule!").await;
+        CommandOutcome::Ok
+    } else {
+        send_msg(
+            ctx,
+            &msg.author,
+            "There is no such rule! Use `!list-suppression-rules <server id>` to see all of them.",
+        )
+        .await;
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_reset_suppression_rules(ctx: &Context, msg: Message) -> CommandOutcome {
+    let args = get_arguments_from_msg(&msg, 1);
+    let guild_id = match get_admin_guild_from_arg(
+        ctx,
+        &msg.author,
+        args.first().copied(),
+        "!reset-suppression-rules <server id>",
+    )
+    .await
+    {
+        Ok(g) => g,
+        Err(outcome) => return outcome,
+    };
+
+    let changed = get_state(ctx)
+        .await
+        .update(|d| d.reset_suppression_rules(guild_id));
+    send_msg(
+        ctx,
+        &msg.author,
+        "Reset embed suppression rules to the defaults!",
+    )
+    .await;
+
+    if changed {
+        CommandOutcome::Ok
+    } else {
+        CommandOutcome::NoChange
+    }
+}
+
+async fn handle_set_suppression_mode(ctx: &Context, msg: Message) -> CommandOutcome {
+    const USAGE: &str = "!set-suppression-mode <server id> <any|all>";
+
+    let author = &msg.author;
+    let args = get_arguments_from_msg(&msg, 2);
+
+    let guild_id = match get_admin_guild_from_arg(ctx, author, args.first().copied(), USAGE).await {
+        Ok(g) => g,
+        Err(outcome) => return outcome,
+    };
+
+    let mode = match args.get(1).and_then(|mode| SuppressionMode::parse(mode)) {
+        Some(m) => m,
         None => {
-            send_msg(
-                &ctx,
-                author,
-                "Could not find server that the channel belongs to!",
-            )
-            .await;
-            return;
+            send_msg(ctx, author, &format!("Use `{}`!", USAGE)).await;
+            return CommandOutcome::InvalidArgument;
         }
     };
 
-    if !pc_data.is_admin(id, guild_channel.guild_id) {
+    let changed = get_state(ctx)
+        .await
+        .update(|d| d.set_suppression_mode(guild_id, mode));
+    send_msg(
+        ctx,
+        author,
+        match mode {
+            SuppressionMode::Any => {
+                "Embeds will be suppressed if any link in a message matches a rule!"
+            }
+            SuppressionMode::All => {
+                "Embeds will only be suppressed if all links in a message match a rule!"
+            }
+        },
+    )
+    .await;
+
+    if changed {
+        CommandOutcome::Ok
+    } else {
+        CommandOutcome::NoChange
+    }
+}
+
+const ADD_SPOILER_RULE_USAGE: &str = "!add-spoiler-rule <server id> <game|all> [channel id]";
+const REMOVE_SPOILER_RULE_USAGE: &str = "!remove-spoiler-rule <server id> <game|all> [channel id]";
+
+async fn handle_list_spoiler_rules(ctx: &Context, msg: Message) -> CommandOutcome {
+    let args = get_arguments_from_msg(&msg, 1);
+    let guild_id = match get_admin_guild_from_arg(
+        ctx,
+        &msg.author,
+        args.first().copied(),
+        "!list-spoiler-rules <server id>",
+    )
+    .await
+    {
+        Ok(g) => g,
+        Err(outcome) => return outcome,
+    };
+
+    let rules = get_state(ctx)
+        .await
+        .read(|d| d.spoiler_rules(guild_id).to_vec());
+
+    if rules.is_empty() {
         send_msg(
-            &ctx,
-            author,
-            "You are not permitted to modify administrative settings for this server!",
+            ctx,
+            &msg.author,
+            &format!(
+                "There are no spoiler rules for {}, so result posts only get their embeds \
+                 suppressed!",
+                guild_name(ctx, guild_id)
+            ),
         )
         .await;
-        return;
+        return CommandOutcome::Ok;
+    }
+
+    let mut text = format!(
+        "Result posts on {} are reposted with spoilers for:",
+        guild_name(ctx, guild_id)
+    );
+    for rule in rules {
+        text.push_str(&format!("\n- {}", rule));
+    }
+
+    send_long_msg(ctx, &msg.author, &text).await;
+
+    CommandOutcome::Ok
+}
+
+async fn handle_add_spoiler_rule(ctx: &Context, msg: Message) -> CommandOutcome {
+    let (guild_id, rule) = match get_spoiler_rule_from_msg(ctx, &msg, ADD_SPOILER_RULE_USAGE).await
+    {
+        Ok(r) => r,
+        Err(outcome) => return outcome,
+    };
+
+    if get_state(ctx)
+        .await
+        .update(|d| d.add_spoiler_rule(guild_id, rule))
+    {
+        send_msg(
+            ctx,
+            &msg.author,
+            "Added spoiler rule! Make sure I'm allowed to manage webhooks and messages in the \
+             channels it applies to.",
+        )
+        .await;
+        CommandOutcome::Ok
+    } else {
+        send_msg(ctx, &msg.author, "That rule already exists!").await;
+        CommandOutcome::NoChange
     }
+}
 
-    if pc_data.remove_afk_channel(guild_channel.guild_id, guild_channel.id) {
-        send_msg(&ctx, author, "Unset channel as AFK channel!").await;
+async fn handle_remove_spoiler_rule(ctx: &Context, msg: Message) -> CommandOutcome {
+    let (guild_id, rule) =
+        match get_spoiler_rule_from_msg(ctx, &msg, REMOVE_SPOILER_RULE_USAGE).await {
+            Ok(r) => r,
+            Err(outcome) => return outcome,
+        };
+
+    if get_state(ctx)
+        .await
+        .update(|d| d.remove_spoiler_rule(guild_id, &rule))
+    {
+        send_msg(ctx, &msg.author, "Removed spoiler rule!").await;
+        CommandOutcome::Ok
     } else {
         send_msg(
-            &ctx,
-            author,
-            "Could not unset as AFK channel. Is the channel currently an AFK channel?",
+            ctx,
+            &msg.author,
+            "There is no such rule! Use `!list-spoiler-rules <server id>` to see all of them.",
         )
         .await;
+        CommandOutcome::NoChange
+    }
+}
+
+// Parses the arguments of `!add-spoiler-rule` and `!remove-spoiler-rule`.
+async fn get_spoiler_rule_from_msg(
+    ctx: &Context,
+    msg: &Message,
+    usage: &str,
+) -> Result<(GuildId, SpoilerRule), CommandOutcome> {
+    let author = &msg.author;
+    let args = get_arguments_from_msg(msg, 3);
+
+    let guild_id = get_admin_guild_from_arg(ctx, author, args.first().copied(), usage).await?;
+
+    let game = match args.get(1) {
+        Some(&"all") => None,
+        Some(name) => match games::find_game(name) {
+            Some(game) => Some(game.name),
+            None => {
+                let names = games::GAMES.iter().map(|g| g.name).collect::<Vec<_>>();
+                send_msg(
+                    ctx,
+                    author,
+                    &format!("Unknown game! I know about {}.", names.join(", ")),
+                )
+                .await;
+                return Err(CommandOutcome::InvalidArgument);
+            }
+        },
+        None => {
+            send_msg(ctx, author, &format!("Use `{}`!", usage)).await;
+            return Err(CommandOutcome::InvalidArgument);
+        }
+    };
+
+    let channel = match args.get(2) {
+        None => None,
+        Some(arg) => {
+            let channel_id = arg.parse::<u64>().ok().map(ChannelId::from);
+            let channel_guild =
+                channel_id.and_then(|c| ctx.cache.guild_channel_field(c, |c| c.guild_id));
+            if channel_guild != Some(guild_id) {
+                send_msg(ctx, author, "Could not find channel on this server!").await;
+                return Err(CommandOutcome::InvalidArgument);
+            }
+            channel_id
+        }
+    };
+
+    Ok((guild_id, SpoilerRule { game, channel }))
+}
+
+// Parses the arguments of `!add-suppression-rule` and `!remove-suppression-rule`.
+async fn get_suppression_rule_from_msg(
+    ctx: &Context,
+    msg: &Message,
+    usage: &str,
+) -> Result<(GuildId, SuppressionRule), CommandOutcome> {
+    let author = &msg.author;
+    let args = get_arguments_from_msg(msg, 3);
+
+    let guild_id = get_admin_guild_from_arg(ctx, author, args.first().copied(), usage).await?;
+
+    let (kind, pattern) = match args[..] {
+        [_, kind, pattern] => (kind, pattern),
+        _ => {
+            send_msg(ctx, author, &format!("Use `{}`!", usage)).await;
+            return Err(CommandOutcome::InvalidArgument);
+        }
+    };
+
+    match SuppressionRule::parse(kind, pattern) {
+        Ok(rule) => Ok((guild_id, rule)),
+        Err(err) => {
+            send_msg(ctx, author, &format!("{}!", err)).await;
+            Err(CommandOutcome::InvalidArgument)
+        }
+    }
+}
+
+// Discord rejects messages with more characters than this.
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+// Like `send_msg`, but splits text that doesn't fit in a single message between lines.
+async fn send_long_msg(ctx: &Context, recipient: &User, text: &str) -> bool {
+    for part in split_message(text) {
+        if !send_msg(ctx, recipient, &part).await {
+            return false;
+        }
+    }
+    true
+}
+
+fn split_message(text: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut part_len = 0;
+
+    for line in text.lines() {
+        // A single line that's too long has to be cut wherever the limit is reached.
+        let chars = line.chars().collect::<Vec<_>>();
+        for piece in chars
+            .chunks(MAX_MESSAGE_LENGTH)
+            .map(|c| c.iter().collect::<String>())
+        {
+            let piece_len = piece.chars().count();
+            if part_len > 0 && part_len + 1 + piece_len > MAX_MESSAGE_LENGTH {
+                parts.push(std::mem::take(&mut part));
+                part_len = 0;
+            }
+            if part_len > 0 {
+                part.push('\n');
+                part_len += 1;
+            }
+            part.push_str(&piece);
+            part_len += piece_len;
+        }
     }
 
-    if let Err(err) = storage::save_data(pc_data) {
-        error!("Error saving notif_data.json: {:?}", err);
+    if part_len > 0 {
+        parts.push(part);
     }
+    parts
 }
 
-async fn send_msg(ctx: &Context, recipient: &User, text: &str) {
-    let dm = recipient
-        .dm(ctx, |m| {
-            m.content(text);
+// Returns whether the message was sent successfully.
+async fn send_msg(ctx: &Context, recipient: &User, text: &str) -> bool {
+    outbox::send_dm(ctx, &get_state(ctx).await, recipient, text)
+        .await
+        .is_ok()
+}
 
-            m
-        })
+// Tells a user in the guild's fallback channel, if there is one, that their notifications were
+// paused.
+async fn send_fallback_notice(ctx: &Context, guild_id: GuildId, user_id: UserId) {
+    let channel_id = match get_state(ctx).await.read(|d| d.fallback_channel(guild_id)) {
+        Some(c) => c,
+        None => return,
+    };
+
+    let result = channel_id
+        .say(
+            &ctx.http,
+            format!(
+                "<@{}> I couldn't send you DMs about voice channel joins {} times in a row, so \
+                 I've paused your notifications. Allow DMs from server members and send me any \
+                 command to resume them!",
+                user_id, PAUSE_AFTER_FAILURES
+            ),
+        )
         .await;
 
-    if let Err(err) = dm {
-        warn!("Error sending DM to {}: {:?}", recipient, err);
+    if let Err(err) = result {
+        warn!(
+            "Error sending fallback notice to {} in {}: {:?}",
+            user_id, channel_id, err
+        );
     }
 }
 
@@ -546,13 +2671,36 @@ fn get_channel_argument_from_msg(msg: &Message) -> Option<String> {
         .map(|space_idx| content[(space_idx + 1)..].to_string())
 }
 
+// Splits the arguments following the command into at most `n` parts, the last of which holds the
+// rest of the message.
+fn get_arguments_from_msg(msg: &Message, n: usize) -> Vec<&str> {
+    let mut rest = match msg.content.trim().split_once(char::is_whitespace) {
+        Some((_, rest)) => rest.trim_start(),
+        None => return vec![],
+    };
+
+    let mut args = vec![];
+    while !rest.is_empty() {
+        if args.len() + 1 == n {
+            args.push(rest);
+            break;
+        }
+
+        let (arg, remainder) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        args.push(arg);
+        rest = remainder.trim_start();
+    }
+
+    args
+}
+
 async fn get_channel_from_msg(ctx: &Context, msg: &Message) -> Option<Channel> {
     let author = &msg.author;
 
-    let channel = match get_channel_argument_from_msg(&msg) {
+    let channel = match get_channel_argument_from_msg(msg) {
         Some(c) => c,
         None => {
-            send_list_of_common_channels(&ctx, author).await;
+            send_list_of_common_channels(ctx, author).await;
             return None;
         }
     };
@@ -560,15 +2708,15 @@ async fn get_channel_from_msg(ctx: &Context, msg: &Message) -> Option<Channel> {
     let channel_id = match channel.parse::<u64>() {
         Ok(id) => id,
         Err(_) => {
-            send_msg(&ctx, author, "Not a valid channel ID!").await;
+            send_msg(ctx, author, "Not a valid channel ID!").await;
             return None;
         }
     };
 
-    let channel = match ctx.http.get_channel(channel_id).await {
+    let channel = match ChannelId::from(channel_id).to_channel(ctx).await {
         Ok(c) => c,
         Err(_) => {
-            send_msg(&ctx, author, "Could not find channel!").await;
+            send_msg(ctx, author, "Could not find channel!").await;
             return None;
         }
     };
@@ -576,9 +2724,184 @@ async fn get_channel_from_msg(ctx: &Context, msg: &Message) -> Option<Channel> {
     Some(channel)
 }
 
+// Like `get_channel_from_msg`, but also makes sure that the channel belongs to a server and that
+// the author is an admin there, letting them know otherwise.
+async fn get_admin_channel_from_msg(
+    ctx: &Context,
+    msg: &Message,
+) -> Result<GuildChannel, CommandOutcome> {
+    let author = &msg.author;
+
+    let channel = match get_channel_from_msg(ctx, msg).await {
+        Some(c) => c,
+        None => return Err(CommandOutcome::InvalidArgument),
+    };
+
+    let guild_channel = match channel.guild() {
+        Some(gc) => gc,
+        None => {
+            send_msg(
+                ctx,
+                author,
+                "Could not find server that the channel belongs to!",
+            )
+            .await;
+            return Err(CommandOutcome::InvalidArgument);
+        }
+    };
+
+    let is_admin = get_state(ctx)
+        .await
+        .read(|d| d.is_admin(author.id, guild_channel.guild_id));
+    if !is_admin {
+        send_msg(
+            ctx,
+            author,
+            "You are not permitted to modify administrative settings for this server!",
+        )
+        .await;
+        return Err(CommandOutcome::NotPermitted);
+    }
+
+    Ok(guild_channel)
+}
+
+// Parses the server ID argument of an admin command and makes sure that the author is an admin
+// there, letting them know otherwise. Without an argument, lists the servers they are an admin of.
+async fn get_admin_guild_from_arg(
+    ctx: &Context,
+    author: &User,
+    arg: Option<&str>,
+    usage: &str,
+) -> Result<GuildId, CommandOutcome> {
+    let state = get_state(ctx).await;
+
+    let arg = match arg {
+        Some(a) => a,
+        None => {
+            let mut guilds = state.read(|d| {
+                d.guilds()
+                    .filter(|g| d.is_admin(author.id, g.id))
+                    .map(|g| g.id)
+                    .collect::<Vec<_>>()
+            });
+
+            if guilds.is_empty() {
+                send_msg(
+                    ctx,
+                    author,
+                    "You are not permitted to modify administrative settings for any server!",
+                )
+                .await;
+                return Err(CommandOutcome::NotPermitted);
+            }
+
+            guilds.sort_unstable();
+
+            let mut text = format!("Use `{}` with one of the following servers:", usage);
+            for guild_id in guilds {
+                let guild_name = ctx
+                    .cache
+                    .guild_field(guild_id, |g| g.name.clone())
+                    .unwrap_or_else(|| guild_id.to_string());
+                text.push_str(&format!("\n[{}] <{}>", guild_name, guild_id));
+            }
+
+            send_long_msg(ctx, author, &text).await;
+            return Err(CommandOutcome::InvalidArgument);
+        }
+    };
+
+    let guild_id = match arg.parse::<u64>() {
+        Ok(id) => GuildId::from(id),
+        Err(_) => {
+            send_msg(ctx, author, "Not a valid server ID!").await;
+            return Err(CommandOutcome::InvalidArgument);
+        }
+    };
+
+    if !state.read(|d| d.is_admin(author.id, guild_id)) {
+        send_msg(
+            ctx,
+            author,
+            "You are not permitted to modify administrative settings for this server!",
+        )
+        .await;
+        return Err(CommandOutcome::NotPermitted);
+    }
+
+    Ok(guild_id)
+}
+
+// Like `get_admin_guild_from_arg`, but only requires the author to be a member of the server.
+// Without an argument, lists the servers they share with the bot that `is_listed` picks, e.g.
+// those that have anything for the command to show.
+async fn get_member_guild_from_arg(
+    ctx: &Context,
+    author: &User,
+    arg: Option<&str>,
+    usage: &str,
+    is_listed: impl Fn(GuildId) -> bool,
+) -> Result<GuildId, CommandOutcome> {
+    let arg = match arg {
+        Some(a) => a,
+        None => {
+            let mut guilds = ctx.cache.guilds();
+            guilds.retain(|&guild_id| {
+                is_listed(guild_id) && ctx.cache.member(guild_id, author.id).is_some()
+            });
+            guilds.sort_unstable();
+
+            let mut text = format!("Use `{}` with one of the following servers:", usage);
+            for guild_id in guilds {
+                text.push_str(&format!("\n[{}] <{}>", guild_name(ctx, guild_id), guild_id));
+            }
+
+            send_long_msg(ctx, author, &text).await;
+            return Err(CommandOutcome::InvalidArgument);
+        }
+    };
+
+    let guild_id = match arg.parse::<u64>() {
+        Ok(id) => GuildId::from(id),
+        Err(_) => {
+            send_msg(ctx, author, "Not a valid server ID!").await;
+            return Err(CommandOutcome::InvalidArgument);
+        }
+    };
+
+    if guild_id.member(ctx, author.id).await.is_err() {
+        send_msg(ctx, author, "You are not a member of this server!").await;
+        return Err(CommandOutcome::NotPermitted);
+    }
+
+    Ok(guild_id)
+}
+
+fn guild_name(ctx: &Context, guild_id: GuildId) -> String {
+    ctx.cache
+        .guild_field(guild_id, |g| g.name.clone())
+        .unwrap_or_else(|| guild_id.to_string())
+}
+
+fn channel_name(ctx: &Context, channel_id: ChannelId) -> String {
+    ctx.cache
+        .guild_channel_field(channel_id, |c| c.name.clone())
+        .unwrap_or_else(|| channel_id.to_string())
+}
+
 async fn get_guild_from_channel(ctx: &Context, channel: ChannelId) -> Option<GuildId> {
-    ctx.http
-        .get_channel(channel.into())
+    if let Some(guild_id) = ctx.cache.guild_channel_field(channel, |c| c.guild_id) {
+        return Some(guild_id);
+    }
+
+    debug!(
+        "Channel {} not found in cache, falling back to HTTP for guild lookup.",
+        channel
+    );
+
+    channel
+        .to_channel(&ctx.http)
         .await
         .ok()
         .and_then(|channel| channel.guild())
@@ -620,7 +2943,7 @@ async fn get_list_of_common_channels(
             .http
             .get_guild_members(guild.id.into(), Some(1), Some(user.id.into()))
             .await
-            .map_or(false, |members| !members.is_empty());
+            .is_ok_and(|members| !members.is_empty());
 
         if is_guild_common {
             if let Ok(guild_channels) = ctx.http.get_channels(guild.id.into()).await {
@@ -635,10 +2958,29 @@ async fn get_list_of_common_channels(
         .collect())
 }
 
-async fn is_member(channel: &GuildChannel, user_id: UserId, ctx: &Context) -> bool {
-    channel
-        .members(&ctx.cache)
-        .await
-        .map(|members| members.into_iter().any(|u| u.user.id == user_id))
-        .unwrap_or(false)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_messages_are_not_split() {
+        assert_eq!(split_message("a\nb"), vec!["a\nb"]);
+    }
+
+    #[test]
+    fn long_messages_are_split_between_lines() {
+        let line = "x".repeat(900);
+        let text = [line.as_str(); 3].join("\n");
+        let parts = split_message(&text);
+        assert_eq!(parts, vec![format!("{}\n{}", line, line), line.clone()]);
+    }
+
+    #[test]
+    fn long_lines_are_cut() {
+        let text = "é".repeat(MAX_MESSAGE_LENGTH + 1);
+        let parts = split_message(&text);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].chars().count(), MAX_MESSAGE_LENGTH);
+        assert_eq!(parts[1], "é");
+    }
 }
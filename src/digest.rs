@@ -0,0 +1,224 @@
+use crate::model::{unix_now, PCData, VoiceSession, VoiceSessions};
+use crate::outbox::{self, DmError};
+use crate::sessions;
+use crate::state::PCState;
+
+use log::{debug, info, warn};
+use serenity::{
+    cache::Cache,
+    model::id::{ChannelId, GuildId, UserId},
+    CacheAndHttp,
+};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How often the scheduler checks for digests that are due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+const WEEK_SECS: u64 = 7 * 24 * 60 * 60;
+const HOUR_SECS: u64 = 60 * 60;
+
+// How many entries each section of a digest lists at most.
+const DIGEST_ENTRIES: usize = 5;
+
+// Sends weekly digests of the previous week's activity in their subscribed channels to everyone
+// who asked for one, shortly after the week ends (Monday 00:00 UTC). When a digest was last sent
+// is persisted, so restarts don't send it again.
+pub async fn run_scheduler(cache_and_http: Arc<CacheAndHttp>, state: PCState) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+
+        let now = unix_now();
+        let week_start = sessions::start_of_week(now);
+        let subscribers = state.read(|d| d.digest_subscribers().collect::<Vec<_>>());
+        let due = state.read_runtime(|r| r.due_digests(subscribers.into_iter(), week_start));
+
+        for user_id in due {
+            send_digest(
+                &cache_and_http,
+                &state,
+                user_id,
+                week_start - WEEK_SECS,
+                week_start,
+            )
+            .await;
+        }
+    }
+}
+
+async fn send_digest(
+    cache_and_http: &CacheAndHttp,
+    state: &PCState,
+    user_id: UserId,
+    from: u64,
+    to: u64,
+) {
+    let paused = state.read_runtime(|r| r.delivery_failures(user_id).is_some_and(|f| f.paused));
+    let text = state.read(|d| {
+        state.read_sessions(|s| build_digest(d, s, &cache_and_http.cache, user_id, from, to))
+    });
+
+    let sent = match text {
+        _ if paused => {
+            debug!(user = user_id.0; "Skipping digest for {} because DMs to them fail.", user_id);
+            true
+        }
+        None => {
+            debug!(user = user_id.0; "Skipping digest for {}, nothing happened.", user_id);
+            true
+        }
+        Some(text) => match user_id.to_user(cache_and_http).await {
+            Ok(user) => match outbox::send_dm(cache_and_http, state, &user, &text).await {
+                Ok(()) => {
+                    info!(user = user_id.0; "[digest] Sent weekly digest to {}", user_id);
+                    true
+                }
+                // Try again at the next check.
+                Err(DmError::Transient) => false,
+                Err(_) => true,
+            },
+            Err(err) => {
+                warn!(
+                    user = user_id.0;
+                    "Error looking up {} to send them a digest: {:?}",
+                    user_id,
+                    err
+                );
+                false
+            }
+        },
+    };
+
+    if sent {
+        state.update_runtime(|r| r.record_digest_sent(user_id, unix_now()));
+    }
+}
+
+// Summarizes activity in the user's subscribed channels between `from` and `to`, or returns `None`
+// if there was none.
+fn build_digest(
+    data: &PCData,
+    voice_sessions: &VoiceSessions,
+    cache: &Cache,
+    user_id: UserId,
+    from: u64,
+    to: u64,
+) -> Option<String> {
+    let subscriptions = data.find_subscriptions(user_id).collect::<HashSet<_>>();
+    let guild_ids = subscriptions
+        .iter()
+        .map(|&(guild_id, _)| guild_id)
+        .collect::<HashSet<_>>();
+
+    let mut sessions = vec![];
+    let mut own_sessions = vec![];
+    for &guild_id in &guild_ids {
+        for session in voice_sessions.voice_sessions(guild_id, from, to) {
+            if session.start >= to {
+                continue;
+            }
+            if session.user == user_id {
+                own_sessions.push((guild_id, session));
+            } else if subscriptions.contains(&(guild_id, session.channel)) {
+                sessions.push((guild_id, session));
+            }
+        }
+    }
+
+    if sessions.is_empty() {
+        return None;
+    }
+
+    let mut text = format!(
+        "Your weekly voice digest for <t:{}:d> to <t:{}:d>:",
+        from,
+        to - 1
+    );
+
+    // Busiest hours, by the total time everyone spent in the channels during them.
+    let mut hours = HashMap::new();
+    for (_, session) in &sessions {
+        let mut hour = session.start.max(from) / HOUR_SECS * HOUR_SECS;
+        while hour < session.end.min(to) {
+            *hours.entry(hour).or_insert(0) += sessions::overlap(session, hour, hour + HOUR_SECS);
+            hour += HOUR_SECS;
+        }
+    }
+    let mut hours = hours.into_iter().collect::<Vec<_>>();
+    hours.sort_unstable_by_key(|&(hour, secs)| (Reverse(secs), hour));
+
+    text.push_str("\n\nBusiest times:");
+    for (hour, secs) in hours.into_iter().take(DIGEST_ENTRIES) {
+        text.push_str(&format!(
+            "\n- <t:{}:f>: {} in total",
+            hour,
+            sessions::format_duration(secs)
+        ));
+    }
+
+    // Who was around most.
+    let mut users = HashMap::new();
+    for (_, session) in &sessions {
+        *users.entry(session.user).or_insert(0) += sessions::overlap(session, from, to);
+    }
+    let mut users = users.into_iter().collect::<Vec<_>>();
+    users.sort_unstable_by_key(|&(user_id, secs)| (Reverse(secs), user_id));
+
+    text.push_str("\n\nAround most:");
+    for (user_id, secs) in users.into_iter().take(DIGEST_ENTRIES) {
+        text.push_str(&format!(
+            "\n- <@{}>: {}",
+            user_id,
+            sessions::format_duration(secs)
+        ));
+    }
+
+    // Sessions that the user wasn't in the same channel for at any point.
+    let mut missed = sessions
+        .iter()
+        .filter(|(guild_id, session)| {
+            !own_sessions.iter().any(|(own_guild_id, own)| {
+                own_guild_id == guild_id
+                    && own.channel == session.channel
+                    && own.start < session.end
+                    && session.start < own.end
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if !missed.is_empty() {
+        missed.sort_unstable_by_key(|(_, s)| (Reverse(s.end - s.start), s.start));
+
+        text.push_str(&format!(
+            "\n\nYou missed {} session(s), including:",
+            missed.len()
+        ));
+        for (guild_id, session) in missed.into_iter().take(DIGEST_ENTRIES) {
+            text.push_str(&format!(
+                "\n- <@{}> in {} at <t:{}:f> for {}",
+                session.user,
+                channel_description(cache, *guild_id, session),
+                session.start,
+                sessions::format_duration(session.end - session.start)
+            ));
+        }
+    }
+
+    Some(text)
+}
+
+fn channel_description(cache: &Cache, guild_id: GuildId, session: &VoiceSession) -> String {
+    let guild_name = cache
+        .guild_field(guild_id, |g| g.name.clone())
+        .unwrap_or_else(|| guild_id.to_string());
+    let channel_name = channel_name(cache, session.channel);
+    format!("{} on {}", channel_name, guild_name)
+}
+
+fn channel_name(cache: &Cache, channel_id: ChannelId) -> String {
+    cache
+        .guild_channel_field(channel_id, |c| c.name.clone())
+        .unwrap_or_else(|| channel_id.to_string())
+}
@@ -0,0 +1,318 @@
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+// Daily puzzle games whose result posts are recorded. These are the games behind the default
+// embed suppression rules.
+pub struct Game {
+    pub name: &'static str,
+    // The most guesses a result can take. Failed attempts count as one more than this when
+    // averaging.
+    pub max_score: u32,
+}
+
+pub const GAMES: [Game; 6] = [
+    Game {
+        name: "Heardle",
+        max_score: 6,
+    },
+    Game {
+        name: "Framed",
+        max_score: 6,
+    },
+    Game {
+        name: "Moviedle",
+        max_score: 6,
+    },
+    Game {
+        name: "Tradle",
+        max_score: 6,
+    },
+    Game {
+        name: "Posterdle",
+        max_score: 6,
+    },
+    Game {
+        name: "Yeardle",
+        max_score: 8,
+    },
+];
+
+pub fn find_game(name: &str) -> Option<&'static Game> {
+    GAMES.iter().find(|g| g.name.eq_ignore_ascii_case(name))
+}
+
+// Scores of one user in one game, by day. `None` means they didn't solve that day's puzzle.
+pub type GameScores = BTreeMap<u32, Option<u32>>;
+
+pub struct GameResult {
+    pub game: &'static Game,
+    // The puzzle's number. Games that number their puzzles by date use days since 1970 instead.
+    pub day: u32,
+    pub score: Option<u32>,
+}
+
+const SQUARES: [char; 9] = ['🟩', '🟨', '🟧', '🟥', '🟦', '🟪', '🟫', '⬛', '⬜'];
+
+// Recognizes result posts like "#Heardle #245" followed by the game's emoji grid. The header has
+// to start a line, with nothing but an explicit score like "3/6" and emoji after it on that line,
+// and unless there is such a score, the grid has to come right after it. The score is taken from
+// an explicit one if the game shares it, otherwise from the grid: with a single row of squares,
+// it's the position of the green one, with several rows the first all-green row.
+pub fn parse_result(content: &str) -> Option<GameResult> {
+    static HEADERS: OnceLock<Vec<Regex>> = OnceLock::new();
+    let headers = HEADERS.get_or_init(|| {
+        GAMES
+            .iter()
+            .map(|game| {
+                Regex::new(&format!(
+                    r"(?im)^[^\S\n]*#?\b{}\s*#\s*(\d{{4}}-\d{{2}}-\d{{2}}|\d+)(?:\s+([1-9X])/\d+)?",
+                    game.name
+                ))
+                .unwrap()
+            })
+            .collect()
+    });
+
+    let (game, captures) = GAMES
+        .iter()
+        .zip(headers)
+        .find_map(|(game, header)| header.captures(content).map(|c| (game, c)))?;
+
+    let day = parse_day(&captures[1])?;
+    let after_header = &content[captures.get(0).unwrap().end()..];
+    if after_header
+        .lines()
+        .next()
+        .is_some_and(|line| line.chars().any(char::is_alphanumeric))
+    {
+        return None;
+    }
+
+    let score = match captures.get(2).map(|s| s.as_str()) {
+        Some("X") | Some("x") => None,
+        Some(score) => Some(score.parse().ok()?),
+        None => score_from_grid(&grid_after_header(after_header))?,
+    };
+
+    if score.is_some_and(|s| s > game.max_score) {
+        return None;
+    }
+
+    Some(GameResult { game, day, score })
+}
+
+fn parse_day(day: &str) -> Option<u32> {
+    match day.split('-').collect::<Vec<_>>()[..] {
+        [number] => number.parse().ok(),
+        [year, month, day] => {
+            days_since_epoch(year.parse().ok()?, month.parse().ok()?, day.parse().ok()?)
+        }
+        _ => None,
+    }
+}
+
+// Howard Hinnant's `days_from_civil`.
+fn days_since_epoch(year: i64, month: i64, day: i64) -> Option<u32> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    u32::try_from(era * 146097 + day_of_era - 719468).ok()
+}
+
+// The rows of squares right after the header, skipping lines without squares or text in between,
+// like the empty one or the "🔉" in Heardle's posts.
+fn grid_after_header(text: &str) -> String {
+    let is_row = |line: &str| line.chars().any(|c| SQUARES.contains(&c));
+
+    text.lines()
+        .skip_while(|line| !is_row(line) && !line.chars().any(char::is_alphanumeric))
+        .take_while(|line| is_row(line) && !line.chars().any(char::is_alphanumeric))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Returns `None` if there is no grid, which means the post isn't a result after all.
+fn score_from_grid(text: &str) -> Option<Option<u32>> {
+    let rows = text
+        .lines()
+        .map(|line| {
+            line.chars()
+                .filter(|c| SQUARES.contains(c))
+                .collect::<Vec<_>>()
+        })
+        .filter(|row| !row.is_empty())
+        .collect::<Vec<_>>();
+
+    let solved_at = match &rows[..] {
+        [] => return None,
+        [row] => row.iter().position(|&c| c == '🟩'),
+        rows => rows.iter().position(|row| row.iter().all(|&c| c == '🟩')),
+    };
+
+    Some(solved_at.map(|i| i as u32 + 1))
+}
+
+// Wraps every line of the result grid in spoiler tags.
+pub fn hide_grid(content: &str) -> String {
+    content
+        .lines()
+        .map(|line| {
+            if line.chars().any(|c| SQUARES.contains(&c)) {
+                format!("||{}||", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub struct PlayerStats {
+    pub played: usize,
+    pub solved: usize,
+    pub average: f64,
+    pub current_streak: u32,
+    pub best_streak: u32,
+}
+
+// A streak is a run of puzzles on consecutive days that were all solved. It is only current if it
+// reaches up to the day before `latest_day`, the newest puzzle anyone has posted, at least.
+pub fn player_stats(game: &Game, scores: &GameScores, latest_day: u32) -> PlayerStats {
+    let mut solved = 0;
+    let mut total = 0;
+    let mut streak = 0;
+    let mut best_streak = 0;
+    let mut previous_day = None;
+
+    for (&day, &score) in scores {
+        match score {
+            Some(score) => {
+                solved += 1;
+                total += score;
+                streak = match previous_day {
+                    Some(previous) if previous + 1 == day && streak > 0 => streak + 1,
+                    _ => 1,
+                };
+            }
+            None => {
+                total += game.max_score + 1;
+                streak = 0;
+            }
+        }
+
+        best_streak = best_streak.max(streak);
+        previous_day = Some(day);
+    }
+
+    let current_streak = match previous_day {
+        Some(day) if day + 1 >= latest_day => streak,
+        _ => 0,
+    };
+
+    PlayerStats {
+        played: scores.len(),
+        solved,
+        average: if scores.is_empty() {
+            0.0
+        } else {
+            total as f64 / scores.len() as f64
+        },
+        current_streak,
+        best_streak,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_explicit_scores() {
+        let result =
+            parse_result("#Heardle #245\n\n🔉🟥🟩⬜⬜⬜⬜\n\nhttps://heardle.app").unwrap();
+        assert_eq!(result.game.name, "Heardle");
+        assert_eq!(result.day, 245);
+        assert_eq!(result.score, Some(2));
+
+        let result = parse_result("Framed #312 X/6\n🎥 🟥 🟥 🟥 🟥 🟥 🟥").unwrap();
+        assert_eq!(result.game.name, "Framed");
+        assert_eq!(result.score, None);
+
+        let result = parse_result("#Yeardle #101 4/8\n⬜⬜⬜🟩").unwrap();
+        assert_eq!(result.score, Some(4));
+    }
+
+    #[test]
+    fn parses_dates_as_days_since_epoch() {
+        let result = parse_result("#Posterdle #2024-03-01\n🟥🟥🟩").unwrap();
+        assert_eq!(result.day, 19783);
+        assert_eq!(result.score, Some(3));
+
+        assert!(parse_result("#Posterdle #2024-13-01\n🟩").is_none());
+    }
+
+    #[test]
+    fn rejects_posts_without_results() {
+        assert!(parse_result("Did anyone do today's Heardle #245 yet?").is_none());
+        assert!(parse_result("#Tradle #12").is_none());
+        assert!(parse_result("Just a message").is_none());
+    }
+
+    #[test]
+    fn rejects_casual_mentions() {
+        assert!(parse_result("heardle #3 was hard 🟩").is_none());
+        assert!(parse_result("heardle #3 3/6 was hard").is_none());
+        assert!(parse_result("I think heardle #3\n🟩").is_none());
+        assert!(parse_result("#Heardle #3\nwas hard\n🟩").is_none());
+        assert!(parse_result("#Heardle #3\n🟩 lol").is_none());
+    }
+
+    #[test]
+    fn allows_emoji_and_blank_lines_before_the_grid() {
+        let result =
+            parse_result("Some text first\n  #Moviedle #2022-05-18 \n\n 🎥 🟥 🟩 ⬜️ \n").unwrap();
+        assert_eq!(result.game.name, "Moviedle");
+        assert_eq!(result.score, Some(2));
+    }
+
+    #[test]
+    fn only_counts_the_grid_right_after_the_header() {
+        let result =
+            parse_result("#Yeardle #101\n⬜⬜🟨\n🟩🟩🟩\n\nhttps://histordle.com/\n🟥").unwrap();
+        assert_eq!(result.score, Some(2));
+    }
+
+    #[test]
+    fn rejects_scores_above_the_maximum() {
+        assert!(parse_result("#Heardle #245 7/6\n🟥🟥🟥🟥🟥🟥🟩").is_none());
+    }
+
+    #[test]
+    fn scores_single_row_grids_by_the_green_square() {
+        assert_eq!(score_from_grid("\n🟥🟨🟩⬜⬜⬜"), Some(Some(3)));
+        assert_eq!(score_from_grid("\n🟥🟥🟥🟥🟥🟥"), Some(None));
+    }
+
+    #[test]
+    fn scores_multi_row_grids_by_the_first_all_green_row() {
+        let grid = "\n⬜🟨⬜⬜⬜\n🟩🟩🟨⬜🟩\n🟩🟩🟩🟩🟩\n";
+        assert_eq!(score_from_grid(grid), Some(Some(3)));
+
+        let grid = "\n⬜🟨⬜⬜⬜\n🟩🟩🟨⬜🟩\n";
+        assert_eq!(score_from_grid(grid), Some(None));
+    }
+
+    #[test]
+    fn no_grid_is_no_score() {
+        assert_eq!(score_from_grid("\nno squares here"), None);
+        assert_eq!(score_from_grid(""), None);
+    }
+}
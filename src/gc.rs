@@ -0,0 +1,326 @@
+use crate::model::{unix_now, PCData, RuntimeData, VoiceSessions};
+use crate::state::PCState;
+
+use log::info;
+use serenity::{
+    cache::Cache,
+    model::{
+        guild::UnavailableGuild,
+        id::{ChannelId, GuildId, UserId},
+    },
+};
+use std::collections::{HashMap, HashSet};
+
+// Removes settings, voice sessions and runtime data that refer to channels, guilds and members that
+// don't exist anymore, so that they don't accumulate dead entries forever. Everything removed is
+// logged.
+
+pub fn channel_deleted(state: &PCState, guild_id: GuildId, channel_id: ChannelId) {
+    prune(state, "channel was deleted", |d| {
+        d.remove_channel(guild_id, channel_id)
+    });
+    prune_sessions(state, "channel was deleted", |s| {
+        s.end_channel_sessions(guild_id, channel_id, unix_now())
+    });
+    prune_runtime(state, "channel was deleted", |r| {
+        r.remove_channel(guild_id, channel_id)
+    });
+}
+
+pub fn guild_deleted(state: &PCState, guild: &UnavailableGuild) {
+    // Guilds also get deleted when they become unavailable because of an outage, in which case
+    // they come back later.
+    if guild.unavailable {
+        return;
+    }
+
+    let guild_id = guild.id;
+    prune(state, "bot left the guild", |d| d.remove_guild(guild_id));
+    prune_sessions(state, "bot left the guild", |s| s.remove_guild(guild_id));
+    prune_runtime(state, "bot left the guild", |r| r.remove_guild(guild_id));
+}
+
+pub fn member_removed(state: &PCState, cache: &Cache, guild_id: GuildId, user_id: UserId) {
+    let in_other_guilds = in_other_guilds(cache, guild_id, user_id);
+    prune(state, "member left the guild", |d| {
+        d.remove_member(guild_id, user_id, in_other_guilds)
+    });
+    prune_sessions(state, "member left the guild", |s| {
+        s.remove_member(guild_id, user_id)
+    });
+    prune_runtime(state, "member left the guild", |r| {
+        r.remove_member(guild_id, user_id, in_other_guilds)
+    });
+}
+
+// Compares all settings against the cache, to clean up after anything that happened while the bot
+// wasn't running. Must only be called once the cache is ready.
+pub fn reconcile(state: &PCState, cache: &Cache) {
+    let mut guilds = state.read(|d| {
+        d.guilds()
+            .map(|g| (g.id, (g.channel_ids(), g.user_ids())))
+            .collect::<HashMap<_, _>>()
+    });
+    state.read_sessions(|s| {
+        for guild_id in s.guild_ids() {
+            let (_, user_ids) = guilds.entry(guild_id).or_default();
+            user_ids.extend(s.user_ids(guild_id));
+        }
+    });
+    state.read_runtime(|r| {
+        for guild_id in r.guild_ids() {
+            let (channel_ids, user_ids) = guilds.entry(guild_id).or_default();
+            channel_ids.extend(r.channel_ids(guild_id));
+            user_ids.extend(r.user_ids(guild_id));
+        }
+    });
+    let unavailable_guilds = cache.unavailable_guilds();
+
+    for (guild_id, (channel_ids, user_ids)) in guilds {
+        if unavailable_guilds.contains(&guild_id) {
+            continue;
+        }
+
+        let missing = cache.guild_field(guild_id, |guild| {
+            find_missing(
+                &channel_ids,
+                &user_ids,
+                |c| guild.channels.contains_key(&c),
+                |u| guild.members.contains_key(&u),
+                guild.members.len() as u64 >= guild.member_count,
+            )
+        });
+
+        let (missing_channels, missing_users) = match missing {
+            Some(m) => m,
+            // The guild missing from the cache doesn't prove that the bot was removed from it, so
+            // its settings are only dropped on an actual `guild_delete`.
+            None => {
+                info!(
+                    "[gc] Guild {} is not in the cache, keeping its settings",
+                    guild_id
+                );
+                continue;
+            }
+        };
+
+        for channel_id in missing_channels {
+            prune(state, "channel doesn't exist anymore", |d| {
+                d.remove_channel(guild_id, channel_id)
+            });
+            prune_runtime(state, "channel doesn't exist anymore", |r| {
+                r.remove_channel(guild_id, channel_id)
+            });
+        }
+        for user_id in missing_users {
+            let in_other_guilds = in_other_guilds(cache, guild_id, user_id);
+            prune(state, "user is not a member anymore", |d| {
+                d.remove_member(guild_id, user_id, in_other_guilds)
+            });
+            prune_sessions(state, "user is not a member anymore", |s| {
+                s.remove_member(guild_id, user_id)
+            });
+            prune_runtime(state, "user is not a member anymore", |r| {
+                r.remove_member(guild_id, user_id, in_other_guilds)
+            });
+        }
+    }
+}
+
+// Finds the channels and users of a guild's settings that don't exist in it anymore.
+fn find_missing(
+    channel_ids: &HashSet<ChannelId>,
+    user_ids: &HashSet<UserId>,
+    has_channel: impl Fn(ChannelId) -> bool,
+    has_member: impl Fn(UserId) -> bool,
+    all_members_cached: bool,
+) -> (Vec<ChannelId>, Vec<UserId>) {
+    let mut missing_channels = channel_ids
+        .iter()
+        .copied()
+        .filter(|&c| !has_channel(c))
+        .collect::<Vec<_>>();
+    missing_channels.sort_unstable();
+
+    // For large guilds, Discord doesn't send all members up front, so a member that isn't cached
+    // may well still be there.
+    let mut missing_users = if all_members_cached {
+        user_ids
+            .iter()
+            .copied()
+            .filter(|&u| !has_member(u))
+            .collect::<Vec<_>>()
+    } else {
+        vec![]
+    };
+    missing_users.sort_unstable();
+
+    (missing_channels, missing_users)
+}
+
+// Whether the user may still be in a guild with the bot other than `guild_id`. Guilds that aren't
+// fully cached count as long as the user could be one of their uncached members.
+fn in_other_guilds(cache: &Cache, guild_id: GuildId, user_id: UserId) -> bool {
+    cache
+        .guilds()
+        .into_iter()
+        .filter(|&g| g != guild_id)
+        .any(|g| {
+            cache
+                .guild_field(g, |guild| {
+                    guild.members.contains_key(&user_id)
+                        || (guild.members.len() as u64) < guild.member_count
+                })
+                .unwrap_or(true)
+        })
+}
+
+fn prune(state: &PCState, reason: &str, f: impl FnOnce(&mut PCData) -> Vec<String>) {
+    let mut removed = vec![];
+    state.update(|d| {
+        removed = f(d);
+        !removed.is_empty()
+    });
+
+    for change in removed {
+        info!("[gc] {} ({})", change, reason);
+    }
+}
+
+fn prune_sessions(
+    state: &PCState,
+    reason: &str,
+    f: impl FnOnce(&mut VoiceSessions) -> Vec<String>,
+) {
+    let mut removed = vec![];
+    state.update_sessions(|s| {
+        removed = f(s);
+        !removed.is_empty()
+    });
+
+    for change in removed {
+        info!("[gc] {} ({})", change, reason);
+    }
+}
+
+fn prune_runtime(state: &PCState, reason: &str, f: impl FnOnce(&mut RuntimeData) -> Vec<String>) {
+    let mut removed = vec![];
+    state.update_runtime(|r| {
+        removed = f(r);
+        !removed.is_empty()
+    });
+
+    for change in removed {
+        info!("[gc] {} ({})", change, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_state() -> PCState {
+        let mut data = PCData::default();
+        data.add_admin(UserId(1), GuildId(10), false);
+        data.add_subscription(UserId(2), GuildId(10), ChannelId(101));
+        data.add_afk_channel(GuildId(10), ChannelId(100));
+        data.add_lobby_channel(GuildId(20), ChannelId(200));
+
+        let mut sessions = VoiceSessions::default();
+        sessions.update_voice_session(GuildId(10), UserId(2), Some(ChannelId(101)), 1_000);
+        sessions.update_voice_session(GuildId(10), UserId(3), Some(ChannelId(100)), 1_000);
+
+        PCState::new(PathBuf::new(), data, sessions, RuntimeData::default())
+    }
+
+    fn open_sessions(state: &PCState, guild_id: GuildId) -> Vec<(UserId, ChannelId)> {
+        let mut open = state.read_sessions(|s| {
+            s.voice_sessions(guild_id, 0, u64::MAX)
+                .filter(|s| s.end == u64::MAX)
+                .map(|s| (s.user, s.channel))
+                .collect::<Vec<_>>()
+        });
+        open.sort_unstable();
+        open
+    }
+
+    #[test]
+    fn deleted_channels_are_pruned_and_their_sessions_ended() {
+        let state = test_state();
+        channel_deleted(&state, GuildId(10), ChannelId(101));
+
+        state.read(|d| {
+            assert_eq!(d.find_subscriptions(UserId(2)).count(), 0);
+            assert!(d.is_afk_channel(GuildId(10), ChannelId(100)));
+            assert!(d.is_admin(UserId(1), GuildId(10)));
+        });
+        assert_eq!(
+            open_sessions(&state, GuildId(10)),
+            [(UserId(3), ChannelId(100))]
+        );
+        // The ended session itself is kept.
+        assert!(state.read_sessions(|s| s.user_ids(GuildId(10)).contains(&UserId(2))));
+    }
+
+    #[test]
+    fn unavailable_guilds_are_kept() {
+        let state = test_state();
+        let guild = UnavailableGuild {
+            id: GuildId(10),
+            unavailable: true,
+        };
+        guild_deleted(&state, &guild);
+
+        assert!(state.read(|d| d.is_admin(UserId(1), GuildId(10))));
+        assert_eq!(open_sessions(&state, GuildId(10)).len(), 2);
+    }
+
+    #[test]
+    fn removed_guilds_are_pruned() {
+        let state = test_state();
+        let guild = UnavailableGuild {
+            id: GuildId(10),
+            unavailable: false,
+        };
+        guild_deleted(&state, &guild);
+
+        state.read(|d| {
+            assert_eq!(d.guilds().map(|g| g.id).collect::<Vec<_>>(), [GuildId(20)]);
+            assert_eq!(d.find_subscriptions(UserId(2)).count(), 0);
+        });
+        assert!(state.read_sessions(|s| s.guild_ids().next().is_none()));
+    }
+
+    #[test]
+    fn guilds_missing_from_the_cache_are_kept() {
+        let state = test_state();
+        reconcile(&state, &Cache::new());
+
+        state.read(|d| {
+            assert!(d.is_admin(UserId(1), GuildId(10)));
+            assert!(d.is_lobby_channel(GuildId(20), ChannelId(200)));
+        });
+        assert_eq!(open_sessions(&state, GuildId(10)).len(), 2);
+    }
+
+    #[test]
+    fn uncached_members_are_only_missing_if_all_members_are_cached() {
+        let channel_ids = HashSet::from([ChannelId(100), ChannelId(101), ChannelId(102)]);
+        let user_ids = HashSet::from([UserId(1), UserId(2), UserId(3)]);
+        let has_channel = |c| c == ChannelId(100);
+        let has_member = |u| u == UserId(2);
+
+        assert_eq!(
+            find_missing(&channel_ids, &user_ids, has_channel, has_member, true),
+            (
+                vec![ChannelId(101), ChannelId(102)],
+                vec![UserId(1), UserId(3)]
+            )
+        );
+        assert_eq!(
+            find_missing(&channel_ids, &user_ids, has_channel, has_member, false),
+            (vec![ChannelId(101), ChannelId(102)], vec![])
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use crate::storage;
+
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use serenity::{
+    async_trait,
+    client::bridge::gateway::ShardManager,
+    gateway::ConnectionStage,
+    model::{event::Event, id::GuildId},
+    prelude::{Context, RawEventHandler},
+};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// How often to check the shards for new heartbeat acknowledgements.
+const HEARTBEAT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// If neither an event nor a heartbeat acknowledgement arrives for this long, the connection is
+// considered dead even if the shards still claim to be connected. Discord asks for a heartbeat
+// about every 41 seconds.
+const MAX_GATEWAY_SILENCE: Duration = Duration::from_secs(3 * 60);
+
+// What the event handlers have learned about the connection to Discord, for the health checks.
+#[derive(Default)]
+pub struct GatewayEvents {
+    // Set once the cache has received all guilds after startup.
+    cache_ready: AtomicBool,
+    last_event: std::sync::Mutex<Option<Instant>>,
+    last_heartbeat: std::sync::Mutex<Option<Instant>>,
+    // Guilds that went unavailable because of a Discord outage and haven't come back yet.
+    unavailable_guilds: std::sync::Mutex<HashSet<GuildId>>,
+}
+
+impl GatewayEvents {
+    pub fn set_cache_ready(&self) {
+        self.cache_ready.store(true, Ordering::SeqCst);
+    }
+
+    pub fn guild_unavailable(&self, guild_id: GuildId) {
+        self.unavailable_guilds.lock().unwrap().insert(guild_id);
+    }
+
+    pub fn guild_available(&self, guild_id: GuildId) {
+        self.unavailable_guilds.lock().unwrap().remove(&guild_id);
+    }
+}
+
+// Records the time of every event received from the gateway.
+pub struct EventRecorder(pub Arc<GatewayEvents>);
+
+#[async_trait]
+impl RawEventHandler for EventRecorder {
+    async fn raw_event(&self, _ctx: Context, _event: Event) {
+        *self.0.last_event.lock().unwrap() = Some(Instant::now());
+    }
+}
+
+#[derive(Clone)]
+pub struct HealthCheck {
+    events: Arc<GatewayEvents>,
+    shard_manager: Arc<Mutex<ShardManager>>,
+}
+
+pub struct HealthReport {
+    // Stage and heartbeat latency of each shard.
+    shards: Vec<(u64, ConnectionStage, Option<Duration>)>,
+    last_event_age: Option<Duration>,
+    last_heartbeat_age: Option<Duration>,
+    cache_ready: bool,
+    unavailable_guilds: Vec<GuildId>,
+    storage_writable: bool,
+}
+
+impl HealthCheck {
+    pub fn new(events: Arc<GatewayEvents>, shard_manager: Arc<Mutex<ShardManager>>) -> HealthCheck {
+        HealthCheck {
+            events,
+            shard_manager,
+        }
+    }
+
+    async fn shards(&self) -> Vec<(u64, ConnectionStage, Option<Duration>)> {
+        let mut shards = {
+            let shard_manager = self.shard_manager.lock().await;
+            let runners = shard_manager.runners.lock().await;
+            runners
+                .iter()
+                .map(|(id, info)| (id.0, info.stage, info.latency))
+                .collect::<Vec<_>>()
+        };
+        shards.sort_unstable_by_key(|(id, _, _)| *id);
+        shards
+    }
+
+    pub async fn report(&self) -> HealthReport {
+        let shards = self.shards().await;
+
+        let mut unavailable_guilds = self
+            .events
+            .unavailable_guilds
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        unavailable_guilds.sort_unstable();
+
+        let storage_writable =
+            tokio::task::spawn_blocking(|| storage::check_writable(Path::new(storage::CONFIG_DIR)))
+                .await
+                .map_err(|e| e.into())
+                .and_then(|r| r)
+                .map_err(|err: io::Error| warn!("Storage is not writable: {:?}", err))
+                .is_ok();
+
+        HealthReport {
+            shards,
+            last_event_age: self.events.last_event.lock().unwrap().map(|t| t.elapsed()),
+            last_heartbeat_age: self
+                .events
+                .last_heartbeat
+                .lock()
+                .unwrap()
+                .map(|t| t.elapsed()),
+            cache_ready: self.events.cache_ready.load(Ordering::SeqCst),
+            unavailable_guilds,
+            storage_writable,
+        }
+    }
+}
+
+impl HealthReport {
+    fn gateway_connected(&self) -> bool {
+        !self.shards.is_empty()
+            && self
+                .shards
+                .iter()
+                .all(|(_, stage, _)| *stage == ConnectionStage::Connected)
+    }
+
+    // Whether nothing has been heard from Discord for too long. Before anything was received at
+    // all, `gateway_connected` is false anyway.
+    fn gateway_silent(&self) -> bool {
+        let last_activity_age = match (self.last_event_age, self.last_heartbeat_age) {
+            (Some(event), Some(heartbeat)) => Some(event.min(heartbeat)),
+            (event, heartbeat) => event.or(heartbeat),
+        };
+        last_activity_age.is_some_and(|age| age > MAX_GATEWAY_SILENCE)
+    }
+
+    // Whether the bot is working at all. If this stays false, it should be restarted.
+    pub fn is_healthy(&self) -> bool {
+        self.gateway_connected() && !self.gateway_silent() && self.storage_writable
+    }
+
+    // Whether the bot is fully started up and knows about all of its guilds.
+    pub fn is_ready(&self) -> bool {
+        self.is_healthy() && self.cache_ready
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "healthy": self.is_healthy(),
+            "ready": self.is_ready(),
+            "gateway": {
+                "connected": self.gateway_connected(),
+                "shards": self
+                    .shards
+                    .iter()
+                    .map(|(id, stage, latency)| json!({
+                        "id": id,
+                        "stage": stage.to_string(),
+                        "heartbeat_latency_ms": latency.map(|l| l.as_millis() as u64),
+                    }))
+                    .collect::<Vec<_>>(),
+                "last_event_seconds_ago": self.last_event_age.map(|a| a.as_secs()),
+                "last_heartbeat_seconds_ago": self.last_heartbeat_age.map(|a| a.as_secs()),
+                "silent": self.gateway_silent(),
+            },
+            "cache_ready": self.cache_ready,
+            "unavailable_guilds": self
+                .unavailable_guilds
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>(),
+            "storage_writable": self.storage_writable,
+        })
+    }
+}
+
+// Serenity doesn't pass heartbeat acknowledgements on as events, but every one of them updates the
+// shard's latency. So whenever a latency changes, an acknowledgement has just arrived.
+pub async fn run_heartbeat_watcher(check: HealthCheck) {
+    let mut interval = tokio::time::interval(HEARTBEAT_POLL_INTERVAL);
+    let mut latencies = HashMap::new();
+
+    loop {
+        interval.tick().await;
+
+        let current = check
+            .shards()
+            .await
+            .into_iter()
+            .map(|(id, _, latency)| (id, latency))
+            .collect::<HashMap<_, _>>();
+        if current
+            .iter()
+            .any(|(id, latency)| latency.is_some() && latencies.get(id) != Some(latency))
+        {
+            *check.events.last_heartbeat.lock().unwrap() = Some(Instant::now());
+        }
+        latencies = current;
+    }
+}
+
+// When running as a systemd service with `Type=notify`, reports readiness and, if `WatchdogSec`
+// is configured, keeps the watchdog fed for as long as the bot is healthy.
+pub async fn run_systemd_notifier(check: HealthCheck) {
+    if env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+
+    let watchdog_interval = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(|usec| Duration::from_micros(usec) / 2);
+    let mut interval = tokio::time::interval(watchdog_interval.unwrap_or(Duration::from_secs(5)));
+    let mut notified_ready = false;
+
+    loop {
+        interval.tick().await;
+        let report = check.report().await;
+
+        if !notified_ready && report.is_ready() {
+            info!("Notifying systemd that the bot is ready.");
+            sd_notify("READY=1");
+            notified_ready = true;
+            if watchdog_interval.is_none() {
+                return;
+            }
+        }
+
+        if notified_ready && report.is_healthy() {
+            sd_notify("WATCHDOG=1");
+        } else if notified_ready {
+            debug!("Not feeding the systemd watchdog, the bot is unhealthy.");
+        }
+    }
+}
+
+fn sd_notify(message: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::ffi::OsStrExt;
+            use std::os::unix::net::SocketAddr;
+
+            // Socket paths starting with '@' are in the abstract namespace.
+            if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+                let addr = SocketAddr::from_abstract_name(name)?;
+                return socket.send_to_addr(message.as_bytes(), &addr);
+            }
+        }
+
+        socket.send_to(message.as_bytes(), &path)
+    });
+
+    if let Err(err) = result {
+        warn!("Error notifying systemd: {:?}", err);
+    }
+}
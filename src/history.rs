@@ -0,0 +1,133 @@
+use serenity::model::{id::UserId, user::OnlineStatus};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// How many join events are remembered per subscriber.
+pub const HISTORY_LENGTH: usize = 10;
+
+// A join that subscribers were considered for notifying about.
+pub struct JoinEvent {
+    pub at: SystemTime,
+    pub guild_name: String,
+    pub channel_name: String,
+    pub joined_user_name: String,
+}
+
+// Why a subscriber was or wasn't notified about a join.
+#[derive(Clone, Copy)]
+pub enum Decision {
+    Notified,
+    IsJoiner,
+    InJoinedChannel,
+    InOtherChannel,
+    NoPresence,
+    Status(OnlineStatus),
+    UnknownUser,
+    Paused,
+    BackingOff,
+    Stale,
+    DeliveryFailed,
+}
+
+impl Decision {
+    // Label for the skipped notifications metric, or `None` if the subscriber was notified.
+    pub fn skip_reason(self) -> Option<&'static str> {
+        match self {
+            Decision::Notified => None,
+            Decision::IsJoiner => Some("self"),
+            Decision::InJoinedChannel => Some("in_channel"),
+            Decision::InOtherChannel => Some("in_other_channel"),
+            Decision::NoPresence => Some("no_presence"),
+            Decision::Status(_) => Some("presence"),
+            Decision::UnknownUser => Some("unknown_user"),
+            Decision::Paused => Some("paused"),
+            Decision::BackingOff => Some("backing_off"),
+            Decision::Stale => Some("stale"),
+            Decision::DeliveryFailed => Some("dm_failure"),
+        }
+    }
+
+    fn describe(self) -> String {
+        match self {
+            Decision::Notified => "you were notified".to_string(),
+            Decision::IsJoiner => "not notified, that was you".to_string(),
+            Decision::InJoinedChannel => {
+                "not notified, you were already in the channel".to_string()
+            }
+            Decision::InOtherChannel => {
+                "not notified, you were in another voice channel on the server".to_string()
+            }
+            Decision::NoPresence => {
+                "not notified, your online status was unknown (offline?)".to_string()
+            }
+            Decision::Status(status) => {
+                format!("not notified, your status was {}", status.name())
+            }
+            Decision::UnknownUser => "not notified, your user couldn't be looked up".to_string(),
+            Decision::Paused => {
+                "not notified, your notifications are paused because I couldn't DM you".to_string()
+            }
+            Decision::BackingOff => {
+                "not notified, I couldn't DM you recently and am waiting before trying again"
+                    .to_string()
+            }
+            Decision::Stale => {
+                "not notified, they had already left again by the time I got to it".to_string()
+            }
+            Decision::DeliveryFailed => {
+                "not notified, sending you a DM failed (are your DMs closed?)".to_string()
+            }
+        }
+    }
+}
+
+type UserHistory = VecDeque<(Arc<JoinEvent>, Decision)>;
+
+// Keeps the most recent notification decisions for each subscriber in memory, so they can find
+// out why they were or weren't notified.
+#[derive(Default)]
+pub struct NotificationHistory {
+    entries: Mutex<HashMap<UserId, UserHistory>>,
+}
+
+impl NotificationHistory {
+    pub fn record(&self, user_id: UserId, event: &Arc<JoinEvent>, decision: Decision) {
+        let mut entries = self.entries.lock().unwrap();
+        let history = entries.entry(user_id).or_default();
+
+        if history.len() == HISTORY_LENGTH {
+            history.pop_front();
+        }
+        history.push_back((event.clone(), decision));
+    }
+
+    // Describes the user's recent decisions, newest first.
+    pub fn describe(&self, user_id: UserId) -> Vec<String> {
+        let entries = self.entries.lock().unwrap();
+        let history = match entries.get(&user_id) {
+            Some(h) => h,
+            None => return Vec::new(),
+        };
+
+        history
+            .iter()
+            .rev()
+            .map(|(event, decision)| {
+                let at = event
+                    .at
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                format!(
+                    "<t:{}:f> {} joined {} on {}: {}",
+                    at,
+                    event.joined_user_name,
+                    event.channel_name,
+                    event.guild_name,
+                    decision.describe()
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,223 @@
+use crate::admin_api;
+use crate::health::HealthCheck;
+use crate::metrics;
+use crate::state::PCState;
+
+use hyper::{server::conn::Http, service::service_fn, Body, Request, Response, StatusCode};
+use log::{debug, error, info, warn};
+use std::convert::Infallible;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+
+// Configuration for the optional local HTTP server, read from the environment:
+// - PROBLEM_CHILD_HTTP_ADDR: Either `<ip>:<port>` or `unix:<path>`. The server is only started
+//   if this is set.
+// - PROBLEM_CHILD_ADMIN_TOKEN or PROBLEM_CHILD_ADMIN_TOKEN_FILE: Token that requests to the admin
+//   API have to present as `Authorization: Bearer <token>`. Without one, the admin API is
+//   disabled.
+pub struct HttpConfig {
+    addr: ListenAddr,
+    admin_token: Option<String>,
+}
+
+enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+struct Server {
+    state: PCState,
+    health: HealthCheck,
+    admin_token: Option<String>,
+}
+
+impl HttpConfig {
+    pub fn from_env() -> Result<Option<HttpConfig>, String> {
+        let addr = match env::var("PROBLEM_CHILD_HTTP_ADDR") {
+            Ok(addr) => addr,
+            Err(_) => return Ok(None),
+        };
+
+        let addr = match addr.strip_prefix("unix:") {
+            Some(path) => ListenAddr::Unix(PathBuf::from(path)),
+            None => ListenAddr::Tcp(
+                addr.parse()
+                    .map_err(|e| format!("Invalid PROBLEM_CHILD_HTTP_ADDR {:?}: {}", addr, e))?,
+            ),
+        };
+
+        let admin_token = if let Ok(path) = env::var("PROBLEM_CHILD_ADMIN_TOKEN_FILE") {
+            let token = std::fs::read_to_string(path).map_err(|e| {
+                format!(
+                    "PROBLEM_CHILD_ADMIN_TOKEN_FILE specified, but failed to read file: {:?}",
+                    e
+                )
+            })?;
+            Some(token.trim().to_string())
+        } else {
+            env::var("PROBLEM_CHILD_ADMIN_TOKEN").ok()
+        };
+
+        if admin_token.as_deref() == Some("") {
+            return Err("The admin API token must not be empty".to_string());
+        }
+
+        Ok(Some(HttpConfig { addr, admin_token }))
+    }
+}
+
+pub async fn serve(config: HttpConfig, state: PCState, health: HealthCheck) {
+    if config.admin_token.is_none() {
+        warn!("No admin API token configured, the admin API is disabled.");
+    }
+
+    let server = Arc::new(Server {
+        state,
+        health,
+        admin_token: config.admin_token,
+    });
+
+    let result = match config.addr {
+        ListenAddr::Tcp(addr) => serve_tcp(addr, server).await,
+        ListenAddr::Unix(path) => serve_unix(path, server).await,
+    };
+
+    if let Err(err) = result {
+        error!("Error running HTTP server: {:?}", err);
+    }
+}
+
+async fn serve_tcp(addr: SocketAddr, server: Arc<Server>) -> std::io::Result<()> {
+    if !addr.ip().is_loopback() {
+        warn!(
+            "HTTP server is listening on non-loopback address {}, make sure this is intended!",
+            addr
+        );
+    }
+
+    let listener = TcpListener::bind(addr).await?;
+    info!("HTTP server listening on {}", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        serve_connection(stream, server.clone());
+    }
+}
+
+async fn serve_unix(path: PathBuf, server: Arc<Server>) -> std::io::Result<()> {
+    // A socket left over from a previous run would make binding fail.
+    match std::fs::remove_file(&path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => (),
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    info!("HTTP server listening on {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        serve_connection(stream, server.clone());
+    }
+}
+
+fn serve_connection<S>(stream: S, server: Arc<Server>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let service = service_fn(move |req| {
+            let server = server.clone();
+            async move { Ok::<_, Infallible>(server.handle(req).await) }
+        });
+
+        if let Err(err) = Http::new()
+            .http1_only(true)
+            .serve_connection(stream, service)
+            .await
+        {
+            debug!("Error serving HTTP connection: {:?}", err);
+        }
+    });
+}
+
+impl Server {
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let path = req.uri().path().to_string();
+        let segments = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+
+        debug!("[http] {} {}", req.method(), path);
+
+        match segments.as_slice() {
+            ["api", rest @ ..] => {
+                if let Err((status, message)) = self.authorize(&req) {
+                    return error_response(status, message);
+                }
+                admin_api::handle(req, rest, &self.state).await
+            }
+            ["metrics"] => Response::builder()
+                .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(metrics::render(&self.state)))
+                .unwrap(),
+            ["health"] => {
+                let report = self.health.report().await;
+                json_response(health_status(report.is_healthy()), &report.to_json())
+            }
+            ["ready"] => {
+                let report = self.health.report().await;
+                json_response(health_status(report.is_ready()), &report.to_json())
+            }
+            _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    fn authorize(&self, req: &Request<Body>) -> Result<(), (StatusCode, &'static str)> {
+        let expected = match &self.admin_token {
+            Some(token) => token,
+            None => return Err((StatusCode::FORBIDDEN, "The admin API is disabled")),
+        };
+
+        let provided = req
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "));
+
+        match provided {
+            Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+            _ => Err((StatusCode::UNAUTHORIZED, "Missing or invalid token")),
+        }
+    }
+}
+
+// Compares without short-circuiting, so the time taken doesn't reveal how much of a guessed
+// token was correct.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn health_status(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+pub fn json_response(status: StatusCode, body: &serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+pub fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
@@ -0,0 +1,310 @@
+use crate::model::PCData;
+use crate::state::PCState;
+
+use log::{info, warn};
+use serenity::{
+    cache::Cache,
+    model::{
+        channel::ChannelType,
+        id::{ChannelId, GuildId, UserId},
+    },
+    prelude::Context,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long a user has to wait after a channel was created for them before another one is, so that
+// hopping in and out of a lobby can't flood the guild with channels.
+const ROOM_COOLDOWN: Duration = Duration::from_secs(30);
+
+// When a channel was last created for each user, by guild.
+#[derive(Default)]
+pub struct RoomCooldowns {
+    last_created: Mutex<HashMap<(GuildId, UserId), Instant>>,
+}
+
+impl RoomCooldowns {
+    // Returns whether a channel may be created for the user now, and if so, starts their cooldown.
+    fn try_start(&self, guild_id: GuildId, user_id: UserId) -> bool {
+        let now = Instant::now();
+        let mut last_created = self.last_created.lock().unwrap();
+        last_created.retain(|_, at| now.duration_since(*at) < ROOM_COOLDOWN);
+
+        if last_created.contains_key(&(guild_id, user_id)) {
+            return false;
+        }
+        last_created.insert((guild_id, user_id), now);
+        true
+    }
+}
+
+// Gives users that join a lobby their own temporary voice channel, and deletes the temporary
+// channel they left if nobody is in it anymore.
+pub async fn voice_channel_changed(
+    ctx: &Context,
+    state: &PCState,
+    cooldowns: &RoomCooldowns,
+    guild_id: GuildId,
+    user_id: UserId,
+    old: Option<ChannelId>,
+    new: Option<ChannelId>,
+) {
+    // Muting, deafening etc. also cause voice state updates.
+    if old == new {
+        return;
+    }
+
+    // The lobby comes first, since going there from your own channel moves you right back, and
+    // the channel mustn't be deleted in between.
+    let mut moved_to = None;
+    if let Some(new) = new {
+        if state.read(|d| d.is_lobby_channel(guild_id, new)) {
+            moved_to = join_room(ctx, state, cooldowns, guild_id, new, user_id).await;
+        }
+    }
+
+    if let Some(old) = old.filter(|&old| Some(old) != moved_to) {
+        delete_if_empty(ctx, state, guild_id, old).await;
+    }
+}
+
+// Deletes temporary voice channels that were left empty while the bot wasn't running. Must only be
+// called once the cache is ready.
+pub async fn clean_up(ctx: &Context, state: &PCState) {
+    for (guild_id, channel_id) in state.read(temp_channels) {
+        delete_if_empty(ctx, state, guild_id, channel_id).await;
+    }
+}
+
+fn temp_channels(data: &PCData) -> Vec<(GuildId, ChannelId)> {
+    data.guilds()
+        .flat_map(|g| g.temp_channels().map(move |(id, _)| (g.id, id)))
+        .collect()
+}
+
+// Moves the user to their own temporary channel, creating one unless they still have one or are
+// on cooldown. Returns the channel they were moved to.
+async fn join_room(
+    ctx: &Context,
+    state: &PCState,
+    cooldowns: &RoomCooldowns,
+    guild_id: GuildId,
+    lobby_id: ChannelId,
+    user_id: UserId,
+) -> Option<ChannelId> {
+    let existing = state
+        .read(|d| d.owned_temp_channel(guild_id, user_id))
+        .filter(|&c| ctx.cache.guild_channel(c).is_some());
+    if let Some(room_id) = existing {
+        return match guild_id.move_member(&ctx.http, user_id, room_id).await {
+            Ok(_) => {
+                info!(
+                    guild = guild_id.0, channel = room_id.0, user = user_id.0;
+                    "[lobby] Moved {} back to their channel {}",
+                    user_id, room_id
+                );
+                Some(room_id)
+            }
+            Err(err) => {
+                warn!(
+                    guild = guild_id.0, channel = room_id.0, user = user_id.0;
+                    "Error moving {} back to their channel {}: {:?}",
+                    user_id, room_id, err
+                );
+                None
+            }
+        };
+    }
+
+    if !cooldowns.try_start(guild_id, user_id) {
+        info!(
+            guild = guild_id.0, channel = lobby_id.0, user = user_id.0;
+            "[lobby] Not creating another channel for {} from lobby {} yet",
+            user_id, lobby_id
+        );
+        return None;
+    }
+
+    create_room(ctx, state, guild_id, lobby_id, user_id).await
+}
+
+// Creates a voice channel named after the user in the lobby's category and moves them there. The
+// channel is tracked before moving them, so that it gets cleaned up even if that fails.
+async fn create_room(
+    ctx: &Context,
+    state: &PCState,
+    guild_id: GuildId,
+    lobby_id: ChannelId,
+    user_id: UserId,
+) -> Option<ChannelId> {
+    let lobby = match ctx.cache.guild_channel(lobby_id) {
+        Some(c) => c,
+        None => {
+            warn!(
+                guild = guild_id.0, channel = lobby_id.0;
+                "Lobby {} not found in cache, not creating a channel for {}",
+                lobby_id, user_id
+            );
+            return None;
+        }
+    };
+
+    let owner_name = match ctx.cache.member(guild_id, user_id) {
+        Some(member) => member.display_name().into_owned(),
+        None => match user_id.to_user(ctx).await {
+            Ok(user) => user.name,
+            Err(_) => user_id.to_string(),
+        },
+    };
+
+    let result = guild_id
+        .create_channel(&ctx.http, |c| {
+            c.name(format!("{}'s Room", owner_name))
+                .kind(ChannelType::Voice);
+            if let Some(category_id) = lobby.parent_id {
+                c.category(category_id);
+            }
+            if let Some(bitrate) = lobby.bitrate {
+                c.bitrate(bitrate as u32);
+            }
+            c
+        })
+        .await;
+
+    let room = match result {
+        Ok(c) => c,
+        Err(err) => {
+            warn!(
+                guild = guild_id.0, channel = lobby_id.0, user = user_id.0;
+                "Error creating a channel for {} from lobby {}: {:?}",
+                user_id, lobby_id, err
+            );
+            return None;
+        }
+    };
+
+    state.update(|d| d.add_temp_channel(guild_id, room.id, lobby_id, user_id));
+    info!(
+        guild = guild_id.0, channel = room.id.0, user = user_id.0;
+        "[lobby] Created {} ({}) for {} from lobby {}",
+        room.name, room.id, user_id, lobby_id
+    );
+
+    if let Err(err) = guild_id.move_member(&ctx.http, user_id, room.id).await {
+        // Most likely they left the lobby again already.
+        warn!(
+            guild = guild_id.0, channel = room.id.0, user = user_id.0;
+            "Error moving {} to their new channel {}: {:?}",
+            user_id, room.id, err
+        );
+        delete_if_empty(ctx, state, guild_id, room.id).await;
+        return None;
+    }
+
+    Some(room.id)
+}
+
+// Only deletes temporary channels.
+async fn delete_if_empty(ctx: &Context, state: &PCState, guild_id: GuildId, channel_id: ChannelId) {
+    if state
+        .read(|d| d.temp_channel_lobby(guild_id, channel_id))
+        .is_none()
+    {
+        return;
+    }
+
+    if !is_empty(&ctx.cache, guild_id, channel_id) {
+        return;
+    }
+
+    match channel_id.delete(&ctx.http).await {
+        Ok(_) => {
+            info!(
+                guild = guild_id.0, channel = channel_id.0;
+                "[lobby] Deleted empty channel {}",
+                channel_id
+            );
+            state.update(|d| d.remove_temp_channel(guild_id, channel_id));
+        }
+        // It stays tracked, so deleting it is tried again when someone leaves it or the bot
+        // restarts.
+        Err(err) => warn!(
+            guild = guild_id.0, channel = channel_id.0;
+            "Error deleting empty channel {}: {:?}",
+            channel_id, err
+        ),
+    }
+}
+
+// If the guild isn't cached, it's unknown whether the channel is empty, so it doesn't count as
+// such.
+fn is_empty(cache: &Cache, guild_id: GuildId, channel_id: ChannelId) -> bool {
+    cache
+        .guild_field(guild_id, |g| {
+            !g.voice_states
+                .values()
+                .any(|vs| vs.channel_id == Some(channel_id))
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cooldowns_are_per_user_and_guild() {
+        let cooldowns = RoomCooldowns::default();
+
+        assert!(cooldowns.try_start(GuildId(10), UserId(1)));
+        assert!(!cooldowns.try_start(GuildId(10), UserId(1)));
+        assert!(cooldowns.try_start(GuildId(10), UserId(2)));
+        assert!(cooldowns.try_start(GuildId(20), UserId(1)));
+    }
+
+    #[test]
+    fn cooldowns_expire() {
+        let cooldowns = RoomCooldowns::default();
+        let expired = Instant::now() - ROOM_COOLDOWN;
+        cooldowns
+            .last_created
+            .lock()
+            .unwrap()
+            .insert((GuildId(10), UserId(1)), expired);
+
+        assert!(cooldowns.try_start(GuildId(10), UserId(1)));
+        assert!(!cooldowns.try_start(GuildId(10), UserId(1)));
+    }
+
+    #[test]
+    fn channels_from_before_a_restart_are_cleaned_up() {
+        let json = r#"{"guilds": [
+            {"id": 10, "admins": [], "afk_channels": [], "notif_channels": [],
+                "lobby_channels": [104], "temp_channels": [{"id": 105, "lobby": 104, "owner": 7},
+                    {"id": 106, "lobby": 104}]},
+            {"id": 20, "admins": [], "afk_channels": [], "notif_channels": [],
+                "lobby_channels": [204], "temp_channels": [{"id": 205, "lobby": 204}]}]}"#;
+        let data: PCData = serde_json::from_str(json).unwrap();
+
+        let mut channels = temp_channels(&data);
+        channels.sort_unstable();
+        assert_eq!(
+            channels,
+            [
+                (GuildId(10), ChannelId(105)),
+                (GuildId(10), ChannelId(106)),
+                (GuildId(20), ChannelId(205))
+            ]
+        );
+        assert_eq!(
+            data.owned_temp_channel(GuildId(10), UserId(7)),
+            Some(ChannelId(105))
+        );
+    }
+
+    #[test]
+    fn channels_in_uncached_guilds_are_left_alone() {
+        assert!(!is_empty(&Cache::new(), GuildId(10), ChannelId(105)));
+    }
+}
@@ -0,0 +1,81 @@
+use env_logger::{fmt::Formatter, Env};
+use log::kv::{self, VisitSource};
+use log::Record;
+use serde_json::{Map, Value};
+use std::env;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// Logging is configured from the environment:
+// - RUST_LOG: The usual env_logger filter, defaults to `warn`.
+// - PROBLEM_CHILD_LOG_FORMAT: `text` (the default) or `json`, which writes one JSON object per
+//   line with the structured fields (guild, channel, user, command, event_id, ...) as keys.
+// - PROBLEM_CHILD_LOG_MESSAGE_CONTENT: If set to `1`, DMs sent to the bot are logged verbatim.
+//   Otherwise only the command is logged.
+
+static LOG_MESSAGE_CONTENT: AtomicBool = AtomicBool::new(false);
+static NEXT_EVENT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Always installs a logger. If the configuration is invalid, it falls back to the text format and
+// returns an error after doing so, so that the error can be logged.
+pub fn init() -> Result<(), String> {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("warn"));
+
+    LOG_MESSAGE_CONTENT.store(
+        env::var("PROBLEM_CHILD_LOG_MESSAGE_CONTENT").as_deref() == Ok("1"),
+        Ordering::Relaxed,
+    );
+
+    let result = match env::var("PROBLEM_CHILD_LOG_FORMAT").as_deref() {
+        Err(_) | Ok("text") => Ok(()),
+        Ok("json") => {
+            builder.format(format_json);
+            Ok(())
+        }
+        Ok(other) => Err(format!("Invalid PROBLEM_CHILD_LOG_FORMAT {:?}", other)),
+    };
+
+    builder.init();
+    result
+}
+
+// Returns a new ID to tag all log lines caused by a single Discord event with, so they can be
+// correlated.
+pub fn next_event_id() -> u64 {
+    NEXT_EVENT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// Returns the content of a DM in the form it may be logged in.
+pub fn loggable_content(content: &str) -> String {
+    if LOG_MESSAGE_CONTENT.load(Ordering::Relaxed) {
+        return content.to_string();
+    }
+
+    match content.split_whitespace().next() {
+        Some(command) if command.starts_with('!') => format!("{} <redacted>", command),
+        _ => "<redacted>".to_string(),
+    }
+}
+
+fn format_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let mut fields = Map::new();
+    fields.insert("timestamp".to_string(), buf.timestamp().to_string().into());
+    fields.insert("level".to_string(), record.level().as_str().into());
+    fields.insert("target".to_string(), record.target().into());
+    fields.insert("message".to_string(), record.args().to_string().into());
+
+    // All values are written as strings, since Discord IDs don't fit into a JSON number without
+    // losing precision in most consumers.
+    let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+
+    writeln!(buf, "{}", Value::Object(fields))
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), value.to_string().into());
+        Ok(())
+    }
+}
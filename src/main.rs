@@ -1,28 +1,85 @@
+mod admin_api;
+mod afk;
 mod commands;
+mod digest;
+mod games;
+mod gc;
+mod health;
+mod history;
+mod http;
+mod lobbies;
+mod logging;
+mod metrics;
 mod model;
+mod outbox;
+mod sessions;
+mod spoilers;
+mod state;
 mod storage;
+mod suppression;
+mod voice;
 
-use env_logger::Env;
-use log::{error, info};
-use serenity::{client::Client, model::gateway::GatewayIntents};
+use log::{error, info, warn};
+use serenity::{
+    client::Client, gateway::GatewayError, http::StatusCode, model::gateway::GatewayIntents,
+};
 use std::env;
+use std::path::Path;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+
+// Distinct exit codes, so that whatever supervises the bot can tell failures that a restart won't
+// fix apart from ones it might.
+const EXIT_RUNTIME_ERROR: i32 = 1;
+const EXIT_CONFIG_ERROR: i32 = 2;
+const EXIT_AUTH_ERROR: i32 = 3;
+// The gateway didn't disconnect within `SHUTDOWN_TIMEOUT` after a shutdown signal.
+const EXIT_SHUTDOWN_TIMEOUT: i32 = 4;
+
+// How long to wait for the gateway to disconnect after a shutdown signal.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+    if let Err(err) = logging::init() {
+        error!("{}", err);
+        process::exit(EXIT_CONFIG_ERROR);
+    }
 
     info!("Starting up...");
 
     let token = get_token();
 
-    let pc_data = storage::load_data().unwrap_or_else(|err| {
+    let config_dir = Path::new(storage::CONFIG_DIR);
+
+    let pc_data = storage::load_data(config_dir).unwrap_or_else(|err| {
         error!("Error loading config/pc_data.json file: {:?}", err);
-        process::exit(1)
+        process::exit(EXIT_CONFIG_ERROR)
+    });
+
+    let sessions = storage::load_sessions(config_dir).unwrap_or_else(|err| {
+        error!("Error loading config/voice_sessions.json file: {:?}", err);
+        process::exit(EXIT_CONFIG_ERROR)
+    });
+
+    let runtime = storage::load_runtime(config_dir).unwrap_or_else(|err| {
+        error!("Error loading config/runtime_data.json file: {:?}", err);
+        process::exit(EXIT_CONFIG_ERROR)
     });
 
     info!("Loaded subscription information!");
 
+    let http_config = http::HttpConfig::from_env().unwrap_or_else(|err| {
+        error!("{}", err);
+        process::exit(EXIT_CONFIG_ERROR)
+    });
+
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let gateway_events = Arc::new(health::GatewayEvents::default());
+
     let mut client = Client::builder(
         &token,
         GatewayIntents::GUILDS
@@ -33,17 +90,108 @@ async fn main() {
             | GatewayIntents::DIRECT_MESSAGES
             | GatewayIntents::MESSAGE_CONTENT,
     )
-    .event_handler(commands::Handler)
+    .event_handler(commands::Handler::new(
+        shutting_down.clone(),
+        gateway_events.clone(),
+    ))
+    .raw_event_handler(health::EventRecorder(gateway_events.clone()))
     .await
-    .expect("Error creating client");
+    .unwrap_or_else(|err| {
+        error!("Error creating client: {:?}", err);
+        process::exit(EXIT_CONFIG_ERROR)
+    });
+
+    let state = state::PCState::new(config_dir.to_path_buf(), pc_data, sessions, runtime);
+    tokio::spawn(state.clone().run_persister());
+    tokio::spawn(state.clone().run_file_watcher());
+    tokio::spawn(reload_on_sighup(state.clone()));
+
+    let health_check = health::HealthCheck::new(gateway_events, client.shard_manager.clone());
+    tokio::spawn(health::run_heartbeat_watcher(health_check.clone()));
+    tokio::spawn(health::run_systemd_notifier(health_check.clone()));
+
+    if let Some(http_config) = http_config {
+        tokio::spawn(http::serve(http_config, state.clone(), health_check));
+    }
 
     {
         let mut data = client.data.write().await;
-        data.insert::<commands::DataKey>(pc_data);
+        data.insert::<commands::DataKey>(state.clone());
+        data.insert::<commands::OutboxKey>(outbox::Outbox::new(
+            client.cache_and_http.clone(),
+            state.clone(),
+        ));
     }
 
-    if let Err(err) = client.start().await {
-        error!("An error occured while running the client: {:?}", err);
+    tokio::spawn(digest::run_scheduler(
+        client.cache_and_http.clone(),
+        state.clone(),
+    ));
+    tokio::spawn(afk::run_idle_mover(
+        client.cache_and_http.clone(),
+        state.clone(),
+    ));
+
+    let shard_manager = client.shard_manager.clone();
+    let shutdown_state = state.clone();
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        info!("Received shutdown signal, shutting down...");
+
+        // Stop handling new commands right away, then disconnect from the gateway, which makes
+        // `client.start()` below return.
+        shutting_down.store(true, Ordering::SeqCst);
+        shard_manager.lock().await.shutdown_all().await;
+
+        // While a shard is still trying to (re)connect, shutting down the shard manager doesn't
+        // always make `client.start()` return, so don't wait for it forever.
+        tokio::time::sleep(SHUTDOWN_TIMEOUT).await;
+        warn!("Gateway did not shut down in time, exiting anyway.");
+        shutdown_state.flush().await;
+        process::exit(EXIT_SHUTDOWN_TIMEOUT);
+    });
+
+    let exit_code = match client.start().await {
+        Ok(()) => 0,
+        Err(err) if is_auth_error(&err) => {
+            error!("Failed to authenticate with Discord: {:?}", err);
+            EXIT_AUTH_ERROR
+        }
+        Err(err) => {
+            error!("An error occured while running the client: {:?}", err);
+            EXIT_RUNTIME_ERROR
+        }
+    };
+
+    info!("Saving data before exiting...");
+    state.flush().await;
+
+    process::exit(exit_code);
+}
+
+fn is_auth_error(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Gateway(GatewayError::InvalidAuthentication) => true,
+        serenity::Error::Http(err) => err.status_code() == Some(StatusCode::UNAUTHORIZED),
+        _ => false,
+    }
+}
+
+async fn reload_on_sighup(state: state::PCState) {
+    let mut sighup = signal(SignalKind::hangup()).expect("Error installing SIGHUP handler");
+
+    while sighup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading pc_data.json...");
+        state.reload(true).await;
+    }
+}
+
+async fn wait_for_shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Error installing SIGTERM handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = sigterm.recv() => (),
     }
 }
 
@@ -54,12 +202,12 @@ fn get_token() -> String {
                 "PROBLEM_CHILD_TOKEN_FILE specified, but failed to read file: {:?}",
                 err
             );
-            process::exit(1);
+            process::exit(EXIT_CONFIG_ERROR);
         })
     } else if let Ok(token) = env::var("PROBLEM_CHILD_TOKEN") {
         token
     } else {
         error!("Couldn't get a token from PROBLEM_CHILD_TOKEN or PROBLEM_CHILD_TOKEN_FILE");
-        process::exit(1);
+        process::exit(EXIT_CONFIG_ERROR);
     }
 }
@@ -0,0 +1,228 @@
+use crate::state::PCState;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Minimal Prometheus metrics, rendered in the text exposition format by `render`.
+
+pub static VOICE_STATE_UPDATES: Counter = Counter::new(
+    "problem_child_voice_state_updates_total",
+    "Voice state updates received from Discord.",
+);
+
+pub static JOINS_DETECTED: Counter = Counter::new(
+    "problem_child_joins_detected_total",
+    "Voice state updates that counted as someone joining a channel.",
+);
+
+pub static NOTIFICATIONS_SENT: Counter = Counter::new(
+    "problem_child_notifications_sent_total",
+    "Join notifications successfully sent to subscribers.",
+);
+
+pub static NOTIFICATIONS_SKIPPED: LabeledCounter<1> = LabeledCounter::new(
+    "problem_child_notifications_skipped_total",
+    "Subscribers that were not notified about a join, by reason.",
+    ["reason"],
+);
+
+pub static COMMANDS: LabeledCounter<2> = LabeledCounter::new(
+    "problem_child_commands_total",
+    "DM commands handled, by command and outcome.",
+    ["command", "outcome"],
+);
+
+pub static EMBED_SUPPRESSIONS: LabeledCounter<1> = LabeledCounter::new(
+    "problem_child_embed_suppressions_total",
+    "Attempts to suppress embeds on messages, by outcome.",
+    ["outcome"],
+);
+
+pub static SPOILER_REPOSTS: LabeledCounter<1> = LabeledCounter::new(
+    "problem_child_spoiler_reposts_total",
+    "Attempts to repost daily puzzle results with spoilers, by outcome.",
+    ["outcome"],
+);
+
+pub static GAME_RESULTS: LabeledCounter<1> = LabeledCounter::new(
+    "problem_child_game_results_total",
+    "Daily puzzle results recorded, by game.",
+    ["game"],
+);
+
+pub static IDLE_MOVES: LabeledCounter<1> = LabeledCounter::new(
+    "problem_child_idle_moves_total",
+    "Attempts to move deafened members to an AFK channel, by outcome.",
+    ["outcome"],
+);
+
+pub static STORAGE_SAVE_DURATION: Histogram = Histogram::new(
+    "problem_child_storage_save_duration_seconds",
+    "Time taken to write pc_data.json.",
+);
+
+pub static STORAGE_SAVE_FAILURES: Counter = Counter::new(
+    "problem_child_storage_save_failures_total",
+    "Failed attempts to write pc_data.json.",
+);
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Counter {
+        Counter {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, "counter");
+        writeln!(out, "{} {}", self.name, self.value.load(Ordering::Relaxed)).unwrap();
+    }
+}
+
+pub struct LabeledCounter<const N: usize> {
+    name: &'static str,
+    help: &'static str,
+    labels: [&'static str; N],
+    values: Mutex<BTreeMap<[&'static str; N], u64>>,
+}
+
+impl<const N: usize> LabeledCounter<N> {
+    const fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: [&'static str; N],
+    ) -> LabeledCounter<N> {
+        LabeledCounter {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, label_values: [&'static str; N]) {
+        *self.values.lock().unwrap().entry(label_values).or_insert(0) += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, "counter");
+        for (label_values, value) in self.values.lock().unwrap().iter() {
+            let labels = self
+                .labels
+                .iter()
+                .zip(label_values)
+                .map(|(name, value)| format!("{}=\"{}\"", name, value))
+                .collect::<Vec<_>>()
+                .join(",");
+            writeln!(out, "{}{{{}}} {}", self.name, labels, value).unwrap();
+        }
+    }
+}
+
+const HISTOGRAM_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    // Non-cumulative counts per bucket, plus one for observations above the largest bucket.
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new(name: &'static str, help: &'static str) -> Histogram {
+        Histogram {
+            name,
+            help,
+            buckets: [const { AtomicU64::new(0) }; HISTOGRAM_BUCKETS.len() + 1],
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = HISTOGRAM_BUCKETS
+            .iter()
+            .position(|&le| seconds <= le)
+            .unwrap_or(HISTOGRAM_BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        write_header(out, self.name, self.help, "histogram");
+
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = HISTOGRAM_BUCKETS
+                .get(i)
+                .map(|le| le.to_string())
+                .unwrap_or_else(|| "+Inf".to_string());
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", self.name, le, cumulative).unwrap();
+        }
+
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        writeln!(out, "{}_sum {}", self.name, sum).unwrap();
+        writeln!(out, "{}_count {}", self.name, cumulative).unwrap();
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn render_gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    write_header(out, name, help, "gauge");
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+pub fn render(state: &PCState) -> String {
+    let mut out = String::new();
+
+    VOICE_STATE_UPDATES.render(&mut out);
+    JOINS_DETECTED.render(&mut out);
+    NOTIFICATIONS_SENT.render(&mut out);
+    NOTIFICATIONS_SKIPPED.render(&mut out);
+    COMMANDS.render(&mut out);
+    EMBED_SUPPRESSIONS.render(&mut out);
+    SPOILER_REPOSTS.render(&mut out);
+    GAME_RESULTS.render(&mut out);
+    IDLE_MOVES.render(&mut out);
+    STORAGE_SAVE_DURATION.render(&mut out);
+    STORAGE_SAVE_FAILURES.render(&mut out);
+
+    let (guilds, subscriptions) = state.read(|d| (d.guilds().count(), d.subscription_count()));
+    render_gauge(
+        &mut out,
+        "problem_child_guilds",
+        "Guilds with stored settings.",
+        guilds,
+    );
+    render_gauge(
+        &mut out,
+        "problem_child_subscriptions",
+        "Subscriptions to voice channel join notifications.",
+        subscriptions,
+    );
+
+    out
+}
@@ -1,70 +1,250 @@
+use crate::afk::IdleMoveExemption;
+use crate::games::{GameResult, GameScores};
+use crate::spoilers::SpoilerRule;
+use crate::suppression::{self, SuppressionMode, SuppressionRule};
+
 use serde::{Deserialize, Serialize};
-use serenity::model::id::{ChannelId, GuildId, UserId};
+use serenity::model::id::{ChannelId, GuildId, RoleId, UserId};
+
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Serialize, Deserialize, Debug)]
+// In memory, everything is indexed by serenity's typed IDs. On disk, `PCData` is stored using
+// the types in the `stored` module below, which keeps the JSON format stable (and files written
+// by older versions loadable) regardless of how the in-memory representation changes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "stored::PCData", into = "stored::PCData")]
 pub struct PCData {
-    pub guilds: Vec<PCGuild>,
+    guilds: HashMap<GuildId, PCGuild>,
+    // Reverse index of `PCNotifChannel::subscribed_users`, kept in sync by `add_subscription`
+    // and `remove_subscription` and rebuilt when loading.
+    subscriptions_by_user: HashMap<UserId, HashSet<(GuildId, ChannelId)>>,
+    // Users that don't want their time in voice channels recorded.
+    voice_tracking_opt_outs: HashSet<UserId>,
+    // Users that get weekly digests.
+    digest_subscribers: HashSet<UserId>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug, Clone)]
 pub struct PCGuild {
-    pub id: u64,
-    admins: Vec<AdminUser>,
-    afk_channels: Vec<u64>,
-    notif_channels: Vec<PCNotifChannel>,
+    pub id: GuildId,
+    admins: HashMap<UserId, AdminUser>,
+    afk_channels: HashSet<ChannelId>,
+    // The AFK channel set in the guild's own settings, kept up to date from the cache. It counts
+    // as an AFK channel too, unless the guild's admins chose to ignore it.
+    discord_afk_channel: Option<ChannelId>,
+    ignore_discord_afk_channel: bool,
+    // Whether the guild's admins were sent suggestions for channels that look like AFK channels.
+    afk_suggestions_offered: bool,
+    notif_channels: HashMap<ChannelId, PCNotifChannel>,
+    // "Join to create" voice channels: whoever joins one gets their own temporary voice channel.
+    lobby_channels: HashSet<ChannelId>,
+    // The temporary voice channels created that way. They are deleted once they are empty.
+    temp_channels: HashMap<ChannelId, TempChannel>,
+    // Text channel to tell users about it when their notifications get paused because they
+    // can't be sent DMs.
+    fallback_channel: Option<ChannelId>,
+    // `None` until the guild's admins change the rules, in which case the default rules apply.
+    suppression_rules: Option<Vec<SuppressionRule>>,
+    suppression_mode: SuppressionMode,
+    spoiler_rules: Vec<SpoilerRule>,
+    // Members that stay deafened for this many seconds get moved to an AFK channel. `None` if
+    // that's turned off.
+    idle_move_after: Option<u64>,
+    idle_move_exemptions: HashSet<IdleMoveExemption>,
+}
+
+// Who was in which voice channel when, by guild. Sessions change with every join, leave and move,
+// so unlike `PCData` they are stored in a file of their own, which isn't meant to be edited and
+// isn't reloaded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(from = "stored::VoiceSessions", into = "stored::VoiceSessions")]
+pub struct VoiceSessions {
+    guilds: HashMap<GuildId, GuildVoiceSessions>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct GuildVoiceSessions {
+    // Finished sessions in the order they ended in, and the channel and start time of the sessions
+    // still going on.
+    finished: Vec<VoiceSession>,
+    open: HashMap<UserId, (ChannelId, u64)>,
+}
+
+// What the bot records as it goes along: puzzle results, idle moves, failed DMs and when digests
+// were sent. For the same reason as `VoiceSessions`, this is kept in a file of its own that isn't
+// reloaded, so that someone editing config/pc_data.json doesn't run into a conflict with every
+// result posted in the meantime.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(from = "stored::RuntimeData", into = "stored::RuntimeData")]
+pub struct RuntimeData {
+    guilds: HashMap<GuildId, GuildRuntimeData>,
+    // Users that DMs recently couldn't be delivered to.
+    delivery_failures: HashMap<UserId, DeliveryFailures>,
+    // Unix timestamp of when each digest subscriber was last sent a digest.
+    digests_sent: HashMap<UserId, u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct GuildRuntimeData {
+    // Recorded daily puzzle results, by game name and user.
+    game_results: HashMap<String, HashMap<UserId, GameScores>>,
+    // The most recent idle moves, oldest first.
+    idle_moves: Vec<IdleMove>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoiceSession {
+    pub user: UserId,
+    pub channel: ChannelId,
+    // Unix timestamps.
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TempChannel {
+    // The lobby the channel was created from.
+    pub lobby: ChannelId,
+    // Who the channel was created for. `None` for channels created before owners were recorded,
+    // or if the owner left the guild.
+    pub owner: Option<UserId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleMove {
+    pub user: UserId,
+    pub from: ChannelId,
+    pub to: ChannelId,
+    // Unix timestamp.
+    pub at: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug, Clone)]
 struct AdminUser {
-    id: u64,
     send_notif_copies: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug, Clone)]
 pub struct PCNotifChannel {
-    pub id: u64,
-    pub subscribed_users: Vec<u64>,
+    pub id: ChannelId,
+    pub subscribed_users: HashSet<UserId>,
+}
+
+// After this many consecutive failed DMs, a user's notifications are paused until a DM to them
+// succeeds again.
+pub const PAUSE_AFTER_FAILURES: u32 = 5;
+
+// After a failed DM, no notifications are sent to the user for this long, doubling with every
+// further consecutive failure.
+const BACKOFF_BASE_SECS: u64 = 5 * 60;
+
+// Finished voice sessions are forgotten after this long.
+const VOICE_SESSION_RETENTION_SECS: u64 = 90 * 24 * 60 * 60;
+
+// Puzzle results are forgotten once a user has posted one for a puzzle this many days newer.
+// Puzzle numbers count days, so this is about a year of results.
+const GAME_RESULT_RETENTION_DAYS: u32 = 366;
+
+// How many idle moves are kept per guild.
+const IDLE_MOVE_LOG_LENGTH: usize = 50;
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryFailures {
+    pub consecutive: u32,
+    // Unix timestamp of the most recent failure.
+    pub last_failure: u64,
+    pub paused: bool,
+}
+
+impl DeliveryFailures {
+    // Whether enough time has passed since the last failure to try sending a notification again.
+    pub fn may_retry(&self, now: u64) -> bool {
+        let backoff = BACKOFF_BASE_SECS << self.consecutive.saturating_sub(1).min(10);
+        !self.paused && now >= self.last_failure + backoff
+    }
 }
 
 impl PCData {
     pub fn default() -> PCData {
-        PCData { guilds: vec![] }
+        PCData {
+            guilds: HashMap::new(),
+            subscriptions_by_user: HashMap::new(),
+            voice_tracking_opt_outs: HashSet::new(),
+            digest_subscribers: HashSet::new(),
+        }
     }
 
-    pub fn find_subscribed_users<'a>(
-        &'a self,
+    pub fn guilds(&self) -> impl Iterator<Item = &PCGuild> {
+        self.guilds.values()
+    }
+
+    pub fn find_guild(&self, guild_id: GuildId) -> Option<&PCGuild> {
+        self.guilds.get(&guild_id)
+    }
+
+    pub fn find_subscribed_users(
+        &self,
         guild_id: GuildId,
         channel_id: ChannelId,
-    ) -> Option<impl Iterator<Item = UserId> + 'a> {
+    ) -> Option<impl Iterator<Item = UserId> + '_> {
         self.guilds
-            .iter()
-            .find(|g| g.id == guild_id.0)
-            .and_then(|guild| guild.notif_channels.iter().find(|c| c.id == channel_id.0))
-            .map(|channel| channel.subscribed_users.iter().map(|id| UserId::from(*id)))
+            .get(&guild_id)
+            .and_then(|guild| guild.notif_channels.get(&channel_id))
+            .map(|channel| channel.subscribed_users.iter().copied())
+    }
+
+    pub fn find_subscriptions(
+        &self,
+        user_id: UserId,
+    ) -> impl Iterator<Item = (GuildId, ChannelId)> + '_ {
+        self.subscriptions_by_user
+            .get(&user_id)
+            .into_iter()
+            .flat_map(|subscriptions| subscriptions.iter().copied())
+    }
+
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions_by_user.values().map(|s| s.len()).sum()
     }
 
     pub fn is_afk_channel(&self, guild_id: GuildId, channel_id: ChannelId) -> bool {
         self.guilds
-            .iter()
-            .find(|g| g.id == guild_id.0)
-            .map(|guild| guild.afk_channels.iter().any(|&c| c == channel_id.0))
+            .get(&guild_id)
+            .map(|guild| {
+                guild.afk_channels.contains(&channel_id)
+                    || (!guild.ignore_discord_afk_channel
+                        && guild.discord_afk_channel == Some(channel_id))
+            })
             .unwrap_or(false)
     }
 
-    pub fn add_subscription(&mut self, user_id: UserId, guild_id: GuildId, channel_id: ChannelId) {
-        let guild = Self::find_or_insert(
-            &mut self.guilds,
-            |g| g.id == guild_id.0,
-            PCGuild::new(guild_id),
-        );
-
-        let notif_channel = Self::find_or_insert(
-            &mut guild.notif_channels,
-            |c| c.id == channel_id.0,
-            PCNotifChannel::new(channel_id),
-        );
+    pub fn add_subscription(
+        &mut self,
+        user_id: UserId,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+    ) -> bool {
+        self.subscriptions_by_user
+            .entry(user_id)
+            .or_default()
+            .insert((guild_id, channel_id));
 
-        Self::insert_if_not_exists(&mut notif_channel.subscribed_users, user_id.0);
+        self.guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id))
+            .notif_channels
+            .entry(channel_id)
+            .or_insert_with(|| PCNotifChannel::new(channel_id))
+            .subscribed_users
+            .insert(user_id)
     }
 
     pub fn remove_subscription(
@@ -73,118 +253,2591 @@ impl PCData {
         guild_id: GuildId,
         channel_id: ChannelId,
     ) -> bool {
-        let guild = match self.guilds.iter_mut().find(|g| g.id == guild_id.0) {
-            Some(g) => g,
-            None => return false,
-        };
-
-        let notif_channel = match guild
-            .notif_channels
-            .iter_mut()
-            .find(|c| c.id == channel_id.0)
-        {
-            Some(c) => c,
-            None => return false,
-        };
+        let removed = self
+            .guilds
+            .get_mut(&guild_id)
+            .and_then(|guild| guild.notif_channels.get_mut(&channel_id))
+            .map(|channel| channel.subscribed_users.remove(&user_id))
+            .unwrap_or(false);
 
-        let index = match notif_channel
-            .subscribed_users
-            .iter()
-            .position(|&u| u == user_id.0)
-        {
-            Some(i) => i,
-            None => return false,
-        };
+        if removed {
+            if let Some(subscriptions) = self.subscriptions_by_user.get_mut(&user_id) {
+                subscriptions.remove(&(guild_id, channel_id));
+                if subscriptions.is_empty() {
+                    self.subscriptions_by_user.remove(&user_id);
+                }
+            }
+        }
 
-        notif_channel.subscribed_users.swap_remove(index);
-        true
+        removed
     }
 
     pub fn is_admin(&self, user_id: UserId, guild_id: GuildId) -> bool {
-        let guild = match self.guilds.iter().find(|g| g.id == guild_id.0) {
-            Some(g) => g,
-            None => return false,
-        };
-        guild.admins.iter().any(|u| u.id == user_id.0)
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| guild.admins.contains_key(&user_id))
+            .unwrap_or(false)
     }
 
     pub fn should_send_notif_copies(&self, joined_user_id: UserId, guild_id: GuildId) -> bool {
-        let guild = match self.guilds.iter().find(|g| g.id == guild_id.0) {
-            Some(g) => g,
-            None => return false,
-        };
-        guild
-            .admins
-            .iter()
-            .find(|u| u.id == joined_user_id.0)
-            .map(|u| u.send_notif_copies)
+        self.guilds
+            .get(&guild_id)
+            .and_then(|guild| guild.admins.get(&joined_user_id))
+            .map(|admin| admin.send_notif_copies)
             .unwrap_or(false)
     }
 
-    pub fn add_afk_channel(&mut self, guild_id: GuildId, channel_id: ChannelId) {
-        let guild = Self::find_or_insert(
-            &mut self.guilds,
-            |g| g.id == guild_id.0,
-            PCGuild::new(guild_id),
-        );
+    pub fn add_admin(
+        &mut self,
+        user_id: UserId,
+        guild_id: GuildId,
+        send_notif_copies: bool,
+    ) -> bool {
+        let admin = AdminUser { send_notif_copies };
+
+        self.guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id))
+            .admins
+            .insert(user_id, admin.clone())
+            .map(|old| old.send_notif_copies != admin.send_notif_copies)
+            .unwrap_or(true)
+    }
+
+    pub fn remove_admin(&mut self, user_id: UserId, guild_id: GuildId) -> bool {
+        self.guilds
+            .get_mut(&guild_id)
+            .map(|guild| guild.admins.remove(&user_id).is_some())
+            .unwrap_or(false)
+    }
 
-        Self::insert_if_not_exists(&mut guild.afk_channels, channel_id.0);
+    pub fn add_afk_channel(&mut self, guild_id: GuildId, channel_id: ChannelId) -> bool {
+        self.guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id))
+            .afk_channels
+            .insert(channel_id)
     }
 
     pub fn remove_afk_channel(&mut self, guild_id: GuildId, channel_id: ChannelId) -> bool {
-        let guild = match self.guilds.iter_mut().find(|g| g.id == guild_id.0) {
-            Some(g) => g,
-            None => return false,
+        self.guilds
+            .get_mut(&guild_id)
+            .map(|guild| guild.afk_channels.remove(&channel_id))
+            .unwrap_or(false)
+    }
+
+    // Doesn't add guilds that have no AFK channel and no settings yet.
+    pub fn set_discord_afk_channel(
+        &mut self,
+        guild_id: GuildId,
+        channel_id: Option<ChannelId>,
+    ) -> bool {
+        let guild = match (self.guilds.get_mut(&guild_id), channel_id) {
+            (Some(guild), _) => guild,
+            (None, Some(_)) => self
+                .guilds
+                .entry(guild_id)
+                .or_insert_with(|| PCGuild::new(guild_id)),
+            (None, None) => return false,
         };
 
-        let index = match guild.afk_channels.iter().position(|&c| c == channel_id.0) {
-            Some(i) => i,
-            None => return false,
+        let changed = guild.discord_afk_channel != channel_id;
+        guild.discord_afk_channel = channel_id;
+        changed
+    }
+
+    pub fn set_ignore_discord_afk_channel(&mut self, guild_id: GuildId, ignore: bool) -> bool {
+        let guild = self
+            .guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id));
+
+        let changed = guild.ignore_discord_afk_channel != ignore;
+        guild.ignore_discord_afk_channel = ignore;
+        changed
+    }
+
+    pub fn afk_suggestions_offered(&self, guild_id: GuildId) -> bool {
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| guild.afk_suggestions_offered)
+            .unwrap_or(false)
+    }
+
+    pub fn set_afk_suggestions_offered(&mut self, guild_id: GuildId) -> bool {
+        let guild = self
+            .guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id));
+
+        !std::mem::replace(&mut guild.afk_suggestions_offered, true)
+    }
+
+    pub fn is_lobby_channel(&self, guild_id: GuildId, channel_id: ChannelId) -> bool {
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| guild.lobby_channels.contains(&channel_id))
+            .unwrap_or(false)
+    }
+
+    pub fn add_lobby_channel(&mut self, guild_id: GuildId, channel_id: ChannelId) -> bool {
+        self.guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id))
+            .lobby_channels
+            .insert(channel_id)
+    }
+
+    pub fn remove_lobby_channel(&mut self, guild_id: GuildId, channel_id: ChannelId) -> bool {
+        self.guilds
+            .get_mut(&guild_id)
+            .map(|guild| guild.lobby_channels.remove(&channel_id))
+            .unwrap_or(false)
+    }
+
+    // The lobby a temporary voice channel was created from, or `None` if it isn't one.
+    pub fn temp_channel_lobby(
+        &self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+    ) -> Option<ChannelId> {
+        self.guilds
+            .get(&guild_id)
+            .and_then(|guild| guild.temp_channels.get(&channel_id))
+            .map(|c| c.lobby)
+    }
+
+    // The temporary voice channel that was created for the user, if they still have one.
+    pub fn owned_temp_channel(&self, guild_id: GuildId, user_id: UserId) -> Option<ChannelId> {
+        self.guilds.get(&guild_id).and_then(|guild| {
+            guild
+                .temp_channels
+                .iter()
+                .find(|(_, c)| c.owner == Some(user_id))
+                .map(|(&id, _)| id)
+        })
+    }
+
+    pub fn add_temp_channel(
+        &mut self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        lobby_id: ChannelId,
+        owner_id: UserId,
+    ) -> bool {
+        let channel = TempChannel {
+            lobby: lobby_id,
+            owner: Some(owner_id),
         };
+        self.guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id))
+            .temp_channels
+            .insert(channel_id, channel)
+            != Some(channel)
+    }
+
+    pub fn remove_temp_channel(&mut self, guild_id: GuildId, channel_id: ChannelId) -> bool {
+        self.guilds
+            .get_mut(&guild_id)
+            .map(|guild| guild.temp_channels.remove(&channel_id).is_some())
+            .unwrap_or(false)
+    }
+
+    pub fn idle_move_after(&self, guild_id: GuildId) -> Option<u64> {
+        self.guilds
+            .get(&guild_id)
+            .and_then(|guild| guild.idle_move_after)
+    }
+
+    pub fn set_idle_move_after(&mut self, guild_id: GuildId, after: Option<u64>) -> bool {
+        let guild = self
+            .guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id));
+
+        let changed = guild.idle_move_after != after;
+        guild.idle_move_after = after;
+        changed
+    }
+
+    // The registered AFK channel that idle members are moved to, the one with the lowest ID if
+    // there are several. The AFK channel from the guild's own settings isn't used, since Discord
+    // already moves idle members there by itself.
+    pub fn idle_move_target(&self, guild_id: GuildId) -> Option<ChannelId> {
+        self.guilds
+            .get(&guild_id)
+            .and_then(|guild| guild.afk_channels.iter().min().copied())
+    }
+
+    pub fn is_idle_move_exempt(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        roles: &[RoleId],
+    ) -> bool {
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| {
+                guild
+                    .idle_move_exemptions
+                    .contains(&IdleMoveExemption::User(user_id))
+                    || roles.iter().any(|&role_id| {
+                        guild
+                            .idle_move_exemptions
+                            .contains(&IdleMoveExemption::Role(role_id))
+                    })
+            })
+            .unwrap_or(false)
+    }
+
+    pub fn add_idle_move_exemption(
+        &mut self,
+        guild_id: GuildId,
+        exemption: IdleMoveExemption,
+    ) -> bool {
+        self.guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id))
+            .idle_move_exemptions
+            .insert(exemption)
+    }
+
+    pub fn remove_idle_move_exemption(
+        &mut self,
+        guild_id: GuildId,
+        exemption: IdleMoveExemption,
+    ) -> bool {
+        self.guilds
+            .get_mut(&guild_id)
+            .map(|guild| guild.idle_move_exemptions.remove(&exemption))
+            .unwrap_or(false)
+    }
+
+    pub fn fallback_channel(&self, guild_id: GuildId) -> Option<ChannelId> {
+        self.guilds
+            .get(&guild_id)
+            .and_then(|guild| guild.fallback_channel)
+    }
+
+    pub fn set_fallback_channel(
+        &mut self,
+        guild_id: GuildId,
+        channel_id: Option<ChannelId>,
+    ) -> bool {
+        let guild = self
+            .guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id));
+
+        let changed = guild.fallback_channel != channel_id;
+        guild.fallback_channel = channel_id;
+        changed
+    }
+
+    pub fn suppression_rules(&self, guild_id: GuildId) -> &[SuppressionRule] {
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| guild.suppression_rules())
+            .unwrap_or_else(|| suppression::default_rules())
+    }
 
-        guild.afk_channels.swap_remove(index);
+    pub fn suppression_mode(&self, guild_id: GuildId) -> SuppressionMode {
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| guild.suppression_mode)
+            .unwrap_or_default()
+    }
+
+    pub fn set_suppression_mode(&mut self, guild_id: GuildId, mode: SuppressionMode) -> bool {
+        let guild = self
+            .guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id));
+
+        let changed = guild.suppression_mode != mode;
+        guild.suppression_mode = mode;
+        changed
+    }
+
+    // Changing a guild's rules for the first time starts out from the default rules.
+    pub fn add_suppression_rule(&mut self, guild_id: GuildId, rule: SuppressionRule) -> bool {
+        let rules = self.custom_suppression_rules(guild_id);
+        if rules.contains(&rule) {
+            return false;
+        }
+
+        rules.push(rule);
         true
     }
 
-    fn find_or_insert<T, P>(vec: &mut Vec<T>, predicate: P, default: T) -> &mut T
-    where
-        P: FnMut(&T) -> bool,
-    {
-        let idx = vec.iter().position(predicate).unwrap_or_else(|| {
-            vec.push(default);
-            vec.len() - 1
-        });
+    pub fn remove_suppression_rule(&mut self, guild_id: GuildId, rule: &SuppressionRule) -> bool {
+        if !self.suppression_rules(guild_id).contains(rule) {
+            return false;
+        }
 
-        &mut vec[idx]
+        self.custom_suppression_rules(guild_id)
+            .retain(|r| r != rule);
+        true
     }
 
-    fn insert_if_not_exists<T>(vec: &mut Vec<T>, val: T)
-    where
-        T: PartialEq,
-    {
-        match vec.iter().find(|&v| *v == val) {
-            Some(_) => (),
-            None => vec.push(val),
+    pub fn reset_suppression_rules(&mut self, guild_id: GuildId) -> bool {
+        self.guilds
+            .get_mut(&guild_id)
+            .map(|guild| guild.suppression_rules.take().is_some())
+            .unwrap_or(false)
+    }
+
+    fn custom_suppression_rules(&mut self, guild_id: GuildId) -> &mut Vec<SuppressionRule> {
+        self.guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id))
+            .suppression_rules
+            .get_or_insert_with(|| suppression::default_rules().to_vec())
+    }
+
+    pub fn spoiler_rules(&self, guild_id: GuildId) -> &[SpoilerRule] {
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| guild.spoiler_rules())
+            .unwrap_or_default()
+    }
+
+    pub fn should_spoiler(&self, guild_id: GuildId, game: &str, channel_id: ChannelId) -> bool {
+        self.spoiler_rules(guild_id)
+            .iter()
+            .any(|rule| rule.matches(game, channel_id))
+    }
+
+    pub fn add_spoiler_rule(&mut self, guild_id: GuildId, rule: SpoilerRule) -> bool {
+        let rules = &mut self
+            .guilds
+            .entry(guild_id)
+            .or_insert_with(|| PCGuild::new(guild_id))
+            .spoiler_rules;
+
+        if rules.contains(&rule) {
+            return false;
         }
+
+        rules.push(rule);
+        true
     }
-}
 
-impl PCGuild {
-    fn new(id: GuildId) -> PCGuild {
-        PCGuild {
-            id: id.0,
-            admins: vec![],
-            afk_channels: vec![],
-            notif_channels: vec![],
+    pub fn remove_spoiler_rule(&mut self, guild_id: GuildId, rule: &SpoilerRule) -> bool {
+        match self.guilds.get_mut(&guild_id) {
+            Some(guild) => {
+                let count = guild.spoiler_rules.len();
+                guild.spoiler_rules.retain(|r| r != rule);
+                guild.spoiler_rules.len() != count
+            }
+            None => false,
         }
     }
-}
 
-impl PCNotifChannel {
-    fn new(id: ChannelId) -> PCNotifChannel {
-        PCNotifChannel {
-            id: id.0,
-            subscribed_users: vec![],
+    // Whether the user's time in the channel is recorded.
+    pub fn is_voice_tracked(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        channel_id: ChannelId,
+    ) -> bool {
+        !self.is_afk_channel(guild_id, channel_id)
+            && !self.voice_tracking_opt_outs.contains(&user_id)
+    }
+
+    pub fn is_voice_tracking_opted_out(&self, user_id: UserId) -> bool {
+        self.voice_tracking_opt_outs.contains(&user_id)
+    }
+
+    // The user's recorded sessions have to be forgotten separately, see `VoiceSessions`.
+    pub fn set_voice_tracking_opt_out(&mut self, user_id: UserId, opt_out: bool) -> bool {
+        if opt_out {
+            self.voice_tracking_opt_outs.insert(user_id)
+        } else {
+            self.voice_tracking_opt_outs.remove(&user_id)
+        }
+    }
+
+    pub fn is_digest_subscriber(&self, user_id: UserId) -> bool {
+        self.digest_subscribers.contains(&user_id)
+    }
+
+    // When digests were sent is recorded separately, see `RuntimeData::record_digest_sent`.
+    pub fn set_digest_subscription(&mut self, user_id: UserId, subscribe: bool) -> bool {
+        if subscribe {
+            self.digest_subscribers.insert(user_id)
+        } else {
+            self.digest_subscribers.remove(&user_id)
+        }
+    }
+
+    pub fn digest_subscribers(&self) -> impl Iterator<Item = UserId> + '_ {
+        self.digest_subscribers.iter().copied()
+    }
+
+    // The following remove settings that refer to things that don't exist anymore. Each returns a
+    // description of what it removed, like `describe_changes`.
+
+    pub fn remove_guild(&mut self, guild_id: GuildId) -> Vec<String> {
+        let mut changes = self.change_guild(guild_id, |guild| *guild = PCGuild::new(guild_id));
+        if self.guilds.remove(&guild_id).is_some() {
+            changes.insert(0, format!("guild {}: removed", guild_id));
+        }
+        changes
+    }
+
+    pub fn remove_channel(&mut self, guild_id: GuildId, channel_id: ChannelId) -> Vec<String> {
+        self.change_guild(guild_id, |guild| {
+            guild.notif_channels.remove(&channel_id);
+            guild.afk_channels.remove(&channel_id);
+            guild.lobby_channels.remove(&channel_id);
+            guild.temp_channels.remove(&channel_id);
+            if guild.discord_afk_channel == Some(channel_id) {
+                guild.discord_afk_channel = None;
+            }
+            if guild.fallback_channel == Some(channel_id) {
+                guild.fallback_channel = None;
+            }
+            guild
+                .spoiler_rules
+                .retain(|rule| rule.channel != Some(channel_id));
+        })
+    }
+
+    // Settings that aren't tied to a guild, like digest subscriptions, are only removed once the
+    // user isn't in any other guild with the bot either. Voice tracking opt-outs are always kept,
+    // since only the user can opt back in.
+    pub fn remove_member(
+        &mut self,
+        guild_id: GuildId,
+        user_id: UserId,
+        in_other_guilds: bool,
+    ) -> Vec<String> {
+        let mut changes = self.change_guild(guild_id, |guild| {
+            guild.admins.remove(&user_id);
+            for channel in guild.notif_channels.values_mut() {
+                channel.subscribed_users.remove(&user_id);
+            }
+            guild
+                .idle_move_exemptions
+                .remove(&IdleMoveExemption::User(user_id));
+            for channel in guild.temp_channels.values_mut() {
+                if channel.owner == Some(user_id) {
+                    channel.owner = None;
+                }
+            }
+        });
+
+        if !in_other_guilds && self.digest_subscribers.remove(&user_id) {
+            changes.push(format!(
+                "user {}: unsubscribed from the weekly digest",
+                user_id
+            ));
+        }
+        changes
+    }
+
+    fn change_guild(&mut self, guild_id: GuildId, f: impl FnOnce(&mut PCGuild)) -> Vec<String> {
+        let guild = match self.guilds.get_mut(&guild_id) {
+            Some(g) => g,
+            None => return vec![],
+        };
+
+        let old_guild = guild.clone();
+        f(guild);
+
+        let mut changes = vec![];
+        describe_guild_changes(guild_id, &old_guild, guild, &mut changes);
+        if !changes.is_empty() {
+            self.rebuild_indexes();
         }
+        changes
+    }
+
+    // Describes everything that differs between `self` and `new`, one change per line.
+    pub fn describe_changes(&self, new: &PCData) -> Vec<String> {
+        let mut changes = vec![];
+
+        let mut guild_ids = self
+            .guilds
+            .keys()
+            .chain(new.guilds.keys())
+            .collect::<Vec<_>>();
+        guild_ids.sort_unstable();
+        guild_ids.dedup();
+
+        let empty = PCGuild::new(GuildId::from(0));
+        for &guild_id in guild_ids {
+            let old_guild = self.guilds.get(&guild_id);
+            let new_guild = new.guilds.get(&guild_id);
+
+            match (old_guild, new_guild) {
+                (None, Some(_)) => changes.push(format!("guild {}: added", guild_id)),
+                (Some(_), None) => changes.push(format!("guild {}: removed", guild_id)),
+                _ => (),
+            }
+
+            describe_guild_changes(
+                guild_id,
+                old_guild.unwrap_or(&empty),
+                new_guild.unwrap_or(&empty),
+                &mut changes,
+            );
+        }
+
+        for user_id in
+            sorted_difference(&new.voice_tracking_opt_outs, &self.voice_tracking_opt_outs)
+        {
+            changes.push(format!("user {}: opted out of voice tracking", user_id));
+        }
+        for user_id in
+            sorted_difference(&self.voice_tracking_opt_outs, &new.voice_tracking_opt_outs)
+        {
+            changes.push(format!("user {}: opted back in to voice tracking", user_id));
+        }
+
+        for user_id in sorted_difference(&new.digest_subscribers, &self.digest_subscribers) {
+            changes.push(format!("user {}: subscribed to the weekly digest", user_id));
+        }
+        for user_id in sorted_difference(&self.digest_subscribers, &new.digest_subscribers) {
+            changes.push(format!(
+                "user {}: unsubscribed from the weekly digest",
+                user_id
+            ));
+        }
+
+        changes
+    }
+
+    // Keeps every voice tracking opt-out of `self` in `new`, so that reloading an outdated file
+    // can't silently undo one. Returns the users whose opt-out was kept this way.
+    pub fn keep_voice_tracking_opt_outs(&self, new: &mut PCData) -> Vec<UserId> {
+        let missing =
+            sorted_difference(&self.voice_tracking_opt_outs, &new.voice_tracking_opt_outs);
+        new.voice_tracking_opt_outs.extend(&missing);
+        missing
+    }
+
+    pub fn voice_tracking_opt_outs(&self) -> impl Iterator<Item = UserId> + '_ {
+        self.voice_tracking_opt_outs.iter().copied()
+    }
+
+    fn rebuild_indexes(&mut self) {
+        self.subscriptions_by_user.clear();
+
+        for guild in self.guilds.values() {
+            for channel in guild.notif_channels.values() {
+                for &user_id in &channel.subscribed_users {
+                    self.subscriptions_by_user
+                        .entry(user_id)
+                        .or_default()
+                        .insert((guild.id, channel.id));
+                }
+            }
+        }
+    }
+}
+
+fn sorted_difference(a: &HashSet<UserId>, b: &HashSet<UserId>) -> Vec<UserId> {
+    let mut difference = a.difference(b).copied().collect::<Vec<_>>();
+    difference.sort_unstable();
+    difference
+}
+
+fn describe_guild_changes(
+    guild_id: GuildId,
+    old_guild: &PCGuild,
+    new_guild: &PCGuild,
+    changes: &mut Vec<String>,
+) {
+    for (user_id, admin) in &new_guild.admins {
+        match old_guild.admins.get(user_id) {
+            None => changes.push(format!("guild {}: added admin {}", guild_id, user_id)),
+            Some(old) if old.send_notif_copies != admin.send_notif_copies => changes.push(format!(
+                "guild {}: admin {} send_notif_copies is now {}",
+                guild_id, user_id, admin.send_notif_copies
+            )),
+            Some(_) => (),
+        }
+    }
+    for user_id in old_guild.admins.keys() {
+        if !new_guild.admins.contains_key(user_id) {
+            changes.push(format!("guild {}: removed admin {}", guild_id, user_id));
+        }
+    }
+
+    for channel_id in new_guild.afk_channels.difference(&old_guild.afk_channels) {
+        changes.push(format!(
+            "guild {}: added AFK channel {}",
+            guild_id, channel_id
+        ));
+    }
+    for channel_id in old_guild.afk_channels.difference(&new_guild.afk_channels) {
+        changes.push(format!(
+            "guild {}: removed AFK channel {}",
+            guild_id, channel_id
+        ));
+    }
+
+    if old_guild.discord_afk_channel != new_guild.discord_afk_channel {
+        match new_guild.discord_afk_channel {
+            Some(channel_id) => changes.push(format!(
+                "guild {}: Discord AFK channel is now {}",
+                guild_id, channel_id
+            )),
+            None => changes.push(format!(
+                "guild {}: no Discord AFK channel anymore",
+                guild_id
+            )),
+        }
+    }
+    if old_guild.ignore_discord_afk_channel != new_guild.ignore_discord_afk_channel {
+        changes.push(format!(
+            "guild {}: ignore_discord_afk_channel is now {}",
+            guild_id, new_guild.ignore_discord_afk_channel
+        ));
+    }
+    if !old_guild.afk_suggestions_offered && new_guild.afk_suggestions_offered {
+        changes.push(format!(
+            "guild {}: offered AFK channel suggestions to admins",
+            guild_id
+        ));
+    }
+
+    for channel_id in new_guild
+        .lobby_channels
+        .difference(&old_guild.lobby_channels)
+    {
+        changes.push(format!(
+            "guild {}: added lobby channel {}",
+            guild_id, channel_id
+        ));
+    }
+    for channel_id in old_guild
+        .lobby_channels
+        .difference(&new_guild.lobby_channels)
+    {
+        changes.push(format!(
+            "guild {}: removed lobby channel {}",
+            guild_id, channel_id
+        ));
+    }
+    for (channel_id, channel) in &new_guild.temp_channels {
+        if old_guild.temp_channels.get(channel_id) == Some(channel) {
+            continue;
+        }
+        match channel.owner {
+            Some(owner_id) => changes.push(format!(
+                "guild {}: added temporary channel {} from lobby {} for {}",
+                guild_id, channel_id, channel.lobby, owner_id
+            )),
+            None => changes.push(format!(
+                "guild {}: added temporary channel {} from lobby {}",
+                guild_id, channel_id, channel.lobby
+            )),
+        }
+    }
+    for channel_id in old_guild.temp_channels.keys() {
+        if !new_guild.temp_channels.contains_key(channel_id) {
+            changes.push(format!(
+                "guild {}: removed temporary channel {}",
+                guild_id, channel_id
+            ));
+        }
+    }
+
+    if old_guild.fallback_channel != new_guild.fallback_channel {
+        match new_guild.fallback_channel {
+            Some(channel_id) => changes.push(format!(
+                "guild {}: fallback channel is now {}",
+                guild_id, channel_id
+            )),
+            None => changes.push(format!("guild {}: removed fallback channel", guild_id)),
+        }
+    }
+
+    if old_guild.suppression_rules.is_some() && new_guild.suppression_rules.is_none() {
+        changes.push(format!(
+            "guild {}: reset embed suppression rules to the defaults",
+            guild_id
+        ));
+    } else {
+        let old_rules = old_guild.suppression_rules();
+        let new_rules = new_guild.suppression_rules();
+        for rule in new_rules.iter().filter(|r| !old_rules.contains(r)) {
+            changes.push(format!(
+                "guild {}: added embed suppression rule {}",
+                guild_id, rule
+            ));
+        }
+        for rule in old_rules.iter().filter(|r| !new_rules.contains(r)) {
+            changes.push(format!(
+                "guild {}: removed embed suppression rule {}",
+                guild_id, rule
+            ));
+        }
+    }
+
+    if old_guild.suppression_mode != new_guild.suppression_mode {
+        changes.push(format!(
+            "guild {}: embed suppression mode is now {}",
+            guild_id,
+            new_guild.suppression_mode.name()
+        ));
+    }
+
+    for rule in new_guild
+        .spoiler_rules
+        .iter()
+        .filter(|r| !old_guild.spoiler_rules.contains(r))
+    {
+        changes.push(format!("guild {}: added spoiler rule {}", guild_id, rule));
+    }
+    for rule in old_guild
+        .spoiler_rules
+        .iter()
+        .filter(|r| !new_guild.spoiler_rules.contains(r))
+    {
+        changes.push(format!("guild {}: removed spoiler rule {}", guild_id, rule));
+    }
+
+    if old_guild.idle_move_after != new_guild.idle_move_after {
+        match new_guild.idle_move_after {
+            Some(secs) => changes.push(format!(
+                "guild {}: members deafened for {}s are now moved to an AFK channel",
+                guild_id, secs
+            )),
+            None => changes.push(format!(
+                "guild {}: deafened members are not moved to an AFK channel anymore",
+                guild_id
+            )),
+        }
+    }
+    for exemption in new_guild
+        .idle_move_exemptions
+        .difference(&old_guild.idle_move_exemptions)
+    {
+        changes.push(format!(
+            "guild {}: added idle move exemption for {}",
+            guild_id, exemption
+        ));
+    }
+    for exemption in old_guild
+        .idle_move_exemptions
+        .difference(&new_guild.idle_move_exemptions)
+    {
+        changes.push(format!(
+            "guild {}: removed idle move exemption for {}",
+            guild_id, exemption
+        ));
+    }
+    let old_subscriptions = old_guild.subscriptions();
+    let new_subscriptions = new_guild.subscriptions();
+    for (channel_id, user_id) in new_subscriptions.difference(&old_subscriptions) {
+        changes.push(format!(
+            "guild {}: subscribed {} to channel {}",
+            guild_id, user_id, channel_id
+        ));
+    }
+    for (channel_id, user_id) in old_subscriptions.difference(&new_subscriptions) {
+        changes.push(format!(
+            "guild {}: unsubscribed {} from channel {}",
+            guild_id, user_id, channel_id
+        ));
+    }
+}
+
+impl PCGuild {
+    fn new(id: GuildId) -> PCGuild {
+        PCGuild {
+            id,
+            admins: HashMap::new(),
+            afk_channels: HashSet::new(),
+            discord_afk_channel: None,
+            ignore_discord_afk_channel: false,
+            afk_suggestions_offered: false,
+            notif_channels: HashMap::new(),
+            lobby_channels: HashSet::new(),
+            temp_channels: HashMap::new(),
+            fallback_channel: None,
+            suppression_rules: None,
+            suppression_mode: SuppressionMode::Any,
+            spoiler_rules: Vec::new(),
+            idle_move_after: None,
+            idle_move_exemptions: HashSet::new(),
+        }
+    }
+
+    pub fn fallback_channel(&self) -> Option<ChannelId> {
+        self.fallback_channel
+    }
+
+    pub fn suppression_rules(&self) -> &[SuppressionRule] {
+        self.suppression_rules
+            .as_deref()
+            .unwrap_or_else(|| suppression::default_rules())
+    }
+
+    pub fn suppression_mode(&self) -> SuppressionMode {
+        self.suppression_mode
+    }
+
+    pub fn spoiler_rules(&self) -> &[SpoilerRule] {
+        &self.spoiler_rules
+    }
+
+    // All channels that any settings refer to.
+    pub fn channel_ids(&self) -> HashSet<ChannelId> {
+        self.notif_channels
+            .keys()
+            .chain(&self.afk_channels)
+            .chain(&self.discord_afk_channel)
+            .chain(&self.lobby_channels)
+            .chain(self.temp_channels.keys())
+            .chain(&self.fallback_channel)
+            .chain(self.spoiler_rules.iter().flat_map(|rule| &rule.channel))
+            .copied()
+            .collect()
+    }
+
+    // All users that any settings refer to.
+    pub fn user_ids(&self) -> HashSet<UserId> {
+        self.notif_channels
+            .values()
+            .flat_map(|c| c.subscribed_users.iter())
+            .chain(self.admins.keys())
+            .chain(self.idle_move_exemptions.iter().filter_map(|e| match e {
+                IdleMoveExemption::User(user_id) => Some(user_id),
+                IdleMoveExemption::Role(_) => None,
+            }))
+            .chain(self.temp_channels.values().filter_map(|c| c.owner.as_ref()))
+            .copied()
+            .collect()
+    }
+
+    pub fn notif_channels(&self) -> impl Iterator<Item = &PCNotifChannel> {
+        self.notif_channels.values()
+    }
+
+    pub fn afk_channels(&self) -> impl Iterator<Item = ChannelId> + '_ {
+        self.afk_channels.iter().copied()
+    }
+
+    pub fn lobby_channels(&self) -> impl Iterator<Item = ChannelId> + '_ {
+        self.lobby_channels.iter().copied()
+    }
+
+    pub fn temp_channels(&self) -> impl Iterator<Item = (ChannelId, TempChannel)> + '_ {
+        self.temp_channels.iter().map(|(&id, &c)| (id, c))
+    }
+
+    pub fn idle_move_after(&self) -> Option<u64> {
+        self.idle_move_after
+    }
+
+    pub fn idle_move_exemptions(&self) -> impl Iterator<Item = IdleMoveExemption> + '_ {
+        self.idle_move_exemptions.iter().copied()
+    }
+
+    pub fn discord_afk_channel(&self) -> Option<ChannelId> {
+        self.discord_afk_channel
+    }
+
+    pub fn ignores_discord_afk_channel(&self) -> bool {
+        self.ignore_discord_afk_channel
+    }
+
+    // Yields every admin along with whether they get copies of join notifications.
+    pub fn admins(&self) -> impl Iterator<Item = (UserId, bool)> + '_ {
+        self.admins
+            .iter()
+            .map(|(&id, admin)| (id, admin.send_notif_copies))
+    }
+
+    fn subscriptions(&self) -> HashSet<(ChannelId, UserId)> {
+        self.notif_channels
+            .values()
+            .flat_map(|c| c.subscribed_users.iter().map(move |&u| (c.id, u)))
+            .collect()
+    }
+}
+
+impl PCNotifChannel {
+    fn new(id: ChannelId) -> PCNotifChannel {
+        PCNotifChannel {
+            id,
+            subscribed_users: HashSet::new(),
+        }
+    }
+}
+
+impl VoiceSessions {
+    pub fn guild_ids(&self) -> impl Iterator<Item = GuildId> + '_ {
+        self.guilds.keys().copied()
+    }
+
+    // Sessions that overlap the given time span, with those still going on ending at `now`.
+    pub fn voice_sessions(
+        &self,
+        guild_id: GuildId,
+        from: u64,
+        now: u64,
+    ) -> impl Iterator<Item = VoiceSession> + '_ {
+        let guild = self.guilds.get(&guild_id);
+        let finished = guild
+            .into_iter()
+            .flat_map(|guild| guild.finished.iter().copied());
+        let open = guild.into_iter().flat_map(move |guild| {
+            guild
+                .open
+                .iter()
+                .map(move |(&user, &(channel, start))| VoiceSession {
+                    user,
+                    channel,
+                    start,
+                    end: now,
+                })
+        });
+
+        finished.chain(open).filter(move |s| s.end > from)
+    }
+
+    // Updates the user's voice session after they joined, moved to or left (`None`) a channel.
+    // Callers pass `None` for channels that aren't tracked, see `PCData::is_voice_tracked`.
+    pub fn update_voice_session(
+        &mut self,
+        guild_id: GuildId,
+        user_id: UserId,
+        channel_id: Option<ChannelId>,
+        now: u64,
+    ) -> bool {
+        let guild = match (self.guilds.get_mut(&guild_id), channel_id) {
+            (Some(g), _) => g,
+            (None, None) => return false,
+            (None, Some(_)) => self.guilds.entry(guild_id).or_default(),
+        };
+
+        let open = guild.open.get(&user_id).copied();
+        if open.map(|(c, _)| c) == channel_id {
+            return false;
+        }
+
+        if let Some((channel, start)) = open {
+            guild.open.remove(&user_id);
+            guild.finished.push(VoiceSession {
+                user: user_id,
+                channel,
+                start,
+                end: now,
+            });
+            guild
+                .finished
+                .retain(|s| s.end + VOICE_SESSION_RETENTION_SECS > now);
+        }
+
+        if let Some(channel) = channel_id {
+            guild.open.insert(user_id, (channel, now));
+        }
+
+        true
+    }
+
+    // Brings open sessions in line with who is currently in which tracked channel, e.g. after a
+    // restart. Sessions of users that left while the bot wasn't watching are dropped, since it's
+    // unknown when they ended.
+    pub fn reconcile(
+        &mut self,
+        guild_id: GuildId,
+        voice_channels: &HashMap<UserId, ChannelId>,
+        now: u64,
+    ) -> bool {
+        let mut changed = false;
+
+        if let Some(guild) = self.guilds.get_mut(&guild_id) {
+            let count = guild.open.len();
+            guild
+                .open
+                .retain(|user_id, (channel_id, _)| voice_channels.get(user_id) == Some(channel_id));
+            changed = guild.open.len() != count;
+        }
+
+        for (&user_id, &channel_id) in voice_channels {
+            changed |= self.update_voice_session(guild_id, user_id, Some(channel_id), now);
+        }
+
+        changed
+    }
+
+    // Forgets all of the user's sessions, e.g. because they opted out.
+    pub fn forget_user(&mut self, user_id: UserId) -> bool {
+        let mut changed = false;
+        for guild in self.guilds.values_mut() {
+            let count = guild.finished.len();
+            guild.finished.retain(|s| s.user != user_id);
+            changed |= guild.finished.len() != count;
+            changed |= guild.open.remove(&user_id).is_some();
+        }
+        changed
+    }
+
+    // Users that any sessions in the guild belong to.
+    pub fn user_ids(&self, guild_id: GuildId) -> HashSet<UserId> {
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| {
+                guild
+                    .finished
+                    .iter()
+                    .map(|s| s.user)
+                    .chain(guild.open.keys().copied())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn remove_guild(&mut self, guild_id: GuildId) -> Vec<String> {
+        match self.guilds.remove(&guild_id) {
+            Some(guild) => vec![format!(
+                "guild {}: removed {} voice session(s)",
+                guild_id,
+                guild.finished.len() + guild.open.len()
+            )],
+            None => vec![],
+        }
+    }
+
+    // Ends the sessions still going on in a channel that was deleted. Finished sessions in it are
+    // kept like any others, e.g. those in temporary channels.
+    pub fn end_channel_sessions(
+        &mut self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        now: u64,
+    ) -> Vec<String> {
+        let user_ids = match self.guilds.get(&guild_id) {
+            Some(guild) => guild
+                .open
+                .iter()
+                .filter(|(_, (channel, _))| *channel == channel_id)
+                .map(|(&user_id, _)| user_id)
+                .collect::<Vec<_>>(),
+            None => return vec![],
+        };
+
+        for &user_id in &user_ids {
+            self.update_voice_session(guild_id, user_id, None, now);
+        }
+
+        if user_ids.is_empty() {
+            vec![]
+        } else {
+            vec![format!(
+                "guild {}: ended {} voice session(s) in channel {}",
+                guild_id,
+                user_ids.len(),
+                channel_id
+            )]
+        }
+    }
+
+    pub fn remove_member(&mut self, guild_id: GuildId, user_id: UserId) -> Vec<String> {
+        self.change_guild(guild_id, |guild| {
+            guild.finished.retain(|s| s.user != user_id);
+            guild.open.remove(&user_id);
+        })
+        .map(|count| {
+            format!(
+                "guild {}: removed {} voice session(s) of {}",
+                guild_id, count, user_id
+            )
+        })
+        .into_iter()
+        .collect()
+    }
+
+    // Returns how many sessions `f` removed, if any.
+    fn change_guild(
+        &mut self,
+        guild_id: GuildId,
+        f: impl FnOnce(&mut GuildVoiceSessions),
+    ) -> Option<usize> {
+        let guild = self.guilds.get_mut(&guild_id)?;
+
+        let count = guild.finished.len() + guild.open.len();
+        f(guild);
+        let removed = count - (guild.finished.len() + guild.open.len());
+
+        if guild.finished.is_empty() && guild.open.is_empty() {
+            self.guilds.remove(&guild_id);
+        }
+        (removed > 0).then_some(removed)
+    }
+}
+
+impl RuntimeData {
+    pub fn record_idle_move(&mut self, guild_id: GuildId, idle_move: IdleMove) -> bool {
+        let idle_moves = &mut self.guilds.entry(guild_id).or_default().idle_moves;
+        idle_moves.push(idle_move);
+        let excess = idle_moves.len().saturating_sub(IDLE_MOVE_LOG_LENGTH);
+        idle_moves.drain(..excess);
+        true
+    }
+
+    // Oldest first.
+    pub fn idle_moves(&self, guild_id: GuildId) -> &[IdleMove] {
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| guild.idle_moves.as_slice())
+            .unwrap_or_default()
+    }
+
+    // Only the first result posted for each day counts.
+    pub fn record_game_result(
+        &mut self,
+        guild_id: GuildId,
+        user_id: UserId,
+        result: &GameResult,
+    ) -> bool {
+        let scores = self
+            .guilds
+            .entry(guild_id)
+            .or_default()
+            .game_results
+            .entry(result.game.name.to_string())
+            .or_default()
+            .entry(user_id)
+            .or_default();
+
+        if scores.contains_key(&result.day) {
+            return false;
+        }
+
+        scores.insert(result.day, result.score);
+        // Relative to the user's own newest result, so that nobody can make everyone else's
+        // results expire by posting one for a puzzle far in the future.
+        let newest = *scores.keys().next_back().unwrap();
+        scores.retain(|&day, _| day + GAME_RESULT_RETENTION_DAYS > newest);
+        true
+    }
+
+    pub fn game_results(
+        &self,
+        guild_id: GuildId,
+        game: &str,
+    ) -> impl Iterator<Item = (UserId, &GameScores)> + '_ {
+        self.guilds
+            .get(&guild_id)
+            .and_then(|guild| guild.game_results.get(game))
+            .into_iter()
+            .flat_map(|users| users.iter().map(|(&user_id, scores)| (user_id, scores)))
+    }
+
+    // Names of the games that results were recorded for in the guild.
+    pub fn games_played(&self, guild_id: GuildId) -> Vec<&str> {
+        let mut games: Vec<&str> = self
+            .guilds
+            .get(&guild_id)
+            .map(|guild| guild.game_results.keys().map(|g| g.as_str()).collect())
+            .unwrap_or_default();
+        games.sort_unstable();
+        games
+    }
+
+    // The `subscribers` that haven't been sent a digest since the week starting at `week_start`
+    // began. Subscribers that were never sent one, e.g. because they were added by editing
+    // config/pc_data.json, are due right away.
+    pub fn due_digests(
+        &self,
+        subscribers: impl Iterator<Item = UserId>,
+        week_start: u64,
+    ) -> Vec<UserId> {
+        subscribers
+            .filter(|user_id| {
+                self.digests_sent
+                    .get(user_id)
+                    .is_none_or(|&sent| sent < week_start)
+            })
+            .collect()
+    }
+
+    pub fn record_digest_sent(&mut self, user_id: UserId, now: u64) -> bool {
+        self.digests_sent.insert(user_id, now) != Some(now)
+    }
+
+    pub fn forget_digests_sent(&mut self, user_id: UserId) -> bool {
+        self.digests_sent.remove(&user_id).is_some()
+    }
+
+    pub fn delivery_failures(&self, user_id: UserId) -> Option<DeliveryFailures> {
+        self.delivery_failures.get(&user_id).copied()
+    }
+
+    // Returns the user's updated failures.
+    pub fn record_delivery_failure(&mut self, user_id: UserId, now: u64) -> DeliveryFailures {
+        let failures = self
+            .delivery_failures
+            .entry(user_id)
+            .or_insert(DeliveryFailures {
+                consecutive: 0,
+                last_failure: now,
+                paused: false,
+            });
+
+        failures.consecutive += 1;
+        failures.last_failure = now;
+        failures.paused |= failures.consecutive >= PAUSE_AFTER_FAILURES;
+        *failures
+    }
+
+    // Returns the user's failures before the successful delivery, if there were any.
+    pub fn record_delivery_success(&mut self, user_id: UserId) -> Option<DeliveryFailures> {
+        self.delivery_failures.remove(&user_id)
+    }
+
+    // Yields subscribers of the guild's channels in `data` that DMs couldn't be delivered to
+    // recently.
+    pub fn undeliverable_subscribers<'a>(
+        &'a self,
+        data: &'a PCData,
+        guild_id: GuildId,
+    ) -> impl Iterator<Item = (UserId, DeliveryFailures)> + 'a {
+        self.delivery_failures
+            .iter()
+            .filter(move |(user_id, _)| {
+                data.find_subscriptions(**user_id)
+                    .any(|(subscribed_guild, _)| subscribed_guild == guild_id)
+            })
+            .map(|(&user_id, &failures)| (user_id, failures))
+    }
+
+    pub fn guild_ids(&self) -> impl Iterator<Item = GuildId> + '_ {
+        self.guilds.keys().copied()
+    }
+
+    // Channels that anything recorded in the guild refers to.
+    pub fn channel_ids(&self, guild_id: GuildId) -> HashSet<ChannelId> {
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| {
+                guild
+                    .idle_moves
+                    .iter()
+                    .flat_map(|m| [m.from, m.to])
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Users that anything recorded in the guild refers to.
+    pub fn user_ids(&self, guild_id: GuildId) -> HashSet<UserId> {
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| {
+                guild
+                    .game_results
+                    .values()
+                    .flat_map(|users| users.keys().copied())
+                    .chain(guild.idle_moves.iter().map(|m| m.user))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn remove_guild(&mut self, guild_id: GuildId) -> Vec<String> {
+        match self.guilds.remove(&guild_id) {
+            Some(guild) => vec![format!(
+                "guild {}: removed {} puzzle result(s) and {} idle move(s)",
+                guild_id,
+                guild.result_count(),
+                guild.idle_moves.len()
+            )],
+            None => vec![],
+        }
+    }
+
+    pub fn remove_channel(&mut self, guild_id: GuildId, channel_id: ChannelId) -> Vec<String> {
+        self.change_guild(guild_id, |guild| {
+            guild
+                .idle_moves
+                .retain(|m| m.from != channel_id && m.to != channel_id);
+        })
+        .map(|(_, moves)| {
+            format!(
+                "guild {}: removed {} idle move(s) involving channel {}",
+                guild_id, moves, channel_id
+            )
+        })
+        .into_iter()
+        .collect()
+    }
+
+    // Like `PCData::remove_member`, what isn't tied to a guild is only removed once the user isn't
+    // in any other guild with the bot either.
+    pub fn remove_member(
+        &mut self,
+        guild_id: GuildId,
+        user_id: UserId,
+        in_other_guilds: bool,
+    ) -> Vec<String> {
+        let mut changes = self
+            .change_guild(guild_id, |guild| {
+                for users in guild.game_results.values_mut() {
+                    users.remove(&user_id);
+                }
+                guild.idle_moves.retain(|m| m.user != user_id);
+            })
+            .map(|(results, moves)| {
+                format!(
+                    "guild {}: removed {} puzzle result(s) and {} idle move(s) of {}",
+                    guild_id, results, moves, user_id
+                )
+            })
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        if !in_other_guilds {
+            if self.delivery_failures.remove(&user_id).is_some() {
+                changes.push(format!("user {}: forgot failed DM deliveries", user_id));
+            }
+            if self.forget_digests_sent(user_id) {
+                changes.push(format!("user {}: forgot when digests were sent", user_id));
+            }
+        }
+        changes
+    }
+
+    // Returns how many results and idle moves `f` removed, if any.
+    fn change_guild(
+        &mut self,
+        guild_id: GuildId,
+        f: impl FnOnce(&mut GuildRuntimeData),
+    ) -> Option<(usize, usize)> {
+        let guild = self.guilds.get_mut(&guild_id)?;
+
+        let (results, moves) = (guild.result_count(), guild.idle_moves.len());
+        f(guild);
+        guild.game_results.retain(|_, users| !users.is_empty());
+        let removed = (
+            results - guild.result_count(),
+            moves - guild.idle_moves.len(),
+        );
+
+        if guild.game_results.is_empty() && guild.idle_moves.is_empty() {
+            self.guilds.remove(&guild_id);
+        }
+        (removed != (0, 0)).then_some(removed)
+    }
+}
+
+impl GuildRuntimeData {
+    fn result_count(&self) -> usize {
+        self.game_results
+            .values()
+            .flat_map(|users| users.values())
+            .map(|scores| scores.len())
+            .sum()
+    }
+}
+
+// The on-disk formats of `PCData`, `VoiceSessions` and `RuntimeData`. Everything is stored in plain
+// `Vec`s of raw IDs, sorted so that saving the same data twice produces the same file.
+mod stored {
+    use log::warn;
+    use serde::{Deserialize, Serialize};
+    use serenity::model::id::{ChannelId, GuildId, RoleId, UserId};
+
+    use crate::afk::IdleMoveExemption;
+    use crate::games;
+    use crate::spoilers;
+    use crate::suppression;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct PCData {
+        guilds: Vec<PCGuild>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        voice_tracking_opt_outs: Vec<u64>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        digests: Vec<DigestSubscriber>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct PCGuild {
+        id: u64,
+        admins: Vec<AdminUser>,
+        afk_channels: Vec<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        discord_afk_channel: Option<u64>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        ignore_discord_afk_channel: bool,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        afk_suggestions_offered: bool,
+        notif_channels: Vec<PCNotifChannel>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        lobby_channels: Vec<u64>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        temp_channels: Vec<TempChannel>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fallback_channel: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        suppression_rules: Option<Vec<SuppressionRule>>,
+        // `any` or `all`, stored only if it isn't the default.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        suppression_mode: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        spoiler_rules: Vec<SpoilerRule>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        idle_move_after: Option<u64>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        idle_move_exempt_roles: Vec<u64>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        idle_move_exempt_users: Vec<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct AdminUser {
+        id: u64,
+        send_notif_copies: bool,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct PCNotifChannel {
+        id: u64,
+        subscribed_users: Vec<u64>,
+    }
+
+    // Kept in the order they were added in.
+    #[derive(Serialize, Deserialize)]
+    struct SuppressionRule {
+        kind: String,
+        pattern: String,
+    }
+
+    // Kept in the order they were added in. A missing game or channel matches all of them.
+    #[derive(Serialize, Deserialize)]
+    struct SpoilerRule {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        game: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct GameResults {
+        game: String,
+        user: u64,
+        results: Vec<GameScore>,
+    }
+
+    // A `score` of `None` means the puzzle wasn't solved.
+    #[derive(Serialize, Deserialize)]
+    struct GameScore {
+        day: u32,
+        score: Option<u32>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct TempChannel {
+        id: u64,
+        lobby: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct IdleMove {
+        user: u64,
+        from: u64,
+        to: u64,
+        at: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct DeliveryFailures {
+        user: u64,
+        consecutive: u32,
+        last_failure: u64,
+        paused: bool,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct DigestSubscriber {
+        user: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct RuntimeData {
+        guilds: Vec<GuildRuntimeData>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        delivery_failures: Vec<DeliveryFailures>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        digests: Vec<DigestSent>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct GuildRuntimeData {
+        id: u64,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        game_results: Vec<GameResults>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        idle_moves: Vec<IdleMove>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct DigestSent {
+        user: u64,
+        last_sent: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct VoiceSessions {
+        guilds: Vec<GuildVoiceSessions>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct GuildVoiceSessions {
+        id: u64,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        sessions: Vec<VoiceSession>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        open_sessions: Vec<OpenVoiceSession>,
+    }
+
+    // Kept in the order they ended in.
+    #[derive(Serialize, Deserialize)]
+    struct VoiceSession {
+        user: u64,
+        channel: u64,
+        start: u64,
+        end: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct OpenVoiceSession {
+        user: u64,
+        channel: u64,
+        start: u64,
+    }
+
+    fn sorted<T: Ord>(mut vec: Vec<T>) -> Vec<T> {
+        vec.sort_unstable();
+        vec
+    }
+
+    // Rules that can't be loaded anymore, e.g. because of a bad regex, are skipped with a warning
+    // rather than failing the whole file.
+    impl From<PCData> for super::PCData {
+        fn from(stored: PCData) -> super::PCData {
+            let mut data = super::PCData::default();
+
+            for guild in stored.guilds {
+                let id = GuildId::from(guild.id);
+                let entry = data
+                    .guilds
+                    .entry(id)
+                    .or_insert_with(|| super::PCGuild::new(id));
+
+                entry.admins.extend(guild.admins.into_iter().map(|admin| {
+                    (
+                        UserId::from(admin.id),
+                        super::AdminUser {
+                            send_notif_copies: admin.send_notif_copies,
+                        },
+                    )
+                }));
+                entry
+                    .afk_channels
+                    .extend(guild.afk_channels.into_iter().map(ChannelId::from));
+                entry.discord_afk_channel = guild.discord_afk_channel.map(ChannelId::from);
+                entry.ignore_discord_afk_channel = guild.ignore_discord_afk_channel;
+                entry.afk_suggestions_offered = guild.afk_suggestions_offered;
+                entry
+                    .lobby_channels
+                    .extend(guild.lobby_channels.into_iter().map(ChannelId::from));
+                entry
+                    .temp_channels
+                    .extend(guild.temp_channels.into_iter().map(|c| {
+                        (
+                            ChannelId::from(c.id),
+                            super::TempChannel {
+                                lobby: ChannelId::from(c.lobby),
+                                owner: c.owner.map(UserId::from),
+                            },
+                        )
+                    }));
+                entry.fallback_channel = guild.fallback_channel.map(ChannelId::from);
+                if let Some(rules) = guild.suppression_rules {
+                    let mut parsed = vec![];
+                    for rule in rules {
+                        match suppression::SuppressionRule::parse(&rule.kind, &rule.pattern) {
+                            Ok(rule) => parsed.push(rule),
+                            Err(err) => warn!(
+                                "guild {}: skipping invalid embed suppression rule {} {}: {}",
+                                id, rule.kind, rule.pattern, err
+                            ),
+                        }
+                    }
+                    entry.suppression_rules = Some(parsed);
+                }
+                if let Some(mode) = guild.suppression_mode {
+                    match suppression::SuppressionMode::parse(&mode) {
+                        Some(mode) => entry.suppression_mode = mode,
+                        None => warn!(
+                            "guild {}: ignoring invalid embed suppression mode {}",
+                            id, mode
+                        ),
+                    }
+                }
+
+                for rule in guild.spoiler_rules {
+                    let game = match rule.game {
+                        Some(name) => match games::find_game(&name) {
+                            Some(game) => Some(game.name),
+                            None => {
+                                warn!(
+                                    "guild {}: skipping spoiler rule for unknown game {}",
+                                    id, name
+                                );
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    entry.spoiler_rules.push(spoilers::SpoilerRule {
+                        game,
+                        channel: rule.channel.map(ChannelId::from),
+                    });
+                }
+
+                entry.idle_move_after = guild.idle_move_after;
+                entry.idle_move_exemptions.extend(
+                    guild
+                        .idle_move_exempt_roles
+                        .into_iter()
+                        .map(|id| IdleMoveExemption::Role(RoleId::from(id)))
+                        .chain(
+                            guild
+                                .idle_move_exempt_users
+                                .into_iter()
+                                .map(|id| IdleMoveExemption::User(UserId::from(id))),
+                        ),
+                );
+                for channel in guild.notif_channels {
+                    let channel_id = ChannelId::from(channel.id);
+                    entry
+                        .notif_channels
+                        .entry(channel_id)
+                        .or_insert_with(|| super::PCNotifChannel::new(channel_id))
+                        .subscribed_users
+                        .extend(channel.subscribed_users.into_iter().map(UserId::from));
+                }
+            }
+
+            data.voice_tracking_opt_outs
+                .extend(stored.voice_tracking_opt_outs.into_iter().map(UserId::from));
+
+            data.digest_subscribers.extend(
+                stored
+                    .digests
+                    .into_iter()
+                    .map(|digest| UserId::from(digest.user)),
+            );
+
+            data.rebuild_indexes();
+            data
+        }
+    }
+
+    impl From<super::PCData> for PCData {
+        fn from(data: super::PCData) -> PCData {
+            let mut guilds = data
+                .guilds
+                .into_values()
+                .map(|guild| PCGuild {
+                    id: guild.id.0,
+                    admins: {
+                        let mut admins = guild
+                            .admins
+                            .into_iter()
+                            .map(|(id, admin)| AdminUser {
+                                id: id.0,
+                                send_notif_copies: admin.send_notif_copies,
+                            })
+                            .collect::<Vec<_>>();
+                        admins.sort_unstable_by_key(|a| a.id);
+                        admins
+                    },
+                    afk_channels: sorted(guild.afk_channels.into_iter().map(|c| c.0).collect()),
+                    discord_afk_channel: guild.discord_afk_channel.map(|c| c.0),
+                    ignore_discord_afk_channel: guild.ignore_discord_afk_channel,
+                    afk_suggestions_offered: guild.afk_suggestions_offered,
+                    lobby_channels: sorted(guild.lobby_channels.into_iter().map(|c| c.0).collect()),
+                    temp_channels: {
+                        let mut channels = guild
+                            .temp_channels
+                            .into_iter()
+                            .map(|(id, c)| TempChannel {
+                                id: id.0,
+                                lobby: c.lobby.0,
+                                owner: c.owner.map(|u| u.0),
+                            })
+                            .collect::<Vec<_>>();
+                        channels.sort_unstable_by_key(|c| c.id);
+                        channels
+                    },
+                    notif_channels: {
+                        let mut channels = guild
+                            .notif_channels
+                            .into_values()
+                            .map(|channel| PCNotifChannel {
+                                id: channel.id.0,
+                                subscribed_users: sorted(
+                                    channel.subscribed_users.into_iter().map(|u| u.0).collect(),
+                                ),
+                            })
+                            .collect::<Vec<_>>();
+                        channels.sort_unstable_by_key(|c| c.id);
+                        channels
+                    },
+                    fallback_channel: guild.fallback_channel.map(|c| c.0),
+                    suppression_rules: guild.suppression_rules.map(|rules| {
+                        rules
+                            .into_iter()
+                            .map(|rule| SuppressionRule {
+                                kind: rule.kind().to_string(),
+                                pattern: rule.pattern().to_string(),
+                            })
+                            .collect()
+                    }),
+                    suppression_mode: match guild.suppression_mode {
+                        suppression::SuppressionMode::Any => None,
+                        mode => Some(mode.name().to_string()),
+                    },
+                    spoiler_rules: guild
+                        .spoiler_rules
+                        .into_iter()
+                        .map(|rule| SpoilerRule {
+                            game: rule.game.map(|g| g.to_string()),
+                            channel: rule.channel.map(|c| c.0),
+                        })
+                        .collect(),
+                    idle_move_after: guild.idle_move_after,
+                    idle_move_exempt_roles: sorted(
+                        guild
+                            .idle_move_exemptions
+                            .iter()
+                            .filter_map(|e| match e {
+                                IdleMoveExemption::Role(role_id) => Some(role_id.0),
+                                IdleMoveExemption::User(_) => None,
+                            })
+                            .collect(),
+                    ),
+                    idle_move_exempt_users: sorted(
+                        guild
+                            .idle_move_exemptions
+                            .iter()
+                            .filter_map(|e| match e {
+                                IdleMoveExemption::User(user_id) => Some(user_id.0),
+                                IdleMoveExemption::Role(_) => None,
+                            })
+                            .collect(),
+                    ),
+                })
+                .collect::<Vec<_>>();
+            guilds.sort_unstable_by_key(|g| g.id);
+
+            let mut digests = data
+                .digest_subscribers
+                .into_iter()
+                .map(|user_id| DigestSubscriber { user: user_id.0 })
+                .collect::<Vec<_>>();
+            digests.sort_unstable_by_key(|d| d.user);
+
+            PCData {
+                guilds,
+                voice_tracking_opt_outs: sorted(
+                    data.voice_tracking_opt_outs
+                        .into_iter()
+                        .map(|u| u.0)
+                        .collect(),
+                ),
+                digests,
+            }
+        }
+    }
+
+    impl From<RuntimeData> for super::RuntimeData {
+        fn from(stored: RuntimeData) -> super::RuntimeData {
+            let mut runtime = super::RuntimeData::default();
+
+            for guild in stored.guilds {
+                let entry = runtime.guilds.entry(GuildId::from(guild.id)).or_default();
+                for results in guild.game_results {
+                    entry
+                        .game_results
+                        .entry(results.game)
+                        .or_default()
+                        .entry(UserId::from(results.user))
+                        .or_default()
+                        .extend(results.results.into_iter().map(|r| (r.day, r.score)));
+                }
+                entry
+                    .idle_moves
+                    .extend(guild.idle_moves.into_iter().map(|m| super::IdleMove {
+                        user: UserId::from(m.user),
+                        from: ChannelId::from(m.from),
+                        to: ChannelId::from(m.to),
+                        at: m.at,
+                    }));
+            }
+
+            runtime
+                .delivery_failures
+                .extend(stored.delivery_failures.into_iter().map(|failures| {
+                    (
+                        UserId::from(failures.user),
+                        super::DeliveryFailures {
+                            consecutive: failures.consecutive,
+                            last_failure: failures.last_failure,
+                            paused: failures.paused,
+                        },
+                    )
+                }));
+            runtime.digests_sent.extend(
+                stored
+                    .digests
+                    .into_iter()
+                    .map(|digest| (UserId::from(digest.user), digest.last_sent)),
+            );
+
+            runtime
+        }
+    }
+
+    impl From<super::RuntimeData> for RuntimeData {
+        fn from(runtime: super::RuntimeData) -> RuntimeData {
+            let mut guilds = runtime
+                .guilds
+                .into_iter()
+                .map(|(guild_id, guild)| GuildRuntimeData {
+                    id: guild_id.0,
+                    game_results: {
+                        let mut results = guild
+                            .game_results
+                            .into_iter()
+                            .flat_map(|(game, users)| {
+                                users.into_iter().map(move |(user_id, scores)| GameResults {
+                                    game: game.clone(),
+                                    user: user_id.0,
+                                    results: scores
+                                        .into_iter()
+                                        .map(|(day, score)| GameScore { day, score })
+                                        .collect(),
+                                })
+                            })
+                            .collect::<Vec<_>>();
+                        results.sort_unstable_by(|a, b| (&a.game, a.user).cmp(&(&b.game, b.user)));
+                        results
+                    },
+                    idle_moves: guild
+                        .idle_moves
+                        .into_iter()
+                        .map(|m| IdleMove {
+                            user: m.user.0,
+                            from: m.from.0,
+                            to: m.to.0,
+                            at: m.at,
+                        })
+                        .collect(),
+                })
+                .collect::<Vec<_>>();
+            guilds.sort_unstable_by_key(|g| g.id);
+
+            let mut delivery_failures = runtime
+                .delivery_failures
+                .into_iter()
+                .map(|(user_id, failures)| DeliveryFailures {
+                    user: user_id.0,
+                    consecutive: failures.consecutive,
+                    last_failure: failures.last_failure,
+                    paused: failures.paused,
+                })
+                .collect::<Vec<_>>();
+            delivery_failures.sort_unstable_by_key(|f| f.user);
+
+            let mut digests = runtime
+                .digests_sent
+                .into_iter()
+                .map(|(user_id, last_sent)| DigestSent {
+                    user: user_id.0,
+                    last_sent,
+                })
+                .collect::<Vec<_>>();
+            digests.sort_unstable_by_key(|d| d.user);
+
+            RuntimeData {
+                guilds,
+                delivery_failures,
+                digests,
+            }
+        }
+    }
+
+    impl From<VoiceSessions> for super::VoiceSessions {
+        fn from(stored: VoiceSessions) -> super::VoiceSessions {
+            let mut sessions = super::VoiceSessions::default();
+
+            for guild in stored.guilds {
+                let entry = sessions.guilds.entry(GuildId::from(guild.id)).or_default();
+                entry
+                    .finished
+                    .extend(guild.sessions.into_iter().map(|s| super::VoiceSession {
+                        user: UserId::from(s.user),
+                        channel: ChannelId::from(s.channel),
+                        start: s.start,
+                        end: s.end,
+                    }));
+                entry.open.extend(
+                    guild
+                        .open_sessions
+                        .into_iter()
+                        .map(|s| (UserId::from(s.user), (ChannelId::from(s.channel), s.start))),
+                );
+            }
+
+            sessions
+        }
+    }
+
+    impl From<super::VoiceSessions> for VoiceSessions {
+        fn from(sessions: super::VoiceSessions) -> VoiceSessions {
+            let mut guilds = sessions
+                .guilds
+                .into_iter()
+                .map(|(guild_id, guild)| GuildVoiceSessions {
+                    id: guild_id.0,
+                    sessions: guild
+                        .finished
+                        .into_iter()
+                        .map(|s| VoiceSession {
+                            user: s.user.0,
+                            channel: s.channel.0,
+                            start: s.start,
+                            end: s.end,
+                        })
+                        .collect(),
+                    open_sessions: {
+                        let mut open = guild
+                            .open
+                            .into_iter()
+                            .map(|(user_id, (channel_id, start))| OpenVoiceSession {
+                                user: user_id.0,
+                                channel: channel_id.0,
+                                start,
+                            })
+                            .collect::<Vec<_>>();
+                        open.sort_unstable_by_key(|s| s.user);
+                        open
+                    },
+                })
+                .collect::<Vec<_>>();
+            guilds.sort_unstable_by_key(|g| g.id);
+
+            VoiceSessions { guilds }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file as written before any of the optional fields existed.
+    const OLD_JSON: &str = r#"{
+  "guilds": [
+    {
+      "id": 10,
+      "admins": [
+        {
+          "id": 1,
+          "send_notif_copies": true
+        },
+        {
+          "id": 2,
+          "send_notif_copies": false
+        }
+      ],
+      "afk_channels": [
+        100
+      ],
+      "notif_channels": [
+        {
+          "id": 101,
+          "subscribed_users": [
+            1,
+            3
+          ]
+        },
+        {
+          "id": 102,
+          "subscribed_users": []
+        }
+      ]
+    },
+    {
+      "id": 20,
+      "admins": [],
+      "afk_channels": [],
+      "notif_channels": []
+    }
+  ]
+}"#;
+
+    const FULL_JSON: &str = r#"{
+  "guilds": [
+    {
+      "id": 10,
+      "admins": [
+        {
+          "id": 1,
+          "send_notif_copies": true
+        }
+      ],
+      "afk_channels": [
+        100
+      ],
+      "discord_afk_channel": 103,
+      "ignore_discord_afk_channel": true,
+      "afk_suggestions_offered": true,
+      "notif_channels": [
+        {
+          "id": 101,
+          "subscribed_users": [
+            1,
+            3
+          ]
+        }
+      ],
+      "lobby_channels": [
+        104
+      ],
+      "temp_channels": [
+        {
+          "id": 105,
+          "lobby": 104,
+          "owner": 7
+        }
+      ],
+      "fallback_channel": 106,
+      "suppression_rules": [
+        {
+          "kind": "prefix",
+          "pattern": "https://example.com/"
+        },
+        {
+          "kind": "domain",
+          "pattern": "example.org"
+        }
+      ],
+      "suppression_mode": "all",
+      "spoiler_rules": [
+        {
+          "game": "Heardle"
+        },
+        {
+          "channel": 101
+        }
+      ],
+      "idle_move_after": 600,
+      "idle_move_exempt_roles": [
+        7
+      ],
+      "idle_move_exempt_users": [
+        3
+      ]
+    }
+  ],
+  "voice_tracking_opt_outs": [
+    4
+  ],
+  "digests": [
+    {
+      "user": 1
+    }
+  ]
+}"#;
+
+    const RUNTIME_JSON: &str = r#"{
+  "guilds": [
+    {
+      "id": 10,
+      "game_results": [
+        {
+          "game": "Heardle",
+          "user": 1,
+          "results": [
+            {
+              "day": 245,
+              "score": 3
+            },
+            {
+              "day": 246,
+              "score": null
+            }
+          ]
+        }
+      ],
+      "idle_moves": [
+        {
+          "user": 3,
+          "from": 101,
+          "to": 100,
+          "at": 4500
+        }
+      ]
+    }
+  ],
+  "delivery_failures": [
+    {
+      "user": 3,
+      "consecutive": 2,
+      "last_failure": 4000,
+      "paused": false
+    }
+  ],
+  "digests": [
+    {
+      "user": 1,
+      "last_sent": 3500
+    }
+  ]
+}"#;
+
+    const SESSIONS_JSON: &str = r#"{
+  "guilds": [
+    {
+      "id": 10,
+      "sessions": [
+        {
+          "user": 3,
+          "channel": 101,
+          "start": 2000,
+          "end": 3000
+        },
+        {
+          "user": 1,
+          "channel": 101,
+          "start": 1000,
+          "end": 4000
+        }
+      ],
+      "open_sessions": [
+        {
+          "user": 1,
+          "channel": 101,
+          "start": 5000
+        },
+        {
+          "user": 3,
+          "channel": 102,
+          "start": 4500
+        }
+      ]
+    },
+    {
+      "id": 20,
+      "sessions": [
+        {
+          "user": 1,
+          "channel": 201,
+          "start": 100,
+          "end": 200
+        }
+      ]
+    }
+  ]
+}"#;
+
+    fn round_trip(json: &str) -> String {
+        let data: PCData = serde_json::from_str(json).unwrap();
+        serde_json::to_string_pretty(&data).unwrap()
+    }
+
+    #[test]
+    fn old_files_load_and_round_trip_unchanged() {
+        assert_eq!(round_trip(OLD_JSON), OLD_JSON);
+    }
+
+    #[test]
+    fn all_fields_round_trip_unchanged() {
+        assert_eq!(round_trip(FULL_JSON), FULL_JSON);
+    }
+
+    #[test]
+    fn voice_sessions_round_trip_unchanged() {
+        let sessions: VoiceSessions = serde_json::from_str(SESSIONS_JSON).unwrap();
+        assert_eq!(
+            serde_json::to_string_pretty(&sessions).unwrap(),
+            SESSIONS_JSON
+        );
+    }
+
+    #[test]
+    fn runtime_data_round_trips_unchanged() {
+        let runtime: RuntimeData = serde_json::from_str(RUNTIME_JSON).unwrap();
+        assert_eq!(
+            serde_json::to_string_pretty(&runtime).unwrap(),
+            RUNTIME_JSON
+        );
+    }
+
+    #[test]
+    fn deleted_channels_keep_their_finished_sessions() {
+        let mut sessions: VoiceSessions = serde_json::from_str(SESSIONS_JSON).unwrap();
+
+        let changes = sessions.end_channel_sessions(GuildId(10), ChannelId(101), 6000);
+        assert_eq!(
+            changes,
+            ["guild 10: ended 1 voice session(s) in channel 101"]
+        );
+
+        let mut in_channel = sessions
+            .voice_sessions(GuildId(10), 0, 7000)
+            .filter(|s| s.channel == ChannelId(101))
+            .map(|s| (s.user, s.start, s.end))
+            .collect::<Vec<_>>();
+        in_channel.sort_unstable();
+        assert_eq!(
+            in_channel,
+            [
+                (UserId(1), 1000, 4000),
+                (UserId(1), 5000, 6000),
+                (UserId(3), 2000, 3000)
+            ]
+        );
+        assert!(sessions
+            .end_channel_sessions(GuildId(10), ChannelId(101), 6000)
+            .is_empty());
+    }
+
+    #[test]
+    fn temp_channels_remember_their_owner() {
+        let mut data: PCData = serde_json::from_str(FULL_JSON).unwrap();
+        let guild_id = GuildId(10);
+        assert_eq!(
+            data.owned_temp_channel(guild_id, UserId(7)),
+            Some(ChannelId(105))
+        );
+        assert!(!data.add_temp_channel(guild_id, ChannelId(105), ChannelId(104), UserId(7)));
+
+        data.remove_member(guild_id, UserId(7), true);
+        assert_eq!(data.owned_temp_channel(guild_id, UserId(7)), None);
+        assert_eq!(
+            data.temp_channel_lobby(guild_id, ChannelId(105)),
+            Some(ChannelId(104))
+        );
+    }
+
+    #[test]
+    fn room_ownership_ends_with_the_channel() {
+        let mut data = PCData::default();
+        let guild_id = GuildId(10);
+        data.add_lobby_channel(guild_id, ChannelId(104));
+
+        assert!(data.add_temp_channel(guild_id, ChannelId(110), ChannelId(104), UserId(8)));
+        assert!(data.add_temp_channel(guild_id, ChannelId(111), ChannelId(104), UserId(9)));
+        assert_eq!(
+            data.owned_temp_channel(guild_id, UserId(8)),
+            Some(ChannelId(110))
+        );
+        assert_eq!(data.owned_temp_channel(GuildId(20), UserId(8)), None);
+
+        assert!(data.remove_temp_channel(guild_id, ChannelId(110)));
+        assert_eq!(data.owned_temp_channel(guild_id, UserId(8)), None);
+        assert_eq!(
+            data.owned_temp_channel(guild_id, UserId(9)),
+            Some(ChannelId(111))
+        );
+    }
+
+    #[test]
+    fn loading_rebuilds_the_subscription_index() {
+        let data: PCData = serde_json::from_str(OLD_JSON).unwrap();
+
+        let mut subscriptions = data.find_subscriptions(UserId(3)).collect::<Vec<_>>();
+        subscriptions.sort_unstable();
+        assert_eq!(subscriptions, [(GuildId(10), ChannelId(101))]);
+        assert!(data.is_afk_channel(GuildId(10), ChannelId(100)));
+        assert!(data.is_admin(UserId(2), GuildId(10)));
+    }
+
+    #[test]
+    fn invalid_rules_are_skipped_on_load() {
+        let json = r#"{"guilds": [{"id": 1, "admins": [], "afk_channels": [], "notif_channels": [],
+            "suppression_rules": [{"kind": "regex", "pattern": "("},
+                {"kind": "domain", "pattern": "example.org"}],
+            "spoiler_rules": [{"game": "Nonsensle"}, {"game": "Heardle"}]}]}"#;
+        let data: PCData = serde_json::from_str(json).unwrap();
+
+        let guild_id = GuildId(1);
+        assert_eq!(
+            data.suppression_rules(guild_id),
+            [SuppressionRule::parse("domain", "example.org").unwrap()]
+        );
+        assert_eq!(
+            data.spoiler_rules(guild_id),
+            [SpoilerRule {
+                game: Some("Heardle"),
+                channel: None
+            }]
+        );
+    }
+
+    #[test]
+    fn may_retry_backs_off_exponentially() {
+        let failures = DeliveryFailures {
+            consecutive: 1,
+            last_failure: 1000,
+            paused: false,
+        };
+        assert!(!failures.may_retry(1000 + BACKOFF_BASE_SECS - 1));
+        assert!(failures.may_retry(1000 + BACKOFF_BASE_SECS));
+
+        let failures = DeliveryFailures {
+            consecutive: 3,
+            ..failures
+        };
+        assert!(!failures.may_retry(1000 + 4 * BACKOFF_BASE_SECS - 1));
+        assert!(failures.may_retry(1000 + 4 * BACKOFF_BASE_SECS));
+
+        // The backoff stops growing at some point instead of overflowing.
+        let failures = DeliveryFailures {
+            consecutive: 100,
+            ..failures
+        };
+        assert!(failures.may_retry(1000 + (BACKOFF_BASE_SECS << 10)));
+    }
+
+    #[test]
+    fn paused_users_are_never_retried() {
+        let failures = DeliveryFailures {
+            consecutive: PAUSE_AFTER_FAILURES,
+            last_failure: 0,
+            paused: true,
+        };
+        assert!(!failures.may_retry(u64::MAX / 2));
+    }
+
+    #[test]
+    fn old_game_results_expire() {
+        let mut data = RuntimeData::default();
+        let result = |day| GameResult {
+            game: &crate::games::GAMES[0],
+            day,
+            score: Some(3),
+        };
+
+        assert!(data.record_game_result(GuildId(1), UserId(1), &result(100)));
+        assert!(data.record_game_result(GuildId(1), UserId(2), &result(100)));
+        assert!(data.record_game_result(GuildId(1), UserId(1), &result(101)));
+        assert!(!data.record_game_result(GuildId(1), UserId(1), &result(101)));
+        assert!(data.record_game_result(GuildId(1), UserId(1), &result(466)));
+
+        let mut results = data
+            .game_results(GuildId(1), "Heardle")
+            .map(|(user_id, scores)| (user_id, scores.keys().copied().collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        results.sort_unstable();
+        assert_eq!(
+            results,
+            [(UserId(1), vec![101, 466]), (UserId(2), vec![100])]
+        );
+    }
+
+    #[test]
+    fn describe_changes_lists_every_difference() {
+        let old: PCData = serde_json::from_str(OLD_JSON).unwrap();
+        let mut new = old.clone();
+        assert!(old.describe_changes(&new).is_empty());
+
+        new.add_subscription(UserId(4), GuildId(10), ChannelId(102));
+        new.remove_afk_channel(GuildId(10), ChannelId(100));
+        new.add_admin(UserId(5), GuildId(30), false);
+        new.remove_admin(UserId(2), GuildId(10));
+        new.set_voice_tracking_opt_out(UserId(6), true);
+        new.set_digest_subscription(UserId(6), true);
+
+        let mut changes = old.describe_changes(&new);
+        changes.sort_unstable();
+        assert_eq!(
+            changes,
+            [
+                "guild 10: removed AFK channel 100",
+                "guild 10: removed admin 2",
+                "guild 10: subscribed 4 to channel 102",
+                "guild 30: added",
+                "guild 30: added admin 5",
+                "user 6: opted out of voice tracking",
+                "user 6: subscribed to the weekly digest",
+            ]
+        );
+
+        let mut changes = new.describe_changes(&old);
+        changes.sort_unstable();
+        assert_eq!(
+            changes,
+            [
+                "guild 10: added AFK channel 100",
+                "guild 10: added admin 2",
+                "guild 10: unsubscribed 4 from channel 102",
+                "guild 30: removed",
+                "guild 30: removed admin 5",
+                "user 6: opted back in to voice tracking",
+                "user 6: unsubscribed from the weekly digest",
+            ]
+        );
+    }
+
+    #[test]
+    fn reloads_keep_voice_tracking_opt_outs() {
+        let mut old = PCData::default();
+        old.set_voice_tracking_opt_out(UserId(1), true);
+        old.set_voice_tracking_opt_out(UserId(2), true);
+        let mut new = PCData::default();
+        new.set_voice_tracking_opt_out(UserId(2), true);
+        new.set_voice_tracking_opt_out(UserId(3), true);
+
+        assert_eq!(old.keep_voice_tracking_opt_outs(&mut new), [UserId(1)]);
+        assert_eq!(
+            old.describe_changes(&new),
+            ["user 3: opted out of voice tracking"]
+        );
+    }
+
+    #[test]
+    fn user_settings_are_kept_while_in_other_guilds() {
+        let mut data = PCData::default();
+        data.add_admin(UserId(1), GuildId(10), false);
+        data.set_voice_tracking_opt_out(UserId(1), true);
+        data.set_digest_subscription(UserId(1), true);
+
+        assert_eq!(
+            data.remove_member(GuildId(10), UserId(1), true),
+            ["guild 10: removed admin 1"]
+        );
+        assert!(data.is_voice_tracking_opted_out(UserId(1)));
+        assert!(data.is_digest_subscriber(UserId(1)));
+
+        assert_eq!(
+            data.remove_member(GuildId(10), UserId(1), false),
+            ["user 1: unsubscribed from the weekly digest"]
+        );
+        assert!(data.is_voice_tracking_opted_out(UserId(1)));
+        assert!(!data.is_digest_subscriber(UserId(1)));
+    }
+
+    #[test]
+    fn deleted_channels_are_removed_from_every_setting() {
+        let mut data: PCData = serde_json::from_str(FULL_JSON).unwrap();
+        let guild_id = GuildId(10);
+
+        let mut changes = data.remove_channel(guild_id, ChannelId(101));
+        changes.sort_unstable();
+        assert_eq!(
+            changes,
+            [
+                "guild 10: removed spoiler rule all games in <#101>",
+                "guild 10: unsubscribed 1 from channel 101",
+                "guild 10: unsubscribed 3 from channel 101",
+            ]
+        );
+        assert!(data.is_afk_channel(guild_id, ChannelId(100)));
+        assert!(data.is_lobby_channel(guild_id, ChannelId(104)));
+        assert_eq!(
+            data.temp_channel_lobby(guild_id, ChannelId(105)),
+            Some(ChannelId(104))
+        );
+        assert!(data.remove_channel(guild_id, ChannelId(101)).is_empty());
+    }
+
+    #[test]
+    fn removed_guilds_keep_user_settings() {
+        let mut data: PCData = serde_json::from_str(FULL_JSON).unwrap();
+
+        let changes = data.remove_guild(GuildId(10));
+        assert_eq!(changes[0], "guild 10: removed");
+        assert!(changes.contains(&"guild 10: removed admin 1".to_string()));
+        assert_eq!(data.guilds().count(), 0);
+        assert_eq!(data.find_subscriptions(UserId(1)).count(), 0);
+        assert!(data.is_digest_subscriber(UserId(1)));
+        assert!(data.is_voice_tracking_opted_out(UserId(4)));
+        assert!(data.remove_guild(GuildId(10)).is_empty());
+    }
+
+    #[test]
+    fn digests_are_due_once_a_week() {
+        let runtime: RuntimeData = serde_json::from_str(RUNTIME_JSON).unwrap();
+        let subscribers = [UserId(1), UserId(2)];
+
+        // User 2 was never sent a digest.
+        assert_eq!(
+            runtime.due_digests(subscribers.into_iter(), 3_000),
+            [UserId(2)]
+        );
+        assert_eq!(
+            runtime.due_digests(subscribers.into_iter(), 4_000),
+            [UserId(1), UserId(2)]
+        );
+    }
+
+    #[test]
+    fn runtime_data_of_departed_members_is_removed() {
+        let mut runtime: RuntimeData = serde_json::from_str(RUNTIME_JSON).unwrap();
+        let guild_id = GuildId(10);
+
+        assert_eq!(
+            runtime.remove_member(guild_id, UserId(3), true),
+            ["guild 10: removed 0 puzzle result(s) and 1 idle move(s) of 3"]
+        );
+        assert!(runtime.delivery_failures(UserId(3)).is_some());
+        assert_eq!(
+            runtime.remove_member(guild_id, UserId(3), false),
+            ["user 3: forgot failed DM deliveries"]
+        );
+        assert_eq!(runtime.delivery_failures(UserId(3)), None);
+
+        assert_eq!(
+            runtime.remove_member(guild_id, UserId(1), false),
+            [
+                "guild 10: removed 2 puzzle result(s) and 0 idle move(s) of 1",
+                "user 1: forgot when digests were sent"
+            ]
+        );
+        assert!(runtime.games_played(guild_id).is_empty());
+        assert_eq!(runtime.guild_ids().count(), 0);
+    }
+
+    #[test]
+    fn runtime_data_of_deleted_channels_and_guilds_is_removed() {
+        let mut runtime: RuntimeData = serde_json::from_str(RUNTIME_JSON).unwrap();
+        let guild_id = GuildId(10);
+
+        assert!(runtime.remove_channel(guild_id, ChannelId(105)).is_empty());
+        assert_eq!(
+            runtime.remove_channel(guild_id, ChannelId(100)),
+            ["guild 10: removed 1 idle move(s) involving channel 100"]
+        );
+        assert!(runtime.idle_moves(guild_id).is_empty());
+
+        assert_eq!(
+            runtime.remove_guild(guild_id),
+            ["guild 10: removed 2 puzzle result(s) and 0 idle move(s)"]
+        );
+        assert!(runtime.remove_guild(guild_id).is_empty());
+        // Neither is tied to the guild.
+        assert!(runtime.delivery_failures(UserId(3)).is_some());
+        assert!(runtime
+            .due_digests([UserId(1)].into_iter(), 3_000)
+            .is_empty());
     }
 }
@@ -0,0 +1,513 @@
+use crate::model::{unix_now, DeliveryFailures};
+use crate::state::PCState;
+
+use log::{debug, info, warn};
+use serenity::{
+    http::{CacheHttp, HttpError, StatusCode},
+    model::{
+        id::{ChannelId, GuildId, UserId},
+        user::User,
+    },
+    CacheAndHttp,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, Semaphore};
+
+// How many DMs are sent at the same time. serenity's HTTP client additionally waits for Discord's
+// rate limits on its own.
+const MAX_CONCURRENT_SENDS: usize = 4;
+
+// How often sending a notification is attempted before giving up, and how long to wait before the
+// first retry. The wait doubles with every further attempt.
+const MAX_ATTEMPTS: u32 = 4;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Why a DM couldn't be sent.
+pub enum DmError {
+    // Discord refused to deliver it, usually because the recipient doesn't allow DMs from server
+    // members or has blocked the bot. Holds the recipient's updated failures.
+    Undeliverable(DeliveryFailures),
+    // Something that might work when tried again, like a server error or a network problem.
+    Transient,
+    Other,
+}
+
+// Sends a DM, keeping track of recipients that DMs can't be delivered to. Any DM that is
+// delivered again, e.g. the reply to a command, resumes paused notifications.
+pub async fn send_dm(
+    cache_http: impl CacheHttp,
+    state: &PCState,
+    recipient: &User,
+    text: &str,
+) -> Result<(), DmError> {
+    let dm = recipient
+        .dm(cache_http, |m| {
+            m.content(text);
+
+            m
+        })
+        .await;
+
+    match dm {
+        Ok(_) => {
+            let mut previous = None;
+            state.update_runtime(|r| {
+                previous = r.record_delivery_success(recipient.id);
+                previous.is_some()
+            });
+
+            if let Some(failures) = previous {
+                info!(
+                    user = recipient.id.0;
+                    "Delivered a DM to {} again after {} failure(s), paused: {}",
+                    recipient,
+                    failures.consecutive,
+                    failures.paused
+                );
+            }
+
+            Ok(())
+        }
+        Err(err) if is_undeliverable(&err) => {
+            let mut failures = None;
+            state.update_runtime(|r| {
+                failures = Some(r.record_delivery_failure(recipient.id, unix_now()));
+                true
+            });
+            let failures = failures.unwrap();
+
+            warn!(
+                user = recipient.id.0;
+                "Could not deliver DM to {} ({} failure(s) in a row): {:?}",
+                recipient,
+                failures.consecutive,
+                err
+            );
+
+            Err(DmError::Undeliverable(failures))
+        }
+        Err(err) => {
+            warn!("Error sending DM to {}: {:?}", recipient, err);
+
+            if is_transient(&err) {
+                Err(DmError::Transient)
+            } else {
+                Err(DmError::Other)
+            }
+        }
+    }
+}
+
+fn is_undeliverable(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Http(err) => err.status_code() == Some(StatusCode::FORBIDDEN),
+        _ => false,
+    }
+}
+
+fn is_transient(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Http(err) => match &**err {
+            HttpError::Request(_) => true,
+            err => err.status_code().is_some_and(|status| {
+                status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+            }),
+        },
+        serenity::Error::Io(_) => true,
+        _ => false,
+    }
+}
+
+// A join notification waiting to be sent.
+pub struct Notification {
+    pub recipient: User,
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub guild_name: String,
+    pub channel_name: String,
+    pub joined_user_id: UserId,
+    pub joined_user_name: String,
+}
+
+// What happened to a queued notification.
+pub enum Delivery {
+    Sent,
+    // Everyone it was about had already left the channel by the time it would have been sent.
+    Stale,
+    Failed(DmError),
+}
+
+// A notification in a recipient's queue. Notifications about further joins to the same channel
+// are merged into it for as long as it hasn't been sent yet.
+struct Job {
+    recipient: User,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    guild_name: String,
+    channel_name: String,
+    // Everyone who joined, each only once, in the order they first joined.
+    joined_users: Vec<(UserId, String)>,
+    // Who each merged notification was about, and where to report what happened to it.
+    waiters: Vec<(UserId, oneshot::Sender<Delivery>)>,
+}
+
+impl Job {
+    fn new(notification: Notification, waiter: oneshot::Sender<Delivery>) -> Job {
+        Job {
+            recipient: notification.recipient,
+            guild_id: notification.guild_id,
+            channel_id: notification.channel_id,
+            guild_name: notification.guild_name,
+            channel_name: notification.channel_name,
+            joined_users: vec![(notification.joined_user_id, notification.joined_user_name)],
+            waiters: vec![(notification.joined_user_id, waiter)],
+        }
+    }
+
+    fn is_for_channel(&self, notification: &Notification) -> bool {
+        self.guild_id == notification.guild_id && self.channel_id == notification.channel_id
+    }
+
+    // Someone who left and rejoined before the notification went out is only mentioned once.
+    fn merge(&mut self, notification: Notification, waiter: oneshot::Sender<Delivery>) {
+        let user_id = notification.joined_user_id;
+        if !self.joined_users.iter().any(|(u, _)| *u == user_id) {
+            self.joined_users
+                .push((user_id, notification.joined_user_name));
+        }
+        self.waiters.push((user_id, waiter));
+    }
+}
+
+// Queue for join notification DMs. Each recipient's notifications are sent one at a time and in
+// order, while different recipients are sent to concurrently.
+#[derive(Clone)]
+pub struct Outbox {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    cache_and_http: Arc<CacheAndHttp>,
+    state: PCState,
+    send_permits: Semaphore,
+    queues: Mutex<Queues>,
+}
+
+#[derive(Default)]
+struct Queues {
+    pending: HashMap<UserId, VecDeque<Job>>,
+    // Recipients that currently have a task working through their queue.
+    active: HashSet<UserId>,
+}
+
+impl Outbox {
+    pub fn new(cache_and_http: Arc<CacheAndHttp>, state: PCState) -> Outbox {
+        Outbox {
+            inner: Arc::new(Inner {
+                cache_and_http,
+                state,
+                send_permits: Semaphore::new(MAX_CONCURRENT_SENDS),
+                queues: Mutex::new(Queues::default()),
+            }),
+        }
+    }
+
+    // Queues a notification. The returned receiver resolves once it has been handled.
+    pub fn enqueue(&self, notification: Notification) -> oneshot::Receiver<Delivery> {
+        let (sender, receiver) = oneshot::channel();
+        let recipient_id = notification.recipient.id;
+
+        let start_task = {
+            let mut queues = self.inner.queues.lock().unwrap();
+            let queue = queues.pending.entry(recipient_id).or_default();
+
+            match queue
+                .iter_mut()
+                .find(|job| job.is_for_channel(&notification))
+            {
+                Some(job) => {
+                    debug!(
+                        user = recipient_id.0;
+                        "Merging notification for {} into a queued one.",
+                        recipient_id
+                    );
+                    job.merge(notification, sender);
+                }
+                None => queue.push_back(Job::new(notification, sender)),
+            }
+
+            queues.active.insert(recipient_id)
+        };
+
+        if start_task {
+            tokio::spawn(self.clone().drain(recipient_id));
+        }
+
+        receiver
+    }
+
+    async fn drain(self, recipient_id: UserId) {
+        loop {
+            let job = {
+                let mut queues = self.inner.queues.lock().unwrap();
+                match queues
+                    .pending
+                    .get_mut(&recipient_id)
+                    .and_then(|q| q.pop_front())
+                {
+                    Some(job) => job,
+                    None => {
+                        queues.pending.remove(&recipient_id);
+                        queues.active.remove(&recipient_id);
+                        return;
+                    }
+                }
+            };
+
+            let mentioned = self.still_in_channel(&job);
+            let delivery = self.deliver(&job, &mentioned).await;
+
+            let mentioned = mentioned.iter().map(|(u, _)| *u).collect::<Vec<_>>();
+            let joiners = job.waiters.iter().map(|(u, _)| *u).collect::<Vec<_>>();
+            let outcomes = outcomes(delivery, &mentioned, &joiners);
+            for ((_, waiter), outcome) in job.waiters.into_iter().zip(outcomes) {
+                let _ = waiter.send(outcome);
+            }
+        }
+    }
+
+    // Only users that are still in the channel are mentioned.
+    fn still_in_channel<'a>(&self, job: &'a Job) -> Vec<&'a (UserId, String)> {
+        let cache = &self.inner.cache_and_http.cache;
+        job.joined_users
+            .iter()
+            .filter(|(user_id, _)| {
+                cache
+                    .guild_field(job.guild_id, |g| {
+                        g.voice_states.get(user_id).and_then(|vs| vs.channel_id)
+                    })
+                    .flatten()
+                    == Some(job.channel_id)
+            })
+            .collect()
+    }
+
+    async fn deliver(&self, job: &Job, mentioned: &[&(UserId, String)]) -> Delivery {
+        if mentioned.is_empty() {
+            debug!(
+                user = job.recipient.id.0;
+                "Dropping notification for {} because nobody is in the channel anymore.",
+                job.recipient.id
+            );
+            return Delivery::Stale;
+        }
+
+        let names = mentioned
+            .iter()
+            .map(|(_, name)| name.as_str())
+            .collect::<Vec<_>>();
+        let text = format!(
+            "{} joined {} on {}!",
+            join_names(&names),
+            job.channel_name,
+            job.guild_name
+        );
+
+        let inner = &*self.inner;
+        let recipient = &job.recipient;
+        let text = &text;
+        let result = send_with_retries(recipient.id, RETRY_DELAY, || async move {
+            // Only hold a permit while actually sending, so that waiting for a retry doesn't keep
+            // other recipients from being sent to.
+            let _permit = inner.send_permits.acquire().await.unwrap();
+            send_dm(&*inner.cache_and_http, &inner.state, recipient, text).await
+        })
+        .await;
+
+        match result {
+            Ok(()) => Delivery::Sent,
+            Err(err) => Delivery::Failed(err),
+        }
+    }
+}
+
+// Tries `send` up to `MAX_ATTEMPTS` times for as long as it fails with a transient error, waiting
+// `delay` before the first retry and twice as long before each further one.
+async fn send_with_retries<F, Fut>(
+    recipient_id: UserId,
+    mut delay: Duration,
+    mut send: F,
+) -> Result<(), DmError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), DmError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match send().await {
+            Err(DmError::Transient) if attempt < MAX_ATTEMPTS => {
+                debug!(
+                    user = recipient_id.0;
+                    "Retrying notification for {} in {:?}.",
+                    recipient_id,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Splits what happened to a job among the notifications merged into it, given who each of them
+// was about. Those about someone the DM didn't mention because they had already left are stale.
+// Of the rest, only the first learns the details of a failure, so that it is only handled once.
+fn outcomes(delivery: Delivery, mentioned: &[UserId], joiners: &[UserId]) -> Vec<Delivery> {
+    let shared = |delivery: &Delivery| match delivery {
+        Delivery::Sent => Delivery::Sent,
+        Delivery::Stale => Delivery::Stale,
+        Delivery::Failed(_) => Delivery::Failed(DmError::Other),
+    };
+
+    let mut outcomes = joiners
+        .iter()
+        .map(|joiner| {
+            if mentioned.contains(joiner) {
+                shared(&delivery)
+            } else {
+                Delivery::Stale
+            }
+        })
+        .collect::<Vec<_>>();
+    if let Some(first) = joiners.iter().position(|joiner| mentioned.contains(joiner)) {
+        outcomes[first] = delivery;
+    }
+    outcomes
+}
+
+fn join_names(names: &[&str]) -> String {
+    match names {
+        [] => String::new(),
+        [name] => name.to_string(),
+        [rest @ .., last] => format!("{} and {}", rest.join(", "), last),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn notification(joined_user_id: u64, channel_id: u64) -> Notification {
+        Notification {
+            recipient: User::default(),
+            guild_id: GuildId(1),
+            channel_id: ChannelId(channel_id),
+            guild_name: "Server".to_string(),
+            channel_name: "General".to_string(),
+            joined_user_id: UserId(joined_user_id),
+            joined_user_name: format!("user {}", joined_user_id),
+        }
+    }
+
+    #[test]
+    fn merging_mentions_each_joiner_once() {
+        let mut job = Job::new(notification(10, 100), oneshot::channel().0);
+        assert!(job.is_for_channel(&notification(11, 100)));
+        assert!(!job.is_for_channel(&notification(11, 101)));
+
+        job.merge(notification(11, 100), oneshot::channel().0);
+        job.merge(notification(10, 100), oneshot::channel().0);
+
+        let joined_users = job.joined_users.iter().map(|(u, _)| *u).collect::<Vec<_>>();
+        assert_eq!(joined_users, [UserId(10), UserId(11)]);
+        let joiners = job.waiters.iter().map(|(u, _)| *u).collect::<Vec<_>>();
+        assert_eq!(joiners, [UserId(10), UserId(11), UserId(10)]);
+    }
+
+    #[test]
+    fn joiners_that_left_are_reported_as_stale() {
+        let joiners = [UserId(10), UserId(11), UserId(10)];
+        let sent = outcomes(Delivery::Sent, &[UserId(11)], &joiners);
+        assert!(matches!(
+            sent[..],
+            [Delivery::Stale, Delivery::Sent, Delivery::Stale]
+        ));
+
+        let stale = outcomes(Delivery::Stale, &[], &joiners);
+        assert!(stale.iter().all(|o| matches!(o, Delivery::Stale)));
+    }
+
+    #[test]
+    fn only_the_first_mentioned_joiner_learns_failure_details() {
+        let failures = DeliveryFailures {
+            consecutive: 1,
+            last_failure: 0,
+            paused: false,
+        };
+        let joiners = [UserId(10), UserId(11), UserId(12)];
+        let failed = outcomes(
+            Delivery::Failed(DmError::Undeliverable(failures)),
+            &[UserId(11), UserId(12)],
+            &joiners,
+        );
+        assert!(matches!(
+            failed[..],
+            [
+                Delivery::Stale,
+                Delivery::Failed(DmError::Undeliverable(_)),
+                Delivery::Failed(DmError::Other),
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        let attempts = AtomicU32::new(0);
+        let result = send_with_retries(UserId(1), Duration::ZERO, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(DmError::Transient),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_give_up_eventually() {
+        let attempts = AtomicU32::new(0);
+        let result = send_with_retries(UserId(1), Duration::ZERO, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(DmError::Transient)
+        })
+        .await;
+        assert!(matches!(result, Err(DmError::Transient)));
+        assert_eq!(attempts.load(Ordering::SeqCst), MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_not_retried() {
+        let attempts = AtomicU32::new(0);
+        let result = send_with_retries(UserId(1), Duration::ZERO, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(DmError::Other)
+        })
+        .await;
+        assert!(matches!(result, Err(DmError::Other)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn names_are_joined_naturally() {
+        assert_eq!(join_names(&["A"]), "A");
+        assert_eq!(join_names(&["A", "B"]), "A and B");
+        assert_eq!(join_names(&["A", "B", "C"]), "A, B and C");
+    }
+}
@@ -0,0 +1,136 @@
+use crate::model::{unix_now, VoiceSession};
+use crate::state::PCState;
+
+use log::info;
+use serenity::cache::Cache;
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use std::collections::HashMap;
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+// Starts sessions for everyone who is in a voice channel once the cache is ready, and drops those
+// of users that left while the bot was offline.
+pub fn reconcile(state: &PCState, cache: &Cache) {
+    let now = unix_now();
+
+    for guild_id in cache.guilds() {
+        let voice_channels = match cache.guild_field(guild_id, |g| {
+            g.voice_states
+                .iter()
+                .filter_map(|(&user_id, vs)| vs.channel_id.map(|c| (user_id, c)))
+                .collect::<HashMap<_, _>>()
+        }) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        // Tracked channels are decided under the same lock as the sessions are updated, so that
+        // an opt-out in between can't leave a session behind.
+        let changed = state.read(|d| {
+            let voice_channels = voice_channels
+                .iter()
+                .filter(|&(&user_id, &channel_id)| {
+                    d.is_voice_tracked(guild_id, user_id, channel_id)
+                })
+                .map(|(&user_id, &channel_id)| (user_id, channel_id))
+                .collect::<HashMap<_, _>>();
+            state.update_sessions(|s| s.reconcile(guild_id, &voice_channels, now))
+        });
+
+        if changed {
+            info!(
+                "[sessions] Reconciled voice sessions for guild {}",
+                guild_id
+            );
+        }
+    }
+}
+
+// Updates the user's voice session after they joined, moved to or left (`None`) a channel.
+pub fn voice_channel_changed(
+    state: &PCState,
+    guild_id: GuildId,
+    user_id: UserId,
+    channel_id: Option<ChannelId>,
+) {
+    state.read(|d| {
+        let channel_id = channel_id.filter(|&c| d.is_voice_tracked(guild_id, user_id, c));
+        state.update_sessions(|s| s.update_voice_session(guild_id, user_id, channel_id, unix_now()))
+    });
+}
+
+// How many seconds of the session fall between `from` and `to`.
+pub fn overlap(session: &VoiceSession, from: u64, to: u64) -> u64 {
+    session.end.min(to).saturating_sub(session.start.max(from))
+}
+
+// Monday 00:00 UTC of the current week.
+pub fn start_of_week(now: u64) -> u64 {
+    let days = now / DAY_SECS;
+    // 1970-01-01 was a Thursday.
+    let weekday = (days + 3) % 7;
+    (days - weekday) * DAY_SECS
+}
+
+// The most recent night that is over, from 18:00 to 06:00 UTC.
+pub fn last_night(now: u64) -> (u64, u64) {
+    let mut end = now - now % DAY_SECS + 6 * 60 * 60;
+    if end > now {
+        end -= DAY_SECS;
+    }
+    (end - 12 * 60 * 60, end)
+}
+
+pub fn format_duration(secs: u64) -> String {
+    let minutes = secs / 60;
+    match minutes / 60 {
+        0 => format!("{}m", minutes),
+        hours => format!("{}h {:02}m", hours, minutes % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serenity::model::id::{ChannelId, UserId};
+
+    const HOUR_SECS: u64 = 60 * 60;
+    // 2024-03-04, a Monday.
+    const MONDAY: u64 = 19786 * DAY_SECS;
+
+    #[test]
+    fn weeks_start_on_monday() {
+        assert_eq!(start_of_week(MONDAY), MONDAY);
+        assert_eq!(start_of_week(MONDAY + 2 * DAY_SECS + 5 * HOUR_SECS), MONDAY);
+        assert_eq!(start_of_week(MONDAY + 7 * DAY_SECS - 1), MONDAY);
+        assert_eq!(start_of_week(MONDAY + 7 * DAY_SECS), MONDAY + 7 * DAY_SECS);
+    }
+
+    #[test]
+    fn last_night_is_the_last_one_that_ended() {
+        let night = (MONDAY - 6 * HOUR_SECS, MONDAY + 6 * HOUR_SECS);
+        assert_eq!(last_night(MONDAY + 6 * HOUR_SECS), night);
+        assert_eq!(last_night(MONDAY + 23 * HOUR_SECS), night);
+        // Still Monday night at 3:00 on Tuesday, so the previous one is meant.
+        assert_eq!(last_night(MONDAY + DAY_SECS + 3 * HOUR_SECS), night);
+        assert_eq!(
+            last_night(MONDAY + DAY_SECS + 6 * HOUR_SECS),
+            (night.0 + DAY_SECS, night.1 + DAY_SECS)
+        );
+    }
+
+    #[test]
+    fn overlap_is_clamped_to_the_range() {
+        let session = VoiceSession {
+            user: UserId(1),
+            channel: ChannelId(2),
+            start: 100,
+            end: 200,
+        };
+        assert_eq!(overlap(&session, 0, 1000), 100);
+        assert_eq!(overlap(&session, 150, 1000), 50);
+        assert_eq!(overlap(&session, 120, 180), 60);
+        assert_eq!(overlap(&session, 200, 300), 0);
+        assert_eq!(overlap(&session, 0, 50), 0);
+    }
+}
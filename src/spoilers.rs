@@ -0,0 +1,75 @@
+use serenity::{
+    model::{id::ChannelId, webhook::Webhook},
+    prelude::Context,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+// Name of the webhooks the bot creates to repost messages as their authors.
+const WEBHOOK_NAME: &str = "Problem Child";
+
+// Which daily puzzle result posts get reposted with their results hidden behind spoiler tags
+// instead of just having their embeds suppressed. `None` matches any game or channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpoilerRule {
+    pub game: Option<&'static str>,
+    pub channel: Option<ChannelId>,
+}
+
+impl SpoilerRule {
+    pub fn matches(&self, game: &str, channel_id: ChannelId) -> bool {
+        self.game.is_none_or(|g| g == game) && self.channel.is_none_or(|c| c == channel_id)
+    }
+}
+
+impl fmt::Display for SpoilerRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.game {
+            Some(game) => write!(f, "{}", game)?,
+            None => write!(f, "all games")?,
+        }
+        match self.channel {
+            Some(channel_id) => write!(f, " in <#{}>", channel_id),
+            None => write!(f, " in all channels"),
+        }
+    }
+}
+
+// The bot's webhooks, by channel, so that they only have to be looked up once.
+#[derive(Default)]
+pub struct Webhooks {
+    webhooks: Mutex<HashMap<ChannelId, Webhook>>,
+}
+
+impl Webhooks {
+    // Returns the bot's webhook for the channel, creating one if there is none yet.
+    pub async fn get(&self, ctx: &Context, channel_id: ChannelId) -> serenity::Result<Webhook> {
+        if let Some(webhook) = self.webhooks.lock().unwrap().get(&channel_id) {
+            return Ok(webhook.clone());
+        }
+
+        let own_id = ctx.cache.current_user_id();
+        let existing = channel_id
+            .webhooks(&ctx.http)
+            .await?
+            .into_iter()
+            .find(|w| w.token.is_some() && w.user.as_ref().is_some_and(|u| u.id == own_id));
+
+        let webhook = match existing {
+            Some(w) => w,
+            None => channel_id.create_webhook(&ctx.http, WEBHOOK_NAME).await?,
+        };
+
+        self.webhooks
+            .lock()
+            .unwrap()
+            .insert(channel_id, webhook.clone());
+        Ok(webhook)
+    }
+
+    // Makes the next `get` look the channel's webhook up again, e.g. after it was deleted.
+    pub fn forget(&self, channel_id: ChannelId) {
+        self.webhooks.lock().unwrap().remove(&channel_id);
+    }
+}
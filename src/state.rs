@@ -0,0 +1,519 @@
+use crate::metrics;
+use crate::model::{PCData, RuntimeData, VoiceSessions};
+use crate::storage;
+
+use log::{debug, error, info, warn};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{Mutex, Notify};
+
+// How long to wait after a change before writing to disk, so that bursts of changes only result
+// in a single write.
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+// How often to check whether config/pc_data.json was edited by someone other than the bot.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Cheaply clonable handle to the bot's persistent data.
+//
+// The data itself is behind a synchronous lock that is only ever held inside the closures passed
+// to `read` and `update`. Since those can't await, no reader (in particular the voice
+// notification logic) ever has to wait for a Discord API call or disk I/O to finish.
+//
+// Voice sessions and other runtime data are kept apart from the rest in
+// config/voice_sessions.json and config/runtime_data.json, which are written the same way but never
+// reloaded. Their locks may be taken inside `read` and `update`, but not the other way around.
+#[derive(Clone)]
+pub struct PCState {
+    data: Arc<RwLock<PCData>>,
+    sessions: Arc<RwLock<VoiceSessions>>,
+    runtime: Arc<RwLock<RuntimeData>>,
+    persistence: Arc<Persistence>,
+}
+
+struct Persistence {
+    // Directory holding the files, see `storage::CONFIG_DIR`.
+    dir: PathBuf,
+    // Whether there are changes that have not been written to disk yet.
+    dirty: AtomicBool,
+    sessions_dirty: AtomicBool,
+    runtime_dirty: AtomicBool,
+    // Wakes up the persister task after a change.
+    changed: Notify,
+    // Serializes all file access, so that an older snapshot can never overwrite a newer one and
+    // reloads never race with saves.
+    file_lock: Mutex<FileStatus>,
+    sessions_file_lock: Mutex<()>,
+    runtime_file_lock: Mutex<()>,
+}
+
+struct FileStatus {
+    // Modification time of config/pc_data.json as of the last time the bot read or wrote it. If
+    // the file's current modification time differs, it was edited externally.
+    known_mtime: Option<SystemTime>,
+    // Modification time of an external edit that failed to load, so it is only reported once.
+    rejected_mtime: Option<SystemTime>,
+    // Set when the file was edited externally while there were unsaved changes. Until a reload is
+    // forced with SIGHUP, the file is left alone and the data is saved to
+    // config/pc_data.unsaved.json instead.
+    conflict: bool,
+}
+
+impl PCState {
+    pub fn new(
+        dir: PathBuf,
+        data: PCData,
+        sessions: VoiceSessions,
+        runtime: RuntimeData,
+    ) -> PCState {
+        let known_mtime = storage::modified_time(&dir).unwrap_or_else(|err| {
+            warn!("Error reading modification time of pc_data.json: {:?}", err);
+            None
+        });
+
+        PCState {
+            data: Arc::new(RwLock::new(data)),
+            sessions: Arc::new(RwLock::new(sessions)),
+            runtime: Arc::new(RwLock::new(runtime)),
+            persistence: Arc::new(Persistence {
+                dir,
+                dirty: AtomicBool::new(false),
+                sessions_dirty: AtomicBool::new(false),
+                runtime_dirty: AtomicBool::new(false),
+                changed: Notify::new(),
+                file_lock: Mutex::new(FileStatus {
+                    known_mtime,
+                    rejected_mtime: None,
+                    conflict: false,
+                }),
+                sessions_file_lock: Mutex::new(()),
+                runtime_file_lock: Mutex::new(()),
+            }),
+        }
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&PCData) -> R) -> R {
+        f(&self.data.read().unwrap())
+    }
+
+    // Modifies the data. `f` must return whether it actually changed anything; only then is the
+    // data scheduled to be written to disk.
+    pub fn update(&self, f: impl FnOnce(&mut PCData) -> bool) -> bool {
+        let changed = f(&mut self.data.write().unwrap());
+
+        if changed {
+            self.persistence.dirty.store(true, Ordering::SeqCst);
+            self.persistence.changed.notify_one();
+        }
+
+        changed
+    }
+
+    pub fn read_sessions<R>(&self, f: impl FnOnce(&VoiceSessions) -> R) -> R {
+        f(&self.sessions.read().unwrap())
+    }
+
+    pub fn update_sessions(&self, f: impl FnOnce(&mut VoiceSessions) -> bool) -> bool {
+        let changed = f(&mut self.sessions.write().unwrap());
+
+        if changed {
+            self.persistence
+                .sessions_dirty
+                .store(true, Ordering::SeqCst);
+            self.persistence.changed.notify_one();
+        }
+
+        changed
+    }
+
+    pub fn read_runtime<R>(&self, f: impl FnOnce(&RuntimeData) -> R) -> R {
+        f(&self.runtime.read().unwrap())
+    }
+
+    pub fn update_runtime(&self, f: impl FnOnce(&mut RuntimeData) -> bool) -> bool {
+        let changed = f(&mut self.runtime.write().unwrap());
+
+        if changed {
+            self.persistence.runtime_dirty.store(true, Ordering::SeqCst);
+            self.persistence.changed.notify_one();
+        }
+
+        changed
+    }
+
+    // Runs forever, writing changes to disk shortly after they happen.
+    pub async fn run_persister(self) {
+        loop {
+            self.persistence.changed.notified().await;
+            tokio::time::sleep(SAVE_DELAY).await;
+            self.flush().await;
+        }
+    }
+
+    // Runs forever, reloading config/pc_data.json whenever it is edited externally.
+    pub async fn run_file_watcher(self) {
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let status = self.persistence.file_lock.lock().await;
+            let current_mtime = match self.modified_time().await {
+                // If the file was deleted, the next save will simply recreate it.
+                None => continue,
+                mtime => mtime,
+            };
+            if status.conflict
+                || current_mtime == status.known_mtime
+                || current_mtime == status.rejected_mtime
+            {
+                continue;
+            }
+            drop(status);
+
+            info!("pc_data.json was modified externally, reloading it.");
+            self.reload(false).await;
+        }
+    }
+
+    // Immediately writes any pending changes to disk.
+    pub async fn flush(&self) {
+        self.flush_data().await;
+        self.flush_sessions().await;
+        self.flush_runtime().await;
+    }
+
+    async fn flush_data(&self) {
+        let mut status = self.persistence.file_lock.lock().await;
+
+        if !self.persistence.dirty.load(Ordering::SeqCst) {
+            return;
+        }
+
+        // Neither the external edit nor the unsaved changes may be lost, so don't write over the
+        // file until someone has merged the two.
+        let current_mtime = self.modified_time().await;
+        if !status.conflict && current_mtime.is_some() && current_mtime != status.known_mtime {
+            error!(
+                "Conflict: pc_data.json was modified externally while there were unsaved changes. \
+                 Not loading it and saving to pc_data.unsaved.json instead until the two have \
+                 been merged into pc_data.json and the bot has been sent SIGHUP."
+            );
+            status.conflict = true;
+        }
+
+        self.persistence.dirty.store(false, Ordering::SeqCst);
+
+        // Taking the snapshot only after acquiring `file_lock` means whichever save runs last
+        // also writes the most recent data.
+        let snapshot = self.read(PCData::clone);
+        let conflict = status.conflict;
+        let dir = self.persistence.dir.clone();
+
+        debug!("Writing pc_data.json");
+        let start = Instant::now();
+        let result = tokio::task::spawn_blocking(move || {
+            if conflict {
+                storage::save_unsaved_data(&dir, &snapshot)
+            } else {
+                storage::save_data(&dir, &snapshot)
+            }
+        })
+        .await
+        .map_err(|e| e.into())
+        .and_then(|r| r);
+        metrics::STORAGE_SAVE_DURATION.observe(start.elapsed());
+
+        match result {
+            Ok(()) if conflict => (),
+            Ok(()) => status.known_mtime = self.modified_time().await,
+            Err(err) => {
+                error!("Error saving pc_data.json: {:?}", err);
+                metrics::STORAGE_SAVE_FAILURES.inc();
+                // Have the persister try again later.
+                self.persistence.dirty.store(true, Ordering::SeqCst);
+                self.persistence.changed.notify_one();
+            }
+        }
+    }
+
+    async fn flush_sessions(&self) {
+        let _lock = self.persistence.sessions_file_lock.lock().await;
+
+        if !self
+            .persistence
+            .sessions_dirty
+            .swap(false, Ordering::SeqCst)
+        {
+            return;
+        }
+
+        let snapshot = self.read_sessions(VoiceSessions::clone);
+        let dir = self.persistence.dir.clone();
+
+        debug!("Writing voice_sessions.json");
+        let start = Instant::now();
+        let result = tokio::task::spawn_blocking(move || storage::save_sessions(&dir, &snapshot))
+            .await
+            .map_err(|e| e.into())
+            .and_then(|r| r);
+        metrics::STORAGE_SAVE_DURATION.observe(start.elapsed());
+
+        if let Err(err) = result {
+            error!("Error saving voice_sessions.json: {:?}", err);
+            metrics::STORAGE_SAVE_FAILURES.inc();
+            self.persistence
+                .sessions_dirty
+                .store(true, Ordering::SeqCst);
+            self.persistence.changed.notify_one();
+        }
+    }
+
+    async fn flush_runtime(&self) {
+        let _lock = self.persistence.runtime_file_lock.lock().await;
+
+        if !self.persistence.runtime_dirty.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let snapshot = self.read_runtime(RuntimeData::clone);
+        let dir = self.persistence.dir.clone();
+
+        debug!("Writing runtime_data.json");
+        let start = Instant::now();
+        let result = tokio::task::spawn_blocking(move || storage::save_runtime(&dir, &snapshot))
+            .await
+            .map_err(|e| e.into())
+            .and_then(|r| r);
+        metrics::STORAGE_SAVE_DURATION.observe(start.elapsed());
+
+        if let Err(err) = result {
+            error!("Error saving runtime_data.json: {:?}", err);
+            metrics::STORAGE_SAVE_FAILURES.inc();
+            self.persistence.runtime_dirty.store(true, Ordering::SeqCst);
+            self.persistence.changed.notify_one();
+        }
+    }
+
+    // Re-reads config/pc_data.json and, if it is valid, replaces the in-memory data with it.
+    //
+    // If there are unsaved changes, the reload is refused and they are saved to
+    // config/pc_data.unsaved.json, see `flush_data`. Forcing the reload (what SIGHUP does) resolves
+    // such a conflict in favor of the file, after writing any unsaved changes to disk first.
+    pub async fn reload(&self, force: bool) {
+        if force {
+            self.flush_data().await;
+        }
+
+        let mut status = self.persistence.file_lock.lock().await;
+        if status.conflict && !force {
+            return;
+        }
+        let mtime = self.modified_time().await;
+
+        let dir = self.persistence.dir.clone();
+        let result = tokio::task::spawn_blocking(move || storage::load_data(&dir))
+            .await
+            .map_err(|e| e.into())
+            .and_then(|r| r);
+
+        let mut new_data = match result {
+            Ok(data) => data,
+            Err(err) => {
+                error!(
+                    "Error reloading pc_data.json, keeping the current data: {:?}",
+                    err
+                );
+                status.rejected_mtime = mtime;
+                return;
+            }
+        };
+
+        if !force && self.persistence.dirty.load(Ordering::SeqCst) {
+            drop(status);
+            self.flush_data().await;
+            return;
+        }
+
+        let had_conflict = std::mem::replace(&mut status.conflict, false);
+        let had_unsaved_changes = self.persistence.dirty.swap(false, Ordering::SeqCst);
+        let (changes, kept_opt_outs, new_opt_outs) = {
+            let mut data = self.data.write().unwrap();
+            let kept_opt_outs = data.keep_voice_tracking_opt_outs(&mut new_data);
+            let new_opt_outs = new_data
+                .voice_tracking_opt_outs()
+                .filter(|&u| !data.is_voice_tracking_opted_out(u))
+                .collect::<Vec<_>>();
+            let changes = data.describe_changes(&new_data);
+            *data = new_data;
+            (changes, kept_opt_outs, new_opt_outs)
+        };
+
+        // Users opted out in the file get their recorded sessions forgotten, just like with the
+        // command.
+        self.update_sessions(|s| {
+            let mut changed = false;
+            for &user_id in &new_opt_outs {
+                changed |= s.forget_user(user_id);
+            }
+            changed
+        });
+        if !kept_opt_outs.is_empty() {
+            // Written back to the file by the next save.
+            self.persistence.dirty.store(true, Ordering::SeqCst);
+            self.persistence.changed.notify_one();
+        }
+
+        status.known_mtime = mtime;
+        status.rejected_mtime = None;
+
+        if had_conflict {
+            warn!(
+                "Resolved the conflict by loading pc_data.json. Changes that were saved to \
+                 pc_data.unsaved.json but not merged into it have been discarded."
+            );
+        }
+        if had_unsaved_changes {
+            // Only possible if something changed during the forced reload, or saving failed.
+            warn!(
+                "There were unsaved changes when pc_data.json was reloaded. Changes below that \
+                 weren't made in the file have been discarded."
+            );
+        }
+
+        for user_id in kept_opt_outs {
+            warn!(
+                "pc_data.json doesn't have the voice tracking opt-out of {}, keeping it anyway. \
+                 Only the user can opt back in.",
+                user_id
+            );
+        }
+
+        if changes.is_empty() {
+            info!("Reloaded pc_data.json, nothing changed.");
+        } else {
+            info!("Reloaded pc_data.json with {} change(s):", changes.len());
+            for change in changes {
+                info!("  {}", change);
+            }
+        }
+    }
+
+    async fn modified_time(&self) -> Option<SystemTime> {
+        let dir = self.persistence.dir.clone();
+        tokio::task::spawn_blocking(move || storage::modified_time(&dir))
+            .await
+            .map_err(|e| e.into())
+            .and_then(|r| r)
+            .unwrap_or_else(|err: std::io::Error| {
+                warn!("Error reading modification time of pc_data.json: {:?}", err);
+                None
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serenity::model::id::{GuildId, UserId};
+    use std::fs::{self, File};
+    use std::path::Path;
+
+    const GUILD: GuildId = GuildId(10);
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("problem-child-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_state(dir: &Path) -> PCState {
+        storage::save_data(dir, &PCData::default()).unwrap();
+        let data = storage::load_data(dir).unwrap();
+        PCState::new(
+            dir.to_path_buf(),
+            data,
+            VoiceSessions::default(),
+            RuntimeData::default(),
+        )
+    }
+
+    // Adds `admin` to pc_data.json the way someone editing the file would. The modification time
+    // is moved forward explicitly, since the edit may happen within the file system's timestamp
+    // granularity of the bot's own write.
+    fn edit_externally(dir: &Path, admin: UserId) {
+        let mut data = storage::load_data(dir).unwrap();
+        data.add_admin(admin, GUILD, false);
+        storage::save_data(dir, &data).unwrap();
+
+        let file = File::options()
+            .write(true)
+            .open(dir.join("pc_data.json"))
+            .unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn runtime_changes_never_conflict_with_edits() {
+        let dir = test_dir("runtime");
+        let state = test_state(&dir);
+
+        state.update_runtime(|r| r.record_digest_sent(UserId(1), 1_000));
+        edit_externally(&dir, UserId(2));
+        state.flush().await;
+        state.reload(false).await;
+
+        assert!(state.read(|d| d.is_admin(UserId(2), GUILD)));
+        assert!(!dir.join("pc_data.unsaved.json").exists());
+        let runtime = storage::load_runtime(&dir).unwrap();
+        assert!(runtime.due_digests([UserId(1)].into_iter(), 500).is_empty());
+        assert!(state.read_runtime(|r| r.due_digests([UserId(1)].into_iter(), 500).is_empty()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn conflicting_edits_are_kept_apart_until_a_forced_reload() {
+        let dir = test_dir("conflict");
+        let state = test_state(&dir);
+
+        state.update(|d| d.add_admin(UserId(1), GUILD, false));
+        edit_externally(&dir, UserId(2));
+        state.flush().await;
+
+        // Neither change is lost: the bot's goes to pc_data.unsaved.json, the edit stays put.
+        let unsaved: PCData =
+            serde_json::from_reader(File::open(dir.join("pc_data.unsaved.json")).unwrap()).unwrap();
+        assert!(unsaved.is_admin(UserId(1), GUILD));
+        assert!(!unsaved.is_admin(UserId(2), GUILD));
+        let file = storage::load_data(&dir).unwrap();
+        assert!(file.is_admin(UserId(2), GUILD));
+        assert!(!file.is_admin(UserId(1), GUILD));
+
+        // Until the reload is forced, the edit isn't loaded and further changes aren't written
+        // to pc_data.json either.
+        state.reload(false).await;
+        assert!(!state.read(|d| d.is_admin(UserId(2), GUILD)));
+        state.update(|d| d.add_admin(UserId(3), GUILD, false));
+        state.flush().await;
+        assert!(!storage::load_data(&dir).unwrap().is_admin(UserId(3), GUILD));
+
+        state.reload(true).await;
+        assert!(state.read(|d| d.is_admin(UserId(2), GUILD)));
+        assert!(!state.read(|d| d.is_admin(UserId(1), GUILD)));
+
+        // Changes are saved to pc_data.json again.
+        state.update(|d| d.add_admin(UserId(4), GUILD, false));
+        state.flush().await;
+        let file = storage::load_data(&dir).unwrap();
+        assert!(file.is_admin(UserId(2), GUILD));
+        assert!(file.is_admin(UserId(4), GUILD));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,16 +1,25 @@
-use crate::model::PCData;
+use crate::model::{PCData, RuntimeData, VoiceSessions};
 
 use log::info;
+use serde::Serialize;
 use std::error::Error;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, ErrorKind};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 
-// Simply loads configuration data from config/pc_data.json relative to the current workding
-// directory.
+// Where the bot keeps its files, relative to the current working directory. Every function below
+// takes the directory to use, so that tests can point them somewhere else.
+pub const CONFIG_DIR: &str = "config";
+
+// Simply loads configuration data from pc_data.json in `dir`.
 // If the file doesn't exist, returns a default configuration instead.
 // On any other errors, returns the error instead.
-pub fn load_data() -> Result<PCData, Box<dyn Error>> {
-    match File::open("config/pc_data.json") {
+pub fn load_data(dir: &Path) -> Result<PCData, Box<dyn Error + Send + Sync>> {
+    let path = dir.join("pc_data.json");
+    match File::open(&path) {
         Ok(file) => {
             let reader = BufReader::new(file);
             let data = serde_json::from_reader(reader)?;
@@ -18,7 +27,10 @@ pub fn load_data() -> Result<PCData, Box<dyn Error>> {
         }
         Err(err) => match err.kind() {
             ErrorKind::NotFound => {
-                info!("config/pc_data.json file not found, proceeding with new default data.");
+                info!(
+                    "{} file not found, proceeding with new default data.",
+                    path.display()
+                );
                 Ok(PCData::default())
             }
             _ => Err(Box::new(err)),
@@ -26,15 +38,94 @@ pub fn load_data() -> Result<PCData, Box<dyn Error>> {
     }
 }
 
-pub fn save_data(data: &PCData) -> Result<(), Box<dyn Error>> {
-    OpenOptions::new()
+pub fn save_data(dir: &Path, data: &PCData) -> Result<(), Box<dyn Error + Send + Sync>> {
+    write_json(data, &dir.join("pc_data.json"))
+}
+
+// Used instead of `save_data` while pc_data.json has external edits that conflict with changes
+// made by the bot, so that neither gets lost.
+pub fn save_unsaved_data(dir: &Path, data: &PCData) -> Result<(), Box<dyn Error + Send + Sync>> {
+    write_json(data, &dir.join("pc_data.unsaved.json"))
+}
+
+// Loads voice sessions from voice_sessions.json in `dir`. If the file doesn't exist, there are no
+// sessions yet.
+pub fn load_sessions(dir: &Path) -> Result<VoiceSessions, Box<dyn Error + Send + Sync>> {
+    match File::open(dir.join("voice_sessions.json")) {
+        Ok(file) => {
+            let reader = BufReader::new(file);
+            let sessions = serde_json::from_reader(reader)?;
+            Ok(sessions)
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(VoiceSessions::default()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+pub fn save_sessions(
+    dir: &Path,
+    sessions: &VoiceSessions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    write_json(sessions, &dir.join("voice_sessions.json"))
+}
+
+// Loads puzzle results, idle moves, failed DMs and sent digests from runtime_data.json in `dir`.
+// If the file doesn't exist, nothing was recorded yet.
+pub fn load_runtime(dir: &Path) -> Result<RuntimeData, Box<dyn Error + Send + Sync>> {
+    match File::open(dir.join("runtime_data.json")) {
+        Ok(file) => {
+            let reader = BufReader::new(file);
+            let runtime = serde_json::from_reader(reader)?;
+            Ok(runtime)
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(RuntimeData::default()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+pub fn save_runtime(dir: &Path, runtime: &RuntimeData) -> Result<(), Box<dyn Error + Send + Sync>> {
+    write_json(runtime, &dir.join("runtime_data.json"))
+}
+
+// Writes to a temporary file first and then renames it over the actual file, so that the bot
+// being stopped in the middle of a write can't leave a truncated file behind.
+fn write_json(data: &impl Serialize, path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let file = OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open("config/pc_data.json")
-        .and_then(|file| {
-            let writer = BufWriter::new(file);
-            serde_json::to_writer_pretty(writer, data).map_err(|e| e.into())
-        })
-        .map_err(|e| e.into())
+        .open(&tmp_path)?;
+
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, data)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Returns when pc_data.json in `dir` was last modified, or `None` if it doesn't exist.
+pub fn modified_time(dir: &Path) -> io::Result<Option<SystemTime>> {
+    match fs::metadata(dir.join("pc_data.json")) {
+        Ok(metadata) => metadata.modified().map(Some),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// Checks that files can still be created in `dir`, which saving relies on. Every check uses its
+// own file, since health checks can run concurrently.
+pub fn check_writable(dir: &Path) -> io::Result<()> {
+    static CHECKS: AtomicU64 = AtomicU64::new(0);
+    let path = dir.join(format!(
+        ".write_check.{}.{}",
+        process::id(),
+        CHECKS.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    File::create(&path)?.sync_all()?;
+    fs::remove_file(&path)
 }
@@ -0,0 +1,236 @@
+use regex::Regex;
+use serenity::model::channel::Embed;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::OnceLock;
+use url::Url;
+
+// A rule for which links posted in a guild get their embeds suppressed.
+#[derive(Debug, Clone)]
+pub enum SuppressionRule {
+    // The URL has to match exactly, except for a trailing slash at the end of its path.
+    Exact(String),
+    // The URL's host has to be the domain or one of its subdomains.
+    Domain(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+// Whether a message's embeds are suppressed when any or only when all of its links match a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SuppressionMode {
+    #[default]
+    Any,
+    All,
+}
+
+impl SuppressionMode {
+    pub fn parse(name: &str) -> Option<SuppressionMode> {
+        match name {
+            "any" => Some(SuppressionMode::Any),
+            "all" => Some(SuppressionMode::All),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SuppressionMode::Any => "any",
+            SuppressionMode::All => "all",
+        }
+    }
+}
+
+// Used by guilds that haven't configured their own rules.
+pub fn default_rules() -> &'static [SuppressionRule] {
+    static DEFAULT_RULES: OnceLock<Vec<SuppressionRule>> = OnceLock::new();
+
+    DEFAULT_RULES.get_or_init(|| {
+        [
+            "https://heardle.app/",
+            "https://www.heardle.app/",
+            "https://framed.wtf/",
+            "https://moviedle.app/",
+            "https://oec.world/en/tradle",
+            "https://posterdle.com/",
+            "https://histordle.com/yeardle",
+        ]
+        .into_iter()
+        .map(|url| SuppressionRule::Exact(url.to_string()))
+        .collect()
+    })
+}
+
+impl SuppressionRule {
+    pub const KINDS: [&'static str; 4] = ["exact", "domain", "prefix", "regex"];
+
+    pub fn parse(kind: &str, pattern: &str) -> Result<SuppressionRule, String> {
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err("The pattern must not be empty".to_string());
+        }
+
+        match kind {
+            "exact" => Ok(SuppressionRule::Exact(pattern.to_string())),
+            "domain" => Ok(SuppressionRule::Domain(
+                pattern.trim_end_matches('.').to_lowercase(),
+            )),
+            "prefix" => Ok(SuppressionRule::Prefix(pattern.to_string())),
+            "regex" => Regex::new(pattern)
+                .map(SuppressionRule::Regex)
+                .map_err(|err| format!("Invalid regex: {}", err)),
+            _ => Err(format!(
+                "Unknown kind of rule {:?}, expected one of {}",
+                kind,
+                SuppressionRule::KINDS.join(", ")
+            )),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SuppressionRule::Exact(_) => "exact",
+            SuppressionRule::Domain(_) => "domain",
+            SuppressionRule::Prefix(_) => "prefix",
+            SuppressionRule::Regex(_) => "regex",
+        }
+    }
+
+    pub fn pattern(&self) -> &str {
+        match self {
+            SuppressionRule::Exact(pattern)
+            | SuppressionRule::Domain(pattern)
+            | SuppressionRule::Prefix(pattern) => pattern,
+            SuppressionRule::Regex(regex) => regex.as_str(),
+        }
+    }
+
+    pub fn matches(&self, url: &str) -> bool {
+        match self {
+            SuppressionRule::Exact(exact) => {
+                without_trailing_slash(url) == without_trailing_slash(exact)
+            }
+            SuppressionRule::Domain(domain) => match Url::parse(url) {
+                Ok(url) => url.host_str().is_some_and(|host| {
+                    host == domain
+                        || host
+                            .strip_suffix(domain.as_str())
+                            .is_some_and(|subdomain| subdomain.ends_with('.'))
+                }),
+                Err(_) => false,
+            },
+            SuppressionRule::Prefix(prefix) => url.starts_with(prefix.as_str()),
+            SuppressionRule::Regex(regex) => regex.is_match(url),
+        }
+    }
+}
+
+// Drops a trailing slash from the URL's path, so that e.g. `https://heardle.app/` and
+// `https://heardle.app` are the same URL.
+fn without_trailing_slash(url: &str) -> Cow<'_, str> {
+    let path_end = url.find(['?', '#']).unwrap_or(url.len());
+    match url[..path_end].strip_suffix('/') {
+        Some(path) => Cow::Owned(format!("{}{}", path, &url[path_end..])),
+        None => Cow::Borrowed(url),
+    }
+}
+
+// Rules are equal if they were created from the same kind and pattern.
+impl PartialEq for SuppressionRule {
+    fn eq(&self, other: &SuppressionRule) -> bool {
+        self.kind() == other.kind() && self.pattern() == other.pattern()
+    }
+}
+
+impl fmt::Display for SuppressionRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.kind(), self.pattern())
+    }
+}
+
+// Collects the links in a message that can have embeds: those in its content and the URLs of
+// the embeds Discord has attached so far. Links wrapped in `<>` are skipped, since Discord doesn't
+// embed them anyway.
+pub fn message_links<'a>(content: &'a str, embeds: &'a [Embed]) -> Vec<&'a str> {
+    static LINK: OnceLock<Regex> = OnceLock::new();
+    let link = LINK.get_or_init(|| Regex::new(r"https?://[^\s<>]+").unwrap());
+
+    let mut links = link
+        .find_iter(content)
+        .filter(|m| !content[..m.start()].ends_with('<'))
+        .map(|m| m.as_str().trim_end_matches(['.', ',', ':', ';', '!', '?']))
+        .chain(embeds.iter().filter_map(|embed| embed.url.as_deref()))
+        .collect::<Vec<_>>();
+    links.sort_unstable();
+    links.dedup();
+    links
+}
+
+pub fn should_suppress(rules: &[SuppressionRule], mode: SuppressionMode, links: &[&str]) -> bool {
+    let matches = |link: &&str| rules.iter().any(|rule| rule.matches(link));
+
+    match mode {
+        SuppressionMode::Any => links.iter().any(matches),
+        SuppressionMode::All => !links.is_empty() && links.iter().all(matches),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(kind: &str, pattern: &str) -> SuppressionRule {
+        SuppressionRule::parse(kind, pattern).unwrap()
+    }
+
+    #[test]
+    fn exact_rules_match_only_the_url() {
+        let rule = rule("exact", "https://heardle.app/");
+        assert!(rule.matches("https://heardle.app/"));
+        assert!(!rule.matches("https://heardle.app/#245"));
+        assert!(!rule.matches("https://www.heardle.app/"));
+    }
+
+    #[test]
+    fn exact_rules_ignore_trailing_slashes() {
+        let root = rule("exact", "https://heardle.app/");
+        assert!(root.matches("https://heardle.app"));
+
+        let path = rule("exact", "https://oec.world/en/tradle");
+        assert!(path.matches("https://oec.world/en/tradle/"));
+        assert!(path.matches("https://oec.world/en/tradle"));
+        assert!(!path.matches("https://oec.world/en/tradle/?page=2"));
+
+        let query = rule("exact", "https://example.com/?a=1");
+        assert!(query.matches("https://example.com?a=1"));
+        assert!(!query.matches("https://example.com/?a=1/"));
+    }
+
+    #[test]
+    fn domain_rules_match_subdomains() {
+        let rule = rule("domain", "Heardle.app.");
+        assert!(rule.matches("https://heardle.app/"));
+        assert!(rule.matches("https://www.heardle.app/some/path"));
+        assert!(!rule.matches("https://notheardle.app/"));
+        assert!(!rule.matches("https://heardle.app.example.com/"));
+        assert!(!rule.matches("not a url"));
+    }
+
+    #[test]
+    fn prefix_and_regex_rules() {
+        let prefix = rule("prefix", "https://oec.world/en/");
+        assert!(prefix.matches("https://oec.world/en/tradle"));
+        assert!(!prefix.matches("https://oec.world/de/tradle"));
+
+        let regex = rule("regex", r"^https://\w+dle\.(app|com)/$");
+        assert!(regex.matches("https://posterdle.com/"));
+        assert!(!regex.matches("https://posterdle.com/archive"));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(SuppressionRule::parse("exact", "  ").is_err());
+        assert!(SuppressionRule::parse("glob", "*").is_err());
+        assert!(SuppressionRule::parse("regex", "(").is_err());
+    }
+}
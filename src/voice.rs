@@ -0,0 +1,145 @@
+use serenity::model::{
+    channel::{Channel, ChannelType},
+    id::{ChannelId, UserId},
+    voice::VoiceState,
+};
+
+use std::collections::{HashMap, HashSet};
+
+// Where a user currently is relative to a voice channel someone just joined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoiceLocation {
+    NotInVoice,
+    InJoinedChannel,
+    InAfkChannel,
+    // Connected to something other than a regular voice channel, like a stage channel, which
+    // doesn't count as being busy.
+    InOtherKindOfChannel,
+    InOtherChannel,
+}
+
+// Looks up a user's location using the guild's `voice_states` map, which the cache keeps
+// up to date for every user currently connected to voice. This is a single hash lookup per
+// user, instead of going through every channel in the guild and collecting its members.
+pub fn locate_user<V, F>(
+    voice_states: &HashMap<UserId, VoiceState>,
+    user_id: UserId,
+    joined_channel: ChannelId,
+    is_voice_channel: V,
+    is_afk_channel: F,
+) -> VoiceLocation
+where
+    V: Fn(ChannelId) -> bool,
+    F: Fn(ChannelId) -> bool,
+{
+    match voice_states.get(&user_id).and_then(|s| s.channel_id) {
+        None => VoiceLocation::NotInVoice,
+        Some(c) if c == joined_channel => VoiceLocation::InJoinedChannel,
+        Some(c) if is_afk_channel(c) => VoiceLocation::InAfkChannel,
+        Some(c) if !is_voice_channel(c) => VoiceLocation::InOtherKindOfChannel,
+        Some(_) => VoiceLocation::InOtherChannel,
+    }
+}
+
+// The channels out of the guild's `channels` that users in `voice_states` are in and that are
+// regular voice channels, including temporary ones created from a lobby. Only being in one of those
+// counts as already being busy, not e.g. listening in on a stage channel.
+pub fn regular_voice_channels(
+    voice_states: &HashMap<UserId, VoiceState>,
+    channels: &HashMap<ChannelId, Channel>,
+) -> HashSet<ChannelId> {
+    voice_states
+        .values()
+        .filter_map(|vs| vs.channel_id)
+        .filter(|c| {
+            matches!(channels.get(c), Some(Channel::Guild(gc)) if gc.kind == ChannelType::Voice)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JOINED: ChannelId = ChannelId(1);
+    const AFK: ChannelId = ChannelId(2);
+    const OTHER: ChannelId = ChannelId(3);
+    const STAGE: ChannelId = ChannelId(4);
+
+    fn voice_states(users: &[(u64, ChannelId)]) -> HashMap<UserId, VoiceState> {
+        users
+            .iter()
+            .map(|&(user_id, channel_id)| {
+                let state = serde_json::from_value(serde_json::json!({
+                    "channel_id": channel_id.to_string(),
+                    "deaf": false,
+                    "mute": false,
+                    "self_deaf": false,
+                    "self_mute": false,
+                    "self_video": false,
+                    "session_id": "",
+                    "suppress": false,
+                    "user_id": user_id.to_string(),
+                }))
+                .unwrap();
+                (UserId(user_id), state)
+            })
+            .collect()
+    }
+
+    fn locate(voice_states: &HashMap<UserId, VoiceState>, user_id: u64) -> VoiceLocation {
+        locate_user(
+            voice_states,
+            UserId(user_id),
+            JOINED,
+            |c| c != STAGE,
+            |c| c == AFK,
+        )
+    }
+
+    #[test]
+    fn locates_users_relative_to_the_joined_channel() {
+        let states = voice_states(&[(10, JOINED), (11, AFK), (12, OTHER), (13, STAGE)]);
+
+        assert_eq!(locate(&states, 10), VoiceLocation::InJoinedChannel);
+        assert_eq!(locate(&states, 11), VoiceLocation::InAfkChannel);
+        assert_eq!(locate(&states, 12), VoiceLocation::InOtherChannel);
+        assert_eq!(locate(&states, 13), VoiceLocation::InOtherKindOfChannel);
+        assert_eq!(locate(&states, 14), VoiceLocation::NotInVoice);
+    }
+
+    #[test]
+    fn users_in_other_temporary_rooms_are_in_another_channel() {
+        const ROOM: ChannelId = ChannelId(5);
+
+        let states = voice_states(&[(10, ROOM), (11, STAGE)]);
+        let channels = [(JOINED, 2), (ROOM, 2), (STAGE, 13)]
+            .into_iter()
+            .map(|(id, kind)| {
+                let channel = serde_json::from_value(serde_json::json!({
+                    "id": id.to_string(),
+                    "guild_id": "100",
+                    "type": kind,
+                    "name": "Someone's Room",
+                }))
+                .unwrap();
+                (id, channel)
+            })
+            .collect();
+
+        let voice_channels = regular_voice_channels(&states, &channels);
+        assert_eq!(voice_channels, HashSet::from([ROOM]));
+
+        let locate = |user_id| {
+            locate_user(
+                &states,
+                UserId(user_id),
+                JOINED,
+                |c| voice_channels.contains(&c),
+                |c| c == AFK,
+            )
+        };
+        assert_eq!(locate(10), VoiceLocation::InOtherChannel);
+        assert_eq!(locate(11), VoiceLocation::InOtherKindOfChannel);
+    }
+}